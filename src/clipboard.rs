@@ -0,0 +1,82 @@
+// The system clipboard, through whichever of the usual command line tools is
+// there: wl-copy and wl-paste under Wayland, xclip or xsel under X, and
+// pbcopy and pbpaste on macOS.
+//
+// `primary` picks X's primary selection, the `*` register, over the
+// clipboard, the `+` one. macOS has just the one clipboard for both.
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// Copy and paste commands for the tools that can work here, most likely
+// first
+fn tools(primary: bool) -> Vec<(Vec<&'static str>, Vec<&'static str>)> {
+    let mut tools = Vec::new();
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        let (copy, paste) = if primary {
+            (vec!["wl-copy", "--primary"], vec!["wl-paste", "--primary"])
+        } else {
+            (vec!["wl-copy"], vec!["wl-paste"])
+        };
+        tools.push((copy, [paste, vec!["--no-newline"]].concat()));
+    }
+    if std::env::var_os("DISPLAY").is_some() {
+        let selection = if primary { "primary" } else { "clipboard" };
+        tools.push((
+            vec!["xclip", "-selection", selection],
+            vec!["xclip", "-selection", selection, "-o"],
+        ));
+        let selection = if primary { "--primary" } else { "--clipboard" };
+        tools.push((
+            vec!["xsel", selection, "--input"],
+            vec!["xsel", selection, "--output"],
+        ));
+    }
+    if cfg!(target_os = "macos") {
+        tools.push((vec!["pbcopy"], vec!["pbpaste"]));
+    }
+    tools
+}
+
+fn unavailable() -> String {
+    "No clipboard: wl-copy, xclip, xsel or pbcopy is needed".to_string()
+}
+
+pub fn copy(text: &str, primary: bool) -> Result<(), String> {
+    for (copy, _) in tools(primary) {
+        let child = Command::new(copy[0])
+            .args(&copy[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        // Not installed, on to the next one
+        let Ok(mut child) = child else {
+            continue;
+        };
+        let written = child
+            .stdin
+            .take()
+            .is_some_and(|mut stdin| stdin.write_all(text.as_bytes()).is_ok());
+        if child.wait().is_ok_and(|status| status.success()) && written {
+            return Ok(());
+        }
+    }
+    Err(unavailable())
+}
+
+pub fn paste(primary: bool) -> Result<String, String> {
+    for (_, paste) in tools(primary) {
+        let output = Command::new(paste[0])
+            .args(&paste[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() => {
+                return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+            }
+            _ => continue,
+        }
+    }
+    Err(unavailable())
+}
@@ -0,0 +1,161 @@
+// Arithmetic for the `=` expression register: `+ - * / %`, unary minus and
+// parentheses over integers and decimals.
+//
+// Like vim, integers stay integers, so `7/2` is 3, and any decimal makes
+// the result one.
+use std::fmt;
+
+#[derive(Clone, Copy)]
+enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Value {
+    fn float(self) -> f64 {
+        match self {
+            Value::Int(n) => n as f64,
+            Value::Float(x) => x,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Int(n) => write!(f, "{}", n),
+            // A whole decimal keeps its point, so it is told from an integer
+            Value::Float(x) if x.is_finite() && x.fract() == 0.0 => write!(f, "{:.1}", x),
+            Value::Float(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+// The value of `text` written out, or what is wrong with it
+pub fn eval(text: &str) -> Result<String, String> {
+    let mut parser = Parser {
+        chars: text.chars().filter(|c| !c.is_whitespace()).collect(),
+        pos: 0,
+    };
+    if parser.chars.is_empty() {
+        return Err("Empty expression".to_string());
+    }
+    let value = parser.sum()?;
+    match parser.chars.get(parser.pos) {
+        Some(c) => Err(format!("Unexpected {} in expression", c)),
+        None => Ok(value.to_string()),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    // Takes `c` if it comes next
+    fn take(&mut self, c: char) -> bool {
+        let found = self.chars.get(self.pos) == Some(&c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn sum(&mut self) -> Result<Value, String> {
+        let mut value = self.product()?;
+        loop {
+            let op = if self.take('+') {
+                '+'
+            } else if self.take('-') {
+                '-'
+            } else {
+                return Ok(value);
+            };
+            value = apply(op, value, self.product()?)?;
+        }
+    }
+
+    fn product(&mut self) -> Result<Value, String> {
+        let mut value = self.unary()?;
+        loop {
+            let op = if self.take('*') {
+                '*'
+            } else if self.take('/') {
+                '/'
+            } else if self.take('%') {
+                '%'
+            } else {
+                return Ok(value);
+            };
+            value = apply(op, value, self.unary()?)?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        if self.take('-') {
+            return apply('-', Value::Int(0), self.unary()?);
+        }
+        if self.take('+') {
+            return self.unary();
+        }
+        if self.take('(') {
+            let value = self.sum()?;
+            if !self.take(')') {
+                return Err("Missing ) in expression".to_string());
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_digit() || *c == '.')
+        {
+            self.pos += 1;
+        }
+        let number: String = self.chars[start..self.pos].iter().collect();
+        if number.is_empty() {
+            return Err(match self.chars.get(self.pos) {
+                Some(c) => format!("Unexpected {} in expression", c),
+                None => "Unexpected end of expression".to_string(),
+            });
+        }
+        if number.contains('.') {
+            number
+                .parse()
+                .map(Value::Float)
+                .map_err(|_| format!("Invalid number: {}", number))
+        } else {
+            number
+                .parse()
+                .map(Value::Int)
+                .map_err(|_| format!("Number too large: {}", number))
+        }
+    }
+}
+
+fn apply(op: char, a: Value, b: Value) -> Result<Value, String> {
+    let overflow = || "Result too large".to_string();
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => match op {
+            '+' => a.checked_add(b).ok_or_else(overflow),
+            '-' => a.checked_sub(b).ok_or_else(overflow),
+            '*' => a.checked_mul(b).ok_or_else(overflow),
+            _ if b == 0 => Err("Division by zero".to_string()),
+            '/' => a.checked_div(b).ok_or_else(overflow),
+            _ => a.checked_rem(b).ok_or_else(overflow),
+        }
+        .map(Value::Int),
+        (a, b) => {
+            let (a, b) = (a.float(), b.float());
+            Ok(Value::Float(match op {
+                '+' => a + b,
+                '-' => a - b,
+                '*' => a * b,
+                '/' => a / b,
+                _ => a % b,
+            }))
+        }
+    }
+}
@@ -0,0 +1,282 @@
+// Just enough JSON for talking to language servers: parsing what they send
+// and writing out what is sent to them.
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    // Members in the order they came, there are only ever a few
+    Object(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        (parser.pos == parser.bytes.len()).then_some(value)
+    }
+
+    pub fn object<'a>(members: impl IntoIterator<Item = (&'a str, Json)>) -> Json {
+        Json::Object(
+            members
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    // The member `key` of an object, Null when there is none
+    pub fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map_or(&NULL, |(_, value)| value),
+            _ => &NULL,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    // The elements of an array, none for anything else
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(text: &str) -> Json {
+        Json::String(text.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(text: String) -> Json {
+        Json::String(text)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        Json::Bool(value)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            // Whole numbers without the `.0`, which some servers reject
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(text) => write_string(f, text),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, text: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in text.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .bytes
+            .get(self.pos)
+            .is_some_and(|b| b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    // Takes `word` if the text goes on with it
+    fn take(&mut self, word: &str) -> bool {
+        let found = self.bytes[self.pos..].starts_with(word.as_bytes());
+        if found {
+            self.pos += word.len();
+        }
+        found
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        let value = match *self.bytes.get(self.pos)? {
+            b'{' => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if !self.take("}") {
+                    loop {
+                        self.skip_whitespace();
+                        let key = self.string()?;
+                        self.skip_whitespace();
+                        if !self.take(":") {
+                            return None;
+                        }
+                        members.push((key, self.value()?));
+                        self.skip_whitespace();
+                        if self.take("}") {
+                            break;
+                        }
+                        if !self.take(",") {
+                            return None;
+                        }
+                    }
+                }
+                Json::Object(members)
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if !self.take("]") {
+                    loop {
+                        values.push(self.value()?);
+                        self.skip_whitespace();
+                        if self.take("]") {
+                            break;
+                        }
+                        if !self.take(",") {
+                            return None;
+                        }
+                    }
+                }
+                Json::Array(values)
+            }
+            b'"' => Json::String(self.string()?),
+            _ if self.take("null") => Json::Null,
+            _ if self.take("true") => Json::Bool(true),
+            _ if self.take("false") => Json::Bool(false),
+            _ => {
+                let start = self.pos;
+                while self
+                    .bytes
+                    .get(self.pos)
+                    .is_some_and(|b| b.is_ascii_digit() || b"+-.eE".contains(b))
+                {
+                    self.pos += 1;
+                }
+                let number = std::str::from_utf8(&self.bytes[start..self.pos]).ok()?;
+                Json::Number(number.parse().ok()?)
+            }
+        };
+        Some(value)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if !self.take("\"") {
+            return None;
+        }
+        let mut text = String::new();
+        loop {
+            // Copy everything up to the next quote or escape as it is
+            let start = self.pos;
+            while !matches!(*self.bytes.get(self.pos)?, b'"' | b'\\') {
+                self.pos += 1;
+            }
+            text.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).ok()?);
+            if self.take("\"") {
+                return Some(text);
+            }
+            self.pos += 1;
+            let escape = *self.bytes.get(self.pos)?;
+            self.pos += 1;
+            match escape {
+                b'"' => text.push('"'),
+                b'\\' => text.push('\\'),
+                b'/' => text.push('/'),
+                b'b' => text.push('\u{8}'),
+                b'f' => text.push('\u{c}'),
+                b'n' => text.push('\n'),
+                b'r' => text.push('\r'),
+                b't' => text.push('\t'),
+                b'u' => {
+                    let mut code = self.hex4()?;
+                    // A char outside the BMP comes as a pair of surrogates
+                    if (0xd800..0xdc00).contains(&code) && self.take("\\u") {
+                        let low = self.hex4()?;
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low.checked_sub(0xdc00)?);
+                    }
+                    text.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Option<u32> {
+        let digits = std::str::from_utf8(self.bytes.get(self.pos..self.pos + 4)?).ok()?;
+        self.pos += 4;
+        u32::from_str_radix(digits, 16).ok()
+    }
+}
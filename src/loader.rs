@@ -0,0 +1,75 @@
+// Reading large files a chunk at a time.
+//
+// A thread reads ahead of the editor by a few chunks and then waits, so only
+// as much of the file is in memory as has been looked at, plus that lookahead.
+use std::fs::File;
+use std::io::Read;
+use std::sync::mpsc::{sync_channel, Receiver};
+
+// Files at least this big are loaded lazily
+pub const LAZY_SIZE: u64 = 16 * 1024 * 1024;
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+const CHUNKS_AHEAD: usize = 4;
+
+pub struct Loader {
+    chunks: Receiver<std::io::Result<String>>,
+    // Bytes in the whole file, for telling how much of it is in
+    pub size: u64,
+}
+
+impl Loader {
+    pub fn start(mut file: File) -> Loader {
+        let size = file.metadata().map_or(0, |metadata| metadata.len());
+        let (sender, chunks) = sync_channel(CHUNKS_AHEAD);
+        std::thread::spawn(move || {
+            // Bytes of a char split across two reads, kept for the next one
+            let mut carry = Vec::new();
+            loop {
+                let mut buf = vec![0; CHUNK_SIZE];
+                let read = match file.read(&mut buf) {
+                    Ok(0) if carry.is_empty() => return,
+                    Ok(0) => Err(invalid_utf8()),
+                    Ok(read) => {
+                        buf.truncate(read);
+                        carry.append(&mut buf);
+                        decode(&mut carry)
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = read.is_err();
+                // The editor hung up, nobody wants the rest
+                if sender.send(read).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Loader { chunks, size }
+    }
+
+    // The next chunk of the file, waiting for it to be read if need be, or
+    // None once the whole file is in
+    pub fn next(&self) -> Option<std::io::Result<String>> {
+        self.chunks.recv().ok()
+    }
+}
+
+// Takes the complete chars from the front of `bytes`
+fn decode(bytes: &mut Vec<u8>) -> std::io::Result<String> {
+    let valid = match std::str::from_utf8(bytes) {
+        Ok(text) => text.len(),
+        Err(err) if err.error_len().is_none() => err.valid_up_to(),
+        Err(_) => return Err(invalid_utf8()),
+    };
+    let rest = bytes.split_off(valid);
+    let text = String::from_utf8(std::mem::replace(bytes, rest)).unwrap();
+    Ok(text)
+}
+
+fn invalid_utf8() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
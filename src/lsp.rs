@@ -0,0 +1,337 @@
+// A language server client, speaking JSON-RPC over the server's stdin and
+// stdout, for the one file being edited.
+//
+// A thread reads what the server sends and passes it on over a channel, so
+// the editor takes messages in between keys and never waits on the server.
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use crate::json::Json;
+
+// Severities as the protocol numbers them
+pub const ERROR: usize = 1;
+pub const WARNING: usize = 2;
+
+pub struct Diagnostic {
+    // Line and char column, end exclusive
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub severity: usize,
+    pub message: String,
+}
+
+pub enum Message {
+    // What the server answered to a request made with `method`
+    Response {
+        method: &'static str,
+        result: Json,
+        error: Option<String>,
+    },
+    Notification {
+        method: String,
+        params: Json,
+    },
+}
+
+pub struct Client {
+    // How the server was started, to tell when the `lsp` option changes
+    pub command: String,
+    child: Child,
+    stdin: ChildStdin,
+    messages: Receiver<Json>,
+    next_id: usize,
+    // Requests still waiting for their response
+    requests: Vec<(usize, &'static str)>,
+    // Set once the server has answered `initialize`
+    pub ready: bool,
+    // The open document and its version
+    pub uri: Option<String>,
+    version: usize,
+    // The editor's change count the server has last been sent
+    pub synced: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Client {
+    // Starts `command` and asks it to initialize for the current directory
+    pub fn start(command: &str) -> std::io::Result<Client> {
+        let mut words = command.split_whitespace();
+        let program = words.next().unwrap_or_default();
+        let mut child = Command::new(program)
+            .args(words)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            // Whatever it logs would land on top of the editor
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let (sender, messages) = channel();
+        std::thread::spawn(move || read_messages(stdout, sender));
+
+        let mut client = Client {
+            command: command.to_string(),
+            child,
+            stdin,
+            messages,
+            next_id: 1,
+            requests: Vec::new(),
+            ready: false,
+            uri: None,
+            version: 0,
+            synced: 0,
+            diagnostics: Vec::new(),
+        };
+        let root = std::env::current_dir()?;
+        let capabilities = Json::object([(
+            "textDocument",
+            Json::object([
+                ("publishDiagnostics", Json::object([])),
+                ("definition", Json::object([])),
+                (
+                    "hover",
+                    Json::object([("contentFormat", Json::Array(vec!["plaintext".into()]))]),
+                ),
+            ]),
+        )]);
+        client.request(
+            "initialize",
+            Json::object([
+                ("processId", (std::process::id() as usize).into()),
+                ("rootUri", path_to_uri(&root).into()),
+                ("capabilities", capabilities),
+            ]),
+        )?;
+        Ok(client)
+    }
+
+    pub fn request(&mut self, method: &'static str, params: Json) -> std::io::Result<()> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.push((id, method));
+        self.send(Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("id", id.into()),
+            ("method", method.into()),
+            ("params", params),
+        ]))
+    }
+
+    // Null `params` are left out, for notifications like `exit` that take none
+    pub fn notify(&mut self, method: &str, params: Json) -> std::io::Result<()> {
+        let mut message = Json::object([("jsonrpc", "2.0".into()), ("method", method.into())]);
+        if let (Json::Object(members), false) = (&mut message, params == Json::Null) {
+            members.push(("params".to_string(), params));
+        }
+        self.send(message)
+    }
+
+    fn send(&mut self, message: Json) -> std::io::Result<()> {
+        let body = message.to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.stdin.flush()
+    }
+
+    // Opens `uri` with `text`, in place of the document open before
+    pub fn open(&mut self, uri: String, language: &str, text: &str) -> std::io::Result<()> {
+        if let Some(old) = self.uri.take() {
+            self.notify(
+                "textDocument/didClose",
+                Json::object([("textDocument", Json::object([("uri", old.into())]))]),
+            )?;
+        }
+        self.version = 1;
+        self.diagnostics.clear();
+        self.notify(
+            "textDocument/didOpen",
+            Json::object([(
+                "textDocument",
+                Json::object([
+                    ("uri", uri.as_str().into()),
+                    ("languageId", language.into()),
+                    ("version", self.version.into()),
+                    ("text", text.into()),
+                ]),
+            )]),
+        )?;
+        self.uri = Some(uri);
+        Ok(())
+    }
+
+    // Sends the whole of the new text, which every server takes
+    pub fn change(&mut self, text: &str) -> std::io::Result<()> {
+        self.version += 1;
+        let mut document = self.document();
+        if let Json::Object(members) = &mut document {
+            members.push(("version".to_string(), self.version.into()));
+        }
+        self.notify(
+            "textDocument/didChange",
+            Json::object([
+                ("textDocument", document),
+                (
+                    "contentChanges",
+                    Json::Array(vec![Json::object([("text", text.into())])]),
+                ),
+            ]),
+        )
+    }
+
+    pub fn save(&mut self) -> std::io::Result<()> {
+        let params = Json::object([("textDocument", self.document())]);
+        self.notify("textDocument/didSave", params)
+    }
+
+    // The open document, as requests about it name it
+    pub fn document(&self) -> Json {
+        Json::object([("uri", self.uri.clone().unwrap_or_default().into())])
+    }
+
+    // The next message from the server if one has come, answering whatever
+    // the server itself asks along the way. Fails once the server is gone.
+    pub fn next(&mut self) -> std::io::Result<Option<Message>> {
+        loop {
+            let message = match self.messages.try_recv() {
+                Ok(message) => message,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "the language server exited",
+                    ))
+                }
+            };
+            let id = message.get("id").clone();
+            let method = message.get("method").as_str().map(str::to_string);
+            match (id, method) {
+                (Json::Null, Some(method)) => {
+                    let params = message.get("params").clone();
+                    return Ok(Some(Message::Notification { method, params }));
+                }
+                (id, Some(method)) => {
+                    // Nothing is configured, so every setting asked for is null
+                    let result = if method == "workspace/configuration" {
+                        let items = message.get("params").get("items").as_array().len();
+                        Json::Array(vec![Json::Null; items])
+                    } else {
+                        Json::Null
+                    };
+                    self.send(Json::object([
+                        ("jsonrpc", "2.0".into()),
+                        ("id", id),
+                        ("result", result),
+                    ]))?;
+                }
+                (id, None) => {
+                    let Some(index) = self
+                        .requests
+                        .iter()
+                        .position(|(request, _)| Some(*request) == id.as_usize())
+                    else {
+                        continue;
+                    };
+                    let (_, method) = self.requests.remove(index);
+                    let error = message
+                        .get("error")
+                        .get("message")
+                        .as_str()
+                        .map(str::to_string);
+                    let result = message.get("result").clone();
+                    if method == "initialize" && error.is_none() {
+                        self.ready = true;
+                        self.notify("initialized", Json::object([]))?;
+                    }
+                    return Ok(Some(Message::Response {
+                        method,
+                        result,
+                        error,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // No waiting for it to shut down properly, it is told to go and then
+        // made to
+        let _ = self.notify("exit", Json::Null);
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Passes on every message the server writes, until it closes its stdout or
+// the editor stops listening
+fn read_messages(stdout: ChildStdout, sender: Sender<Json>) -> Option<()> {
+    let mut reader = BufReader::new(stdout);
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).ok()? == 0 {
+                return None;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse().ok();
+            }
+        }
+        let mut body = vec![0; length?];
+        reader.read_exact(&mut body).ok()?;
+        if let Some(message) = std::str::from_utf8(&body).ok().and_then(Json::parse) {
+            sender.send(message).ok()?;
+        }
+    }
+}
+
+// Bytes that can go in a file URI's path as they are
+fn is_uri_safe(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte)
+}
+
+pub fn path_to_uri(path: &Path) -> String {
+    let absolute = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = "file://".to_string();
+    for byte in absolute.to_string_lossy().bytes() {
+        if is_uri_safe(byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    uri
+}
+
+pub fn uri_to_path(uri: &str) -> Option<std::path::PathBuf> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    Some(String::from_utf8(bytes).ok()?.into())
+}
+
+// How the protocol names the language of `filetype`
+pub fn language_id(filetype: &str) -> &str {
+    match filetype {
+        "sh" => "shellscript",
+        "make" => "makefile",
+        "" | "text" => "plaintext",
+        filetype => filetype,
+    }
+}
@@ -1,17 +1,252 @@
+use std::collections::{HashMap, VecDeque};
 use std::fs::{File, OpenOptions};
 use std::io::{stdout, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use clap::Parser;
+use crossterm::style::Stylize;
 use crossterm::{cursor, event, execute, style, terminal, ExecutableCommand};
 use ropey::Rope;
 
-const COMMAND_VIEW_ROWS: u16 = 2;
+mod clipboard;
+mod expr;
+mod json;
+mod loader;
+mod lsp;
+mod matchit;
+mod options;
+mod regex;
+mod screen;
+mod snippet;
+mod spell;
+mod statusline;
+mod undo;
+mod window;
+
+use json::Json;
+use loader::Loader;
+use options::{
+    comment_prefix, detect_filetype, detect_indent, split_args, Config, Indent, Options,
+};
+use regex::Regex;
+use screen::Screen;
+use spell::Dictionary;
+use undo::{Edit, History};
+use window::{Layout, Rect};
+
+// Entries the jump list keeps
+const MAX_JUMPS: usize = 100;
+
+// Entries the change list keeps
+const MAX_CHANGES: usize = 100;
+
+// Messages `:messages` keeps
+const MAX_MESSAGES: usize = 200;
+
+// The full names of the ex commands, for Tab to complete
+const EX_COMMANDS: [&str; 29] = [
+    "close",
+    "cnext",
+    "copen",
+    "cprevious",
+    "delete",
+    "edit",
+    "Explore",
+    "file",
+    "grep",
+    "messages",
+    "only",
+    "put",
+    "quit",
+    "registers",
+    "retab",
+    "reverse",
+    "set",
+    "sort",
+    "split",
+    "substitute",
+    "tabclose",
+    "tabedit",
+    "tabnew",
+    "undojoin",
+    "version",
+    "vsplit",
+    "words",
+    "wq",
+    "write",
+];
+
+// Width of the `showcmd` area at the right of the status line
+const SHOWCMD_COLS: usize = 10;
+
+// How long an operation goes on before its progress shows, and how often
+// that is updated
+const PROGRESS_DELAY: Duration = Duration::from_millis(200);
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+// Pending keys that wait for a motion
+const OPERATORS: [&str; 10] = ["d", "c", ">", "<", "gc", "gq", "ys", "g~", "gu", "gU"];
+
+// Pairs `%` jumps between
+const BRACKETS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+
+// Text objects for what is inside a pair of brackets, or all of it with them
+const BRACKET_OBJECTS: [&str; 16] = [
+    "i(", "i)", "ib", "a(", "a)", "ab", "i[", "i]", "a[", "a]", "i{", "i}", "iB", "a{", "a}", "aB",
+];
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    /// File to edit, or `-` to read the text from stdin
+    filename: Option<PathBuf>,
+    /// Read the text from stdin into an unnamed buffer
+    #[arg(long)]
+    stdin: bool,
+    /// Open the file without allowing it to be written
+    #[arg(short = 'R', long)]
+    readonly: bool,
+}
+
+// (line, col) in the buffer
+type Pos = (usize, usize);
+
+// First and last line of an ex command range, inclusive
+type LineRange = (usize, usize);
+
+#[derive(Clone, Default)]
+struct Register {
+    text: String,
+    linewise: bool,
+}
+
+// Ctrl-N/Ctrl-P state, and Tab's on the command line: the word being
+// completed starts at `start`, and `index` picks a candidate, or the typed
+// `prefix` when it equals their count
+struct Completion {
+    start: usize,
+    prefix: String,
+    candidates: Vec<String>,
+    index: usize,
+}
+
+// `:s` with the `c` flag, going down through the matches and asking about
+// each in Confirm mode
+struct Confirm {
+    regex: Regex,
+    replacement: String,
+    global: bool,
+    // The last line to substitute in, further down once replacements have
+    // added lines
+    last: usize,
+    // The match being asked about: its line and groups
+    line: usize,
+    caps: regex::Captures,
+    substitutions: usize,
+    changed_lines: usize,
+    last_changed: Option<usize>,
+}
+
+// A `:w` to another file that already exists, waiting in Confirm mode to
+// be told whether to overwrite it
+struct PendingWrite {
+    path: String,
+    // The lines to write, all of them when None
+    lines: Option<(usize, usize)>,
+    quit: bool,
+}
+
+// A long operation like a search through a large file, shown on the command
+// line once it has gone on for a moment
+struct Progress {
+    what: &'static str,
+    started: Instant,
+    // When it was last shown and Esc and Ctrl-C were looked for
+    checked: Instant,
+}
+
+impl Progress {
+    fn new(what: &'static str) -> Progress {
+        let started = Instant::now();
+        Progress {
+            what,
+            started,
+            checked: started,
+        }
+    }
+}
+
+// Lines made into a fold with `zf`, both ends inclusive. A closed fold shows
+// as a single line.
+struct Fold {
+    start: usize,
+    end: usize,
+    closed: bool,
+}
+
+// What a tab page keeps of its file while another one is shown
+#[derive(Default)]
+struct Tab {
+    text: Rope,
     filename: Option<PathBuf>,
+    cursor_col: u16,
+    cursor_row: u16,
+    shift_row: usize,
+    dirty: bool,
+    readonly: bool,
+    large_file: bool,
+    scratch: bool,
+    backed_up: bool,
+    loader: Option<Loader>,
+    filetype: String,
+    indent: Option<Indent>,
+    history: History,
+    folds: Vec<Fold>,
+    jumps: Vec<Pos>,
+    jump: usize,
+    change_list: Vec<Pos>,
+    change_at: usize,
+    last_visual: Option<(&'static str, Pos, Pos)>,
+    last_insert: Option<Pos>,
+    windows: Vec<Window>,
+    window: usize,
+    previous_window: Option<usize>,
+    layout: Layout,
+}
+
+// Where a window is in the buffer while another one is the current one
+#[derive(Clone, Default)]
+struct Window {
+    cursor_col: u16,
+    cursor_row: u16,
+    shift_row: usize,
+}
+
+// Output too long for the command view, shown full screen
+#[derive(Default)]
+struct Pager {
+    lines: Vec<String>,
+    top: usize,
+}
+
+// Matches found by `:grep`, listed full screen and stepped through with `:cn`
+// and `:cp`
+#[derive(Default)]
+struct Quickfix {
+    matches: Vec<(Pos, String)>,
+    index: usize,
+    top: usize,
+}
+
+// `:Explore`: a directory's entries, to open a file from
+#[derive(Default)]
+struct Browser {
+    dir: PathBuf,
+    // Directories first, each ending with `/`, then files. `../` leads up.
+    entries: Vec<String>,
+    index: usize,
+    top: usize,
 }
 
 struct Editor {
@@ -23,9 +258,189 @@ struct Editor {
     mode: &'static str,
     cmd_message: Rope,
     dirty: bool,
+    visual_start: Pos,
+    // Mode, start and cursor of the last visual selection, for `gv`
+    last_visual: Option<(&'static str, Pos, Pos)>,
+    // Pattern of the last `/` or `?`, and whether it was `?`
+    last_search: Option<String>,
+    // Where Insert mode was last left, for `gi`
+    last_insert: Option<Pos>,
+    search_backward: bool,
+    // By name: `"` is the unnamed one, `0` the last yank and `a` to `z` the
+    // named ones
+    registers: HashMap<char, Register>,
+    // The register picked with `"x` for the next command
+    register_name: Option<char>,
+    // The count typed before a command
+    count: Option<usize>,
+    // Set when a command line goes wrong, for `@:` with a count to stop
+    command_failed: bool,
+    // Set once it has been said that the system clipboard can't be used
+    clipboard_failed: bool,
+    options: Options,
+    dictionary: Dictionary,
+    filetype: String,
+    // Indentation detected when the file was loaded
+    indent: Option<Indent>,
+    config: Config,
+    pending: String,
+    prefered_col: Option<u16>,
+    // With virtualedit=all, how far past the end of its line the cursor is
+    virtual_cols: usize,
+    prev_cursor_row: u16,
+    prev_cursor_col: u16,
+    // The mode Command mode was entered from, which a search from a visual
+    // mode goes back to
+    command_from: &'static str,
+    logs: Option<File>,
+    // Bumped on every text edit
+    changes: usize,
+    change_start: usize,
+    change_keys: Vec<event::KeyEvent>,
+    last_change: Vec<event::KeyEvent>,
+    // The last command line run with `:`, for `@:`
+    last_command: Option<String>,
+    // Pattern, replacement and flags of the last `:s`, for `&` and `g&`
+    last_substitute: Option<(String, String, String)>,
+    replaying: bool,
+    history: History,
+    pager: Pager,
+    quickfix: Quickfix,
+    browser: Browser,
+    completion: Option<Completion>,
+    // Tab's on the command line, `start` counting the `:`
+    command_completion: Option<Completion>,
+    confirm: Option<Confirm>,
+    pending_write: Option<PendingWrite>,
+    // In the order they were made, which may nest
+    folds: Vec<Fold>,
+    // Where jumps were made from, oldest first, for Ctrl-O and Ctrl-I to go
+    // back and forth between. `jump` is the entry they are at, past the end
+    // until Ctrl-O is used.
+    jumps: Vec<Pos>,
+    jump: usize,
+    // Where the text was changed, oldest first and one entry per line, for
+    // `g;` and `g,`. `change_at` is the entry they are at, past the end
+    // after every change.
+    change_list: Vec<Pos>,
+    change_at: usize,
+    // Every tab page, in the order they are shown, with an empty one in place
+    // of the current one at `tab`. None at all while there is only one.
+    tabs: Vec<Tab>,
+    tab: usize,
+    // Every window on the buffer, with an empty one in place of the current
+    // one at `window`, and how they share the screen. None at all while
+    // there is only one.
+    windows: Vec<Window>,
+    window: usize,
+    // The window last gone to another one from, for Ctrl-W p
+    previous_window: Option<usize>,
+    layout: Layout,
+    // Set once `backup` has copied the file this session
+    backed_up: bool,
+    // Events that came in during a long operation, handled once it is done
+    typeahead: VecDeque<event::Event>,
+    // Messages shown so far, oldest first, each with when it was
+    messages: VecDeque<(Instant, String)>,
+    // The message last added to `messages` while it is still showing
+    logged_message: Option<String>,
+    // What the last render left on the terminal, None to redraw it all
+    screen: Option<Screen>,
+    // Reads the rest of a large file, until all of it is in `text`
+    loader: Option<Loader>,
+    readonly: bool,
+    // Past the `largefile` limit when it was opened
+    large_file: bool,
+    // Command output from `:tabnew !`, thrown away without asking
+    scratch: bool,
+    // Language server for the file, when the `lsp` option names one
+    lsp: Option<lsp::Client>,
+    // The snippet Tab is going through, until Insert mode is left
+    snippet: Option<snippet::Active>,
+    // Cursors besides the real one, as char positions kept up to date
+    // through edits. Typing in Insert mode is done at all of them.
+    cursors: Vec<usize>,
+    // Text just yanked, highlighted until `yankflash` runs out or the next
+    // event, and when it was
+    flash: Option<(std::ops::Range<usize>, Instant)>,
 }
 
 impl Editor {
+    // An editor for `text`, in Normal mode with the default options
+    fn new(text: Rope, filename: Option<PathBuf>, config: Config) -> Editor {
+        let filetype = detect_filetype(filename.as_deref(), &text.line(0).to_string());
+        Editor {
+            text,
+            filename,
+            cursor_col: 0,
+            cursor_row: 0,
+            shift_row: 0,
+            mode: "Normal",
+            cmd_message: Rope::new(),
+            dirty: false,
+            visual_start: (0, 0),
+            last_visual: None,
+            last_search: None,
+            last_insert: None,
+            search_backward: false,
+            registers: HashMap::new(),
+            register_name: None,
+            count: None,
+            command_failed: false,
+            clipboard_failed: false,
+            options: Options::default(),
+            dictionary: Dictionary::new(),
+            filetype,
+            indent: None,
+            config,
+            pending: String::new(),
+            prefered_col: None,
+            virtual_cols: 0,
+            prev_cursor_row: 0,
+            prev_cursor_col: 0,
+            command_from: "Normal",
+            logs: None,
+            changes: 0,
+            change_start: 0,
+            change_keys: Vec::new(),
+            last_change: Vec::new(),
+            last_command: None,
+            last_substitute: None,
+            replaying: false,
+            history: History::default(),
+            pager: Pager::default(),
+            quickfix: Quickfix::default(),
+            browser: Browser::default(),
+            screen: None,
+            loader: None,
+            readonly: false,
+            large_file: false,
+            scratch: false,
+            lsp: None,
+            snippet: None,
+            cursors: Vec::new(),
+            flash: None,
+            completion: None,
+            command_completion: None,
+            confirm: None,
+            pending_write: None,
+            folds: Vec::new(),
+            jumps: Vec::new(),
+            jump: 0,
+            change_list: Vec::new(),
+            change_at: 0,
+            tabs: Vec::new(),
+            tab: 0,
+            windows: Vec::new(),
+            window: 0,
+            previous_window: None,
+            layout: Layout::default(),
+            backed_up: false,
+            typeahead: VecDeque::new(),
+            messages: VecDeque::new(),
+            logged_message: None,
+        }
+    }
     fn currline(&self) -> String {
         let mut currline = self
             .text
@@ -37,395 +452,6813 @@ impl Editor {
         currline
     }
     fn line_max(&self) -> u16 {
-        self.currline().chars().into_iter().count() as u16
+        self.currline().chars().count() as u16
     }
-    fn save(&mut self) -> bool {
-        if let Some(&ref pathbuf) = self.filename.as_ref() {
-            self.text
-                .write_to(BufWriter::new(File::create(pathbuf).unwrap()))
-                .unwrap();
-            self.cmd_message.remove(0..self.cmd_message.len_chars());
-            self.cmd_message
-                .insert(0, &format!("{:?} written", self.filename.as_ref().unwrap()));
-            self.dirty = false;
-            true
+    // Number of lines, not counting the empty one after a final newline
+    fn line_count(&self) -> usize {
+        let lines = self.text.len_lines();
+        if lines > 1 && self.line_len(lines - 1) == 0 {
+            lines - 1
         } else {
-            self.cmd_message.remove(0..self.cmd_message.len_chars());
-            self.cmd_message
-                .insert(0, "Cannot save file without a name");
-            false
+            lines
         }
     }
-    fn render(&self) -> std::io::Result<()> {
-        let (cols, rows) = terminal::size()?;
-        stdout()
-            .execute(terminal::Clear(terminal::ClearType::All))?
-            .execute(cursor::MoveTo(0, 0))?
-            .execute(style::SetForegroundColor(style::Color::Blue))?
-            .execute(style::ResetColor)?;
-
-        for (line, i) in self
-            .text
-            .lines_at(self.shift_row)
-            .zip(0..(rows - COMMAND_VIEW_ROWS).min(self.text.len_lines() as u16))
+    fn line_chars(&self, line: usize) -> Vec<char> {
+        self.text
+            .line(line)
+            .chars()
+            .take(self.line_len(line))
+            .collect()
+    }
+    // Screen column of char `col` in `line`, with tabs expanded
+    fn display_col(&self, line: usize, col: usize) -> usize {
+        let mut width = 0;
+        for c in self.text.line(line).chars().take(col) {
+            width += if c == '\t' {
+                self.options.tabstop - width % self.options.tabstop
+            } else {
+                1
+            };
+        }
+        width
+    }
+    // Char index of the bracket pairing with the one at `pos`, if `pos` is a
+    // bracket and it has a partner
+    fn matching_bracket(&self, pos: usize) -> Option<usize> {
+        let c = self.text.get_char(pos)?;
+        let &(open, close) = BRACKETS.iter().find(|(o, cl)| c == *o || c == *cl)?;
+        let mut depth = 0;
+        if c == open {
+            for (offset, next) in self.text.chars_at(pos).enumerate() {
+                if next == open {
+                    depth += 1;
+                } else if next == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(pos + offset);
+                    }
+                }
+            }
+        } else {
+            let mut chars = self.text.chars_at(pos + 1);
+            let mut index = pos + 1;
+            while let Some(prev) = chars.prev() {
+                index -= 1;
+                if prev == close {
+                    depth += 1;
+                } else if prev == open {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(index);
+                    }
+                }
+            }
+        }
+        None
+    }
+    // Where `%` goes from a keyword or tag of the filetype under the cursor:
+    // on from an opening or middle one to the next of its group, back from
+    // a closing one to the one that opened it
+    fn matching_word(&self) -> Option<Pos> {
+        let line = self.cursor_line();
+        let col = self.cursor_col as usize;
+        let words = matchit::words(&self.filetype, &self.line_chars(line));
+        let word = words
+            .iter()
+            .find(|word| (word.col..word.col + word.len).contains(&col))?;
+        let mut depth = 0;
+        if word.role == matchit::Role::Close {
+            for other_line in (0..=line).rev() {
+                let mut words = matchit::words(&self.filetype, &self.line_chars(other_line));
+                if other_line == line {
+                    words.retain(|other| other.col < word.col);
+                }
+                for other in words.iter().rev().filter(|other| other.group == word.group) {
+                    match other.role {
+                        matchit::Role::Close => depth += 1,
+                        matchit::Role::Open if depth == 0 => return Some((other_line, other.col)),
+                        matchit::Role::Open => depth -= 1,
+                        matchit::Role::Middle => {}
+                    }
+                }
+            }
+        } else {
+            for other_line in line..self.line_count() {
+                let mut words = matchit::words(&self.filetype, &self.line_chars(other_line));
+                if other_line == line {
+                    words.retain(|other| other.col > word.col);
+                }
+                for other in words.iter().filter(|other| other.group == word.group) {
+                    match other.role {
+                        matchit::Role::Open => depth += 1,
+                        matchit::Role::Close | matchit::Role::Middle if depth == 0 => {
+                            return Some((other_line, other.col))
+                        }
+                        matchit::Role::Close => depth -= 1,
+                        matchit::Role::Middle => {}
+                    }
+                }
+            }
+        }
+        None
+    }
+    // The bracket under the cursor, or else right before it, as a char index
+    fn cursor_bracket(&self) -> Option<usize> {
+        let line_start = self.text.line_to_char(self.cursor_line());
+        let col = self.cursor_col as usize;
+        let chars = self.line_chars(self.cursor_line());
+        let is_bracket = |col: usize| {
+            chars
+                .get(col)
+                .is_some_and(|c| BRACKETS.iter().any(|(o, cl)| c == o || c == cl))
+        };
+        if is_bracket(col) {
+            Some(line_start + col)
+        } else if col > 0 && is_bracket(col - 1) {
+            Some(line_start + col - 1)
+        } else {
+            None
+        }
+    }
+    fn first_non_blank(&self, line: usize) -> usize {
+        self.text
+            .line(line)
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .count()
+    }
+    // Char in `line` drawn at screen column `display`
+    fn col_at_display(&self, line: usize, display: usize) -> usize {
+        let chars = self.line_chars(line);
+        (0..chars.len())
+            .find(|col| self.display_col(line, col + 1) > display)
+            .unwrap_or(chars.len())
+    }
+    // Width of the gutter: the diagnostic sign column when there is a
+    // language server, then the line numbers with their trailing space
+    fn gutter_width(&self) -> usize {
+        let signs = if self.lsp.is_some() { 2 } else { 0 };
+        if self.options.number {
+            signs + self.text.len_lines().to_string().len().max(3) + 1
+        } else {
+            signs
+        }
+    }
+    // Screen columns left for the text of a `cols` wide window
+    fn text_cols(&self, cols: u16) -> usize {
+        let scrollbar = if self.options.scrollbar { 1 } else { 0 };
+        (cols as usize).saturating_sub(self.gutter_width() + scrollbar)
+    }
+    fn leading_whitespace(&self, line: usize) -> String {
+        self.line_chars(line)[..self.first_non_blank(line)]
+            .iter()
+            .collect()
+    }
+    fn indent_string(&self, width: usize) -> String {
+        if self.options.expandtab {
+            " ".repeat(width)
+        } else {
+            let tabstop = self.options.tabstop;
+            "\t".repeat(width / tabstop) + &" ".repeat(width % tabstop)
+        }
+    }
+    fn insert(&mut self, pos: usize, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        shift_folds(&mut self.folds, &self.text, pos, text, false);
+        self.text.insert(pos, text);
+        self.dirty = true;
+        self.changes += 1;
+        self.push_change(pos);
+        if let Some(snippet) = self.snippet.as_mut() {
+            snippet.inserted(pos, text.chars().count());
+        }
+        for cursor in self.cursors.iter_mut().filter(|cursor| **cursor >= pos) {
+            *cursor += text.chars().count();
+        }
+        self.history.record(Edit {
+            pos,
+            removed: String::new(),
+            inserted: text.to_string(),
+        });
+    }
+    fn remove(&mut self, range: std::ops::Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let removed = self.text.slice(range.clone()).to_string();
+        shift_folds(&mut self.folds, &self.text, range.start, &removed, true);
+        self.history.record(Edit {
+            pos: range.start,
+            removed,
+            inserted: String::new(),
+        });
+        if let Some(snippet) = self.snippet.as_mut() {
+            snippet.removed(range.clone());
+        }
+        for cursor in self.cursors.iter_mut() {
+            if *cursor >= range.end {
+                *cursor -= range.len();
+            } else if *cursor > range.start {
+                *cursor = range.start;
+            }
+        }
+        self.text.remove(range.clone());
+        self.dirty = true;
+        self.changes += 1;
+        self.push_change(range.start);
+    }
+    // Adds where the text changed at char `pos` to the change list, in place
+    // of the last entry when that is on the same line
+    fn push_change(&mut self, pos: usize) {
+        let line = self.text.char_to_line(pos);
+        let change = (line, pos - self.text.line_to_char(line));
+        if self
+            .change_list
+            .last()
+            .is_some_and(|(last, _)| *last == line)
         {
-            let mut string_line = line.to_string();
-            if string_line.ends_with('\n') {
-                string_line.pop();
+            self.change_list.pop();
+        }
+        self.change_list.push(change);
+        if self.change_list.len() > MAX_CHANGES {
+            self.change_list.remove(0);
+        }
+        self.change_at = self.change_list.len();
+    }
+    // `g;` and `g,`: goes to where the text was changed before, or after
+    fn step_change_list(&mut self, newer: bool) -> std::io::Result<()> {
+        let target = if self.change_list.is_empty() {
+            Err("Change list is empty")
+        } else if newer {
+            Some(self.change_at + 1)
+                .filter(|&target| target < self.change_list.len())
+                .ok_or("At end of change list")
+        } else {
+            self.change_at
+                .checked_sub(1)
+                .ok_or("At start of change list")
+        };
+        match target {
+            Ok(target) => {
+                self.change_at = target;
+                let (line, col) = self.change_list[target];
+                self.move_cursor(line, col)
             }
-            let colls_string = &string_line[..(string_line.len().min(cols as usize))];
-            stdout().execute(style::Print(colls_string))?;
-
-            if i != rows - 2 {
-                stdout().execute(style::Print("\r\n"))?;
+            Err(err) => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(0, err);
+                Ok(())
+            }
+        }
+    }
+    // Reverts the last change (or reapplies the last undone one, if `redo`)
+    fn undo(&mut self, redo: bool) -> std::io::Result<()> {
+        self.history.commit();
+        let target = if redo {
+            self.history.redo_target()
+        } else {
+            self.history.undo_target()
+        };
+        let Some(target) = target else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(
+                0,
+                if redo {
+                    "Already at newest change"
+                } else {
+                    "Already at oldest change"
+                },
+            );
+            return Ok(());
+        };
+        self.undo_to(target)
+    }
+    // Goes to the state made just before this one (or just after, if
+    // `later`), whichever branch of the history it is on
+    fn undo_in_time(&mut self, later: bool) -> std::io::Result<()> {
+        self.history.commit();
+        let state = self.history.state();
+        let target = if later {
+            Some(state + 1).filter(|&target| target <= self.history.newest())
+        } else {
+            state.checked_sub(1)
+        };
+        let Some(target) = target else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(
+                0,
+                if later {
+                    "Already at newest change"
+                } else {
+                    "Already at oldest change"
+                },
+            );
+            return Ok(());
+        };
+        self.undo_to(target)?;
+        let message = if target == 0 {
+            "At original text".to_string()
+        } else {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            format!(
+                "At change {} of {}, {}",
+                target,
+                self.history.newest(),
+                time_ago(now.saturating_sub(self.history.time(target)))
+            )
+        };
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(0, &message);
+        Ok(())
+    }
+    fn undo_to(&mut self, target: usize) -> std::io::Result<()> {
+        // Its stops and the extra cursors don't follow the text being put back
+        self.snippet = None;
+        self.cursors.clear();
+        let apply = |text: &mut Rope, folds: &mut Vec<Fold>, pos: usize, from: &str, to: &str| {
+            shift_folds(folds, text, pos, from, true);
+            text.remove(pos..pos + from.chars().count());
+            shift_folds(folds, text, pos, to, false);
+            text.insert(pos, to);
+        };
+        let mut cursor = None;
+        let folds = &mut self.folds;
+        for (change, redo) in self.history.go_to(target) {
+            if redo {
+                for edit in change.iter() {
+                    apply(
+                        &mut self.text,
+                        folds,
+                        edit.pos,
+                        &edit.removed,
+                        &edit.inserted,
+                    );
+                }
             } else {
-                stdout().execute(style::Print("\r"))?;
+                for edit in change.iter().rev() {
+                    apply(
+                        &mut self.text,
+                        folds,
+                        edit.pos,
+                        &edit.inserted,
+                        &edit.removed,
+                    );
+                }
             }
+            cursor = Some(change[0].pos);
         }
+        let Some(pos) = cursor else {
+            return Ok(());
+        };
+        self.dirty = true;
+        self.changes += 1;
 
-        let filename_label;
-        if self.filename.is_some() {
-            filename_label = format!(" | {}", self.filename.as_ref().unwrap().to_str().unwrap())
+        let pos = pos.min(self.text.len_chars());
+        let line = self.text.char_to_line(pos);
+        self.move_cursor(line, pos - self.text.line_to_char(line))
+    }
+    fn set_indent(&mut self, line: usize, width: usize) {
+        let start = self.text.line_to_char(line);
+        let indent = self.indent_string(width);
+        self.remove(start..start + self.first_non_blank(line));
+        self.insert(start, &indent);
+    }
+    // Chars removed by Backspace at `col`: spaces back to the previous
+    // softtabstop, or a single char
+    fn backspace_width(&self, line: usize, col: usize) -> usize {
+        let softtabstop = self.options.softtabstop;
+        if softtabstop == 0 || col == 0 {
+            return 1;
+        }
+        let chars = self.line_chars(line);
+        let target = (self.display_col(line, col) - 1) / softtabstop * softtabstop;
+        let mut width = 0;
+        while width < col
+            && chars[col - width - 1] == ' '
+            && self.display_col(line, col - width) > target
+        {
+            width += 1;
+        }
+        width.max(1)
+    }
+    fn cursor_line(&self) -> usize {
+        self.shift_row + self.cursor_row as usize
+    }
+    fn line_len(&self, line: usize) -> usize {
+        let line = self.text.line(line);
+        let len = line.len_chars();
+        if len > 0 && line.char(len - 1) == '\n' {
+            len - 1
         } else {
-            filename_label = "".to_string();
+            len
         }
+    }
+    fn move_cursor(&mut self, line: usize, col: usize) -> std::io::Result<()> {
+        let size = terminal_size()?;
+        let text_rows = self.text_rows(size);
+        let line = line.min(self.text.len_lines() - 1);
+        // A closed fold is one line, that of its start
+        let line = self.closed_fold(line).map_or(line, |(start, _)| start);
 
-        let dirty_label;
-        if self.dirty {
-            dirty_label = " | +"
+        let jump = self.scroll_jump(text_rows);
+        if line < self.shift_row {
+            self.shift_row = line.saturating_sub(jump - 1);
+        } else if line >= self.shift_row + text_rows {
+            let max_shift = self.text.len_lines().saturating_sub(text_rows);
+            self.shift_row = (line + jump - text_rows).min(max_shift);
+        }
+        self.cursor_row = (line - self.shift_row) as u16;
+        self.cursor_col = col.min(self.line_len(line)) as u16;
+        self.virtual_cols = 0;
+        Ok(())
+    }
+    // The lines hidden in a closed fold with `line`, all of the outermost one
+    // when folds nest
+    fn closed_fold(&self, line: usize) -> Option<(usize, usize)> {
+        let closed = self
+            .folds
+            .iter()
+            .filter(|fold| fold.closed && fold.start <= line && line <= fold.end);
+        let start = closed.clone().map(|fold| fold.start).min()?;
+        let end = closed.map(|fold| fold.end).max()?;
+        Some((start, end))
+    }
+    // The innermost open fold with `line` in it, or if `closed` the
+    // outermost closed one, which is the one showing
+    fn fold_at(&self, line: usize, closed: bool) -> Option<usize> {
+        let folds = (0..self.folds.len()).filter(|&i| {
+            let fold = &self.folds[i];
+            fold.closed == closed && fold.start <= line && line <= fold.end
+        });
+        let len = |&i: &usize| self.folds[i].end - self.folds[i].start;
+        if closed {
+            folds.max_by_key(len)
         } else {
-            dirty_label = ""
+            folds.min_by_key(len)
         }
-
-        stdout().execute(cursor::MoveTo(0, rows - 2))?;
-        stdout().execute(style::Print(format!(
-            "{}{}{}\r\n{}",
-            self.mode, filename_label, dirty_label, self.cmd_message
-        )))?;
-        stdout().execute(cursor::MoveTo(self.cursor_col, self.cursor_row))?;
-
-        if self.mode == "Normal" {
-            stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-        } else if self.mode == "Insert" {
-            stdout().execute(cursor::SetCursorStyle::SteadyBar)?;
-        } else if self.mode == "Command" {
-            stdout().execute(cursor::SetCursorStyle::SteadyBar)?;
+    }
+    // `zo`, `zc`, `za`, `zR`, `zM` and `zd`, for the folds at the cursor line
+    // or all of them
+    fn fold_command(&mut self, keys: &str) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let at_cursor = self
+            .folds
+            .iter()
+            .any(|fold| fold.start <= line && line <= fold.end);
+        if !at_cursor && !matches!(keys, "zR" | "zM") {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No fold found");
+            return Ok(());
+        }
+        let keys = match keys {
+            "za" if self.closed_fold(line).is_some() => "zo",
+            "za" => "zc",
+            keys => keys,
+        };
+        match keys {
+            "zo" => {
+                for fold in self.folds.iter_mut() {
+                    if fold.start <= line && line <= fold.end {
+                        fold.closed = false;
+                    }
+                }
+            }
+            "zc" => {
+                if let Some(i) = self.fold_at(line, false) {
+                    self.folds[i].closed = true;
+                }
+            }
+            "zR" | "zM" => {
+                for fold in self.folds.iter_mut() {
+                    fold.closed = keys == "zM";
+                }
+            }
+            _ => {
+                let closed = self.closed_fold(line).is_some();
+                if let Some(i) = self.fold_at(line, closed) {
+                    self.folds.remove(i);
+                }
+            }
+        }
+        // Off the lines a closed fold now hides
+        self.move_cursor(line, self.cursor_col as usize)
+    }
+    // Remembers the cursor position before a jump, as the newest entry of
+    // the jump list and the only one for its line
+    fn push_jump(&mut self) {
+        let line = self.cursor_line();
+        self.jumps.retain(|(jump_line, _)| *jump_line != line);
+        self.jumps.push((line, self.cursor_col as usize));
+        if self.jumps.len() > MAX_JUMPS {
+            self.jumps.remove(0);
+        }
+        self.jump = self.jumps.len();
+    }
+    // Ctrl-O, or Ctrl-I if `newer`: back to where the last jump was made
+    // from, or forward again
+    fn step_jump_list(&mut self, newer: bool) -> std::io::Result<()> {
+        if !newer && self.jump == self.jumps.len() {
+            // So that Ctrl-I can come back here
+            self.push_jump();
+            self.jump -= 1;
+        }
+        let target = if newer {
+            Some(self.jump + 1).filter(|&target| target < self.jumps.len())
         } else {
-            stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
+            self.jump.checked_sub(1)
+        };
+        if let Some(target) = target {
+            self.jump = target;
+            let (line, col) = self.jumps[target];
+            self.move_cursor(line, col)?;
         }
         Ok(())
     }
-}
-
-fn run(mut logs: Option<File>, filename: Option<PathBuf>) -> std::io::Result<()> {
-    let text;
-    if let Some(&ref pathbuf) = filename.as_ref() {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(pathbuf)?;
-
-        text = Rope::from_reader(BufReader::new(file))?;
-    } else {
-        text = Rope::new();
+    // Puts the cursor at `col` on its line, or at the end when the line is
+    // shorter, unless virtualedit=all lets it stay past the end
+    fn set_col(&mut self, col: u16) {
+        self.cursor_col = col.min(self.line_max());
+        if self.options.virtualedit == "all" {
+            self.virtual_cols = (col - self.cursor_col) as usize;
+        }
     }
-
-    let mut editor = Editor {
-        text,
-        filename,
-        cursor_col: 0,
-        cursor_row: 0,
-        shift_row: 0,
-        mode: "Normal",
-        cmd_message: Rope::new(),
-        dirty: false,
-    };
-
-    let mut prefered_col: Option<u16> = None;
-
-    let mut prev_cursor_row = 0;
-    let mut prev_cursor_col = 0;
-
-    loop {
-        let (cols, rows) = terminal::size()?;
-
-        if let Some(logs) = logs.as_mut() {
-            writeln!(logs, "Size ({} x {})", cols, rows)?;
+    // Takes the cursor from past the end of its line back to the end, for
+    // any key but h, j, k and l. Typing there with `i` or `a` first fills the
+    // line with spaces up to it.
+    fn leave_virtual_cols(&mut self, keyev: event::KeyEvent) -> std::io::Result<()> {
+        let cols = std::mem::take(&mut self.virtual_cols);
+        let append = match keyev.code {
+            event::KeyCode::Char(c @ ('i' | 'a')) if keyev.modifiers.is_empty() => c == 'a',
+            _ => return Ok(()),
+        };
+        if self.mode != "Normal" || !self.cursors.is_empty() {
+            return Ok(());
         }
-
-        editor.render()?;
-        // Events
-        let ev = event::read()?;
-
-        if let Some(logs) = logs.as_mut() {
-            writeln!(logs, "Got event {:?}", ev)?;
-            writeln!(logs, "shift_row {}", editor.shift_row)?;
-            writeln!(logs, "text len lines {}", editor.text.len_lines())?;
+        let line = self.cursor_line();
+        let end = self.text.line_to_char(line) + self.line_len(line);
+        self.insert(end, &" ".repeat(cols + append as usize));
+        self.cursor_col += cols as u16;
+        Ok(())
+    }
+    // Lines to scroll by at the edge of a view `text_rows` high
+    fn scroll_jump(&self, text_rows: usize) -> usize {
+        self.options.scrolljump.clamp(1, text_rows.max(1))
+    }
+    // Ordered (start, end) of the visual selection, both ends inclusive
+    fn selection(&self) -> (Pos, Pos) {
+        let cursor = (self.cursor_line(), self.cursor_col as usize);
+        if self.visual_start <= cursor {
+            (self.visual_start, cursor)
+        } else {
+            (cursor, self.visual_start)
         }
-
-        if let event::Event::Key(keyev) = ev {
-            if let event::KeyCode::Char(c) = keyev.code {
-                if c != 'j' && c != 'k' {
-                    prefered_col = None;
+    }
+    // Char range of the selection, end exclusive
+    fn selection_chars(&self) -> (usize, usize) {
+        let ((start_line, start_col), (end_line, end_col)) = self.selection();
+        if self.mode == "Visual Line" {
+            (
+                self.text.line_to_char(start_line),
+                self.text.line_to_char(end_line + 1),
+            )
+        } else {
+            (
+                self.text.line_to_char(start_line) + start_col,
+                (self.text.line_to_char(end_line) + end_col + 1).min(self.text.len_chars()),
+            )
+        }
+    }
+    // Columns of `line` covered by the selection, end exclusive
+    fn selected_cols(&self, line: usize) -> Option<(usize, usize)> {
+        if self.mode != "Visual" && self.mode != "Visual Line" {
+            return None;
+        }
+        let ((start_line, start_col), (end_line, end_col)) = self.selection();
+        if line < start_line || line > end_line {
+            return None;
+        }
+        if self.mode == "Visual Line" {
+            return Some((0, usize::MAX));
+        }
+        let start = if line == start_line { start_col } else { 0 };
+        let end = if line == end_line {
+            end_col + 1
+        } else {
+            usize::MAX
+        };
+        Some((start, end))
+    }
+    // The start of the word before the cursor and its template, when that
+    // word triggers a snippet: owlrc's first, then the built-in ones
+    fn snippet_trigger(&self) -> Option<(usize, String)> {
+        let line = self.cursor_line();
+        let chars = self.line_chars(line);
+        let col = self.cursor_col as usize;
+        let len = chars[..col.min(chars.len())]
+            .iter()
+            .rev()
+            .take_while(|c| regex::is_word_char(**c))
+            .count();
+        if len == 0 {
+            return None;
+        }
+        let word: String = chars[col - len..col].iter().collect();
+        let template = self
+            .config
+            .snippets(&self.filetype)
+            .into_iter()
+            .find(|(trigger, _)| *trigger == word)
+            .map(|(_, template)| template)
+            .or_else(|| {
+                snippet::builtin(&self.filetype)
+                    .iter()
+                    .find(|(trigger, _)| *trigger == word)
+                    .map(|(_, template)| template.to_string())
+            })?;
+        Some((self.text.line_to_char(line) + col - len, template))
+    }
+    // Tab in Insert mode expands a snippet trigger, or goes to the next stop
+    // of the snippet being filled in. Shift-Tab goes back a stop.
+    fn snippet_tab(&mut self, backward: bool) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let cursor = self.text.line_to_char(line) + self.cursor_col as usize;
+        if let Some((start, template)) = self.snippet_trigger().filter(|_| !backward) {
+            let indent = self.leading_whitespace(line);
+            let unit = self.indent_string(self.options.shiftwidth);
+            let (text, stops) = snippet::expand(&template, &indent, &unit);
+            self.snippet = None;
+            self.remove(start..cursor);
+            self.insert(start, &text);
+            self.snippet = Some(snippet::Active {
+                stops: stops
+                    .iter()
+                    .map(|stop| (start + stop.start, start + stop.end))
+                    .collect(),
+                current: 0,
+                fresh: true,
+            });
+        } else if let Some(snippet) = self.snippet.as_mut() {
+            match (backward, snippet.current) {
+                (true, 0) => return Ok(()),
+                (true, _) => snippet.current -= 1,
+                (false, _) => snippet.current += 1,
+            }
+            snippet.fresh = true;
+        }
+        let Some(snippet) = self.snippet.as_ref() else {
+            return Ok(());
+        };
+        let pos = snippet.stops[snippet.current].0;
+        let line = self.text.char_to_line(pos);
+        self.move_cursor(line, pos - self.text.line_to_char(line))
+    }
+    // Ctrl-A and Ctrl-X: adds `delta` to the number at or after the cursor
+    // and leaves the cursor on its last char
+    fn increment(&mut self, delta: i64) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let chars = self.line_chars(line);
+        let formats: Vec<&str> = self.options.nrformats.split(',').collect();
+        // The cursor can be past the end of a line moved to with `j` or `k`
+        let col = (self.cursor_col as usize).min(chars.len().saturating_sub(1));
+        let Some((start, end, number)) = increment_number(&chars, col, delta, &formats) else {
+            return Ok(());
+        };
+        let line_start = self.text.line_to_char(line);
+        self.remove(line_start + start..line_start + end);
+        self.insert(line_start + start, &number);
+        self.move_cursor(line, start + number.chars().count() - 1)
+    }
+    // Where `motion` takes the cursor, for motions operators can be used with
+    fn motion_target(&self, motion: &str) -> Option<Pos> {
+        let line = self.cursor_line();
+        let last_line = self.line_count().saturating_sub(1);
+        let col = self.cursor_col as usize;
+        let target = match motion {
+            "j" => (line + 1, col),
+            "k" => (line.checked_sub(1)?, col),
+            "G" => (last_line, self.first_non_blank(last_line)),
+            "gg" => (0, self.first_non_blank(0)),
+            "h" => (line, col.saturating_sub(1)),
+            "l" => (line, col + 1),
+            "0" => (line, 0),
+            "^" => (line, self.first_non_blank(line)),
+            "$" => (line, self.line_len(line)),
+            "%" => {
+                if let Some(target) = self.matching_word() {
+                    return Some(target);
                 }
+                // Like vim, from the first bracket at or after the cursor
+                let chars = self.line_chars(line);
+                let offset = chars[col.min(chars.len())..]
+                    .iter()
+                    .position(|c| BRACKETS.iter().any(|(o, cl)| c == o || c == cl))?;
+                let target = self.matching_bracket(self.text.line_to_char(line) + col + offset)?;
+                let target_line = self.text.char_to_line(target);
+                (target_line, target - self.text.line_to_char(target_line))
             }
+            _ => return None,
+        };
+        (target.0 <= last_line).then_some(target)
+    }
+    // Runs an operator once its motion has been typed. Repeating the
+    // operator's last key (`>>`, `gcc`) applies it to the cursor line.
+    fn run_operator(&mut self, keys: String) -> std::io::Result<()> {
+        let Some(operator) = OPERATORS.iter().find(|op| keys.starts_with(**op)) else {
+            return Ok(());
+        };
+        let motion = &keys[operator.len()..];
+        if *operator == "ys" {
+            return self.surround_motion(keys.clone(), motion);
         }
-
-        match ev {
-            event::Event::Key(keyev) => match (keyev.code, editor.mode) {
-                (event::KeyCode::Char('q'), "Normal") => {}
-                (event::KeyCode::Char('h'), "Normal") => {
-                    if editor.cursor_col != 0 {
-                        editor.cursor_col -= 1;
-                    }
+        if matches!(*operator, "g~" | "gu" | "gU") {
+            return self.case_motion(operator, keys.clone(), motion);
+        }
+        if matches!(*operator, "d" | "c") {
+            return self.delete_motion(operator, keys.clone(), motion);
+        }
+        // Still waiting for the motion, or for the second key of `gg`, `ap`
+        // or `ip`
+        if motion.is_empty() || motion == "g" || motion == "a" || motion == "i" {
+            self.pending = keys;
+            return Ok(());
+        }
+        if motion == "G" && !self.finish_loading() {
+            return Ok(());
+        }
+        let line = self.cursor_line();
+        let (first, last) = if operator.ends_with(motion) {
+            (line, line)
+        } else if motion == "ap" || motion == "ip" {
+            self.paragraph_lines(line, motion == "ap")
+        } else if BRACKET_OBJECTS.contains(&motion) {
+            match self.bracket_object(motion) {
+                Some((start, end)) if start < end => (
+                    self.text.char_to_line(start),
+                    self.text.char_to_line(end - 1),
+                ),
+                _ => return Ok(()),
+            }
+        } else {
+            match self.motion_target(motion) {
+                Some((target, _)) => (line.min(target), line.max(target)),
+                None => return Ok(()),
+            }
+        };
+        match *operator {
+            ">" | "<" => self.indent_lines(first, last, *operator == "<"),
+            "gc" => self.toggle_comment(first, last),
+            "gq" => self.reflow(first, last),
+            _ => unreachable!(),
+        }
+        self.move_cursor(first, self.first_non_blank(first))
+    }
+    // Lines of the paragraph (or run of blank lines) `line` is in. `ap` also
+    // takes the blank lines after it, or before it when there are none after.
+    fn paragraph_lines(&self, line: usize, around: bool) -> (usize, usize) {
+        let last_line = self.line_count().saturating_sub(1);
+        let is_blank = |line: usize| self.first_non_blank(line) == self.line_len(line);
+        let blank = is_blank(line);
+        let (mut first, mut last) = (line, line);
+        while first > 0 && is_blank(first - 1) == blank {
+            first -= 1;
+        }
+        while last < last_line && is_blank(last + 1) == blank {
+            last += 1;
+        }
+        if around {
+            let end = last;
+            while last < last_line && is_blank(last + 1) != blank {
+                last += 1;
+            }
+            if last == end && !blank {
+                while first > 0 && is_blank(first - 1) {
+                    first -= 1;
                 }
-                (event::KeyCode::Char('j'), "Normal") => {
-                    if let None = prefered_col {
-                        prefered_col = Some(editor.cursor_col);
-                    }
-
-                    if (editor.cursor_row != rows - 1 - COMMAND_VIEW_ROWS)
-                        && (((editor.cursor_row + 1) as usize) < editor.text.len_lines())
-                    {
-                        editor.cursor_row += 1;
-                    } else {
-                        if (editor.shift_row + rows as usize - COMMAND_VIEW_ROWS as usize)
-                            < editor.text.len_lines() - 1
-                        {
-                            editor.shift_row += 1;
+            }
+        }
+        (first, last)
+    }
+    // `i(`, `a{` and the like: the innermost pair of brackets around the
+    // cursor, or at it, which may be lines apart, and what is inside it. When
+    // the opening bracket ends its line, and the closing one has only
+    // indentation before it, the inside is just the lines in between.
+    fn bracket_object(&self, motion: &str) -> Option<(usize, usize)> {
+        let (around, kind) = motion.split_at(1);
+        let kind = match kind {
+            "b" => "(",
+            "B" => "{",
+            kind => kind,
+        };
+        let &(open, close) = BRACKETS
+            .iter()
+            .find(|(o, c)| kind.starts_with(*o) || kind.starts_with(*c))?;
+        let cursor = self.text.line_to_char(self.cursor_line()) + self.cursor_col as usize;
+        let start = match self.text.get_char(cursor) {
+            Some(c) if c == open => cursor,
+            Some(c) if c == close => self.matching_bracket(cursor)?,
+            // Back to the first opening bracket not closed before the cursor
+            _ => {
+                let mut chars = self.text.chars_at(cursor);
+                let mut index = cursor;
+                let mut depth = 0;
+                loop {
+                    let c = chars.prev()?;
+                    index -= 1;
+                    if c == close {
+                        depth += 1;
+                    } else if c == open {
+                        if depth == 0 {
+                            break index;
                         }
+                        depth -= 1;
                     }
-
-                    editor.cursor_col = prefered_col.unwrap().min(editor.line_max());
                 }
-                (event::KeyCode::Char('k'), "Normal") => {
-                    if let None = prefered_col {
-                        prefered_col = Some(editor.cursor_col);
-                    }
-
-                    if editor.cursor_row != 0 {
-                        editor.cursor_row -= 1;
+            }
+        };
+        let end = self.matching_bracket(start)?;
+        if around == "a" {
+            return Some((start, end + 1));
+        }
+        let (open_line, close_line) = (self.text.char_to_line(start), self.text.char_to_line(end));
+        let mut inner = (start + 1, end);
+        if open_line < close_line {
+            if start + 1 - self.text.line_to_char(open_line) == self.line_len(open_line) {
+                inner.0 = self.text.line_to_char(open_line + 1);
+            }
+            let close_line_start = self.text.line_to_char(close_line);
+            if self.first_non_blank(close_line) == end - close_line_start {
+                inner.1 = close_line_start;
+            }
+        }
+        Some((inner.0, inner.1.max(inner.0)))
+    }
+    // Char range `motion` or text object covers from the cursor, end exclusive
+    fn motion_range(&self, motion: &str) -> Option<(usize, usize)> {
+        let line = self.cursor_line();
+        let line_start = self.text.line_to_char(line);
+        let col = self.cursor_col as usize;
+        let chars = self.line_chars(line);
+        match motion {
+            "iw" | "aw" => {
+                let (mut start, mut end) = word_bounds(&chars, col)?;
+                if motion == "aw" {
+                    // Take the spaces after the word, or before it at line end
+                    let after = chars[end..]
+                        .iter()
+                        .take_while(|c| c.is_whitespace())
+                        .count();
+                    if after > 0 {
+                        end += after;
                     } else {
-                        if editor.shift_row != 0 {
-                            editor.shift_row -= 1;
-                        }
+                        start -= chars[..start]
+                            .iter()
+                            .rev()
+                            .take_while(|c| c.is_whitespace())
+                            .count();
                     }
-
-                    editor.cursor_col = prefered_col.unwrap().min(editor.line_max());
                 }
-                (event::KeyCode::Char('l'), "Normal") => {
-                    if (editor.cursor_col != cols - 1) && (editor.cursor_col < editor.line_max()) {
-                        editor.cursor_col += 1;
+                Some((line_start + start, line_start + end))
+            }
+            // Like vim's `dw`, this stops at the end of the line rather than
+            // going on into the next one when the word is the line's last
+            "w" => {
+                let cursor = line_start + col;
+                let line_end = line_start + chars.len();
+                let target = self.next_word_start(cursor);
+                let target = if self.text.char_to_line(target) == line {
+                    target.min(line_end)
+                } else {
+                    line_end
+                };
+                Some((cursor, target))
+            }
+            "e" => {
+                let cursor = line_start + col;
+                Some((cursor, self.word_end(cursor) + 1))
+            }
+            "%" => {
+                let (target_line, target_col) = self.motion_target(motion)?;
+                let target = self.text.line_to_char(target_line) + target_col;
+                let cursor = line_start + col;
+                Some((cursor.min(target), cursor.max(target) + 1))
+            }
+            _ if BRACKET_OBJECTS.contains(&motion) => self.bracket_object(motion),
+            "j" | "k" | "G" | "gg" => {
+                let (target, _) = self.motion_target(motion)?;
+                let (first, last) = (line.min(target), line.max(target));
+                Some((
+                    self.text.line_to_char(first) + self.first_non_blank(first),
+                    self.text.line_to_char(last) + self.line_len(last),
+                ))
+            }
+            _ => {
+                let (_, target) = self.motion_target(motion)?;
+                let target = target.min(chars.len());
+                Some((line_start + col.min(target), line_start + col.max(target)))
+            }
+        }
+    }
+    // `g~`, `gu` and `gU` with their motion: toggles, lowers or raises the
+    // case of the chars it covers. The operator's last key again, or the
+    // whole operator, does the cursor line.
+    fn case_motion(&mut self, operator: &str, keys: String, motion: &str) -> std::io::Result<()> {
+        const MOTIONS: [&str; 14] = [
+            "iw", "aw", "w", "e", "j", "k", "G", "gg", "h", "l", "0", "^", "$", "%",
+        ];
+        let line_motions = [&operator[1..], operator];
+        let mut motions = MOTIONS.iter().chain(&BRACKET_OBJECTS).chain(&line_motions);
+        if !motions.clone().any(|m| *m == motion) {
+            if motion.is_empty() || motions.any(|m| m.starts_with(motion)) {
+                self.pending = keys;
+            }
+            return Ok(());
+        }
+        if motion == "G" && !self.finish_loading() {
+            return Ok(());
+        }
+        let line = self.cursor_line();
+        let line_start = self.text.line_to_char(line);
+        let Some((start, end)) = (if line_motions.contains(&motion) {
+            Some((line_start, line_start + self.line_len(line)))
+        } else {
+            self.motion_range(motion)
+        }) else {
+            return Ok(());
+        };
+        let text = self.text.slice(start..end).to_string();
+        let changed: String = match operator {
+            "gu" => text.to_lowercase(),
+            "gU" => text.to_uppercase(),
+            _ => text
+                .chars()
+                .flat_map(|c| {
+                    if c.is_uppercase() {
+                        c.to_lowercase().collect::<Vec<_>>()
+                    } else {
+                        c.to_uppercase().collect()
+                    }
+                })
+                .collect(),
+        };
+        if changed != text {
+            self.remove(start..end);
+            self.insert(start, &changed);
+        }
+        let line = self.text.char_to_line(start);
+        self.move_cursor(line, start - self.text.line_to_char(line))
+    }
+    // `d` and `c` with their motion: deletes what it covers into the picked
+    // register, and for `c` starts Insert mode in its place. Line motions,
+    // and the operator's key again for the cursor line, take whole lines.
+    fn delete_motion(&mut self, operator: &str, keys: String, motion: &str) -> std::io::Result<()> {
+        const MOTIONS: [&str; 10] = ["iw", "aw", "w", "e", "h", "l", "0", "^", "$", "%"];
+        const LINE_MOTIONS: [&str; 6] = ["j", "k", "G", "gg", "ap", "ip"];
+        let doubled = [operator];
+        let mut motions = MOTIONS
+            .iter()
+            .chain(&BRACKET_OBJECTS)
+            .chain(&LINE_MOTIONS)
+            .chain(&doubled);
+        if !motions.clone().any(|m| *m == motion) {
+            if motion.is_empty() || motions.any(|m| m.starts_with(motion)) {
+                self.pending = keys;
+            }
+            return Ok(());
+        }
+        if motion == "G" && !self.finish_loading() {
+            return Ok(());
+        }
+        let line = self.cursor_line();
+        if motion == operator || LINE_MOTIONS.contains(&motion) {
+            let (first, last) = if motion == operator {
+                (line, line)
+            } else if motion == "ap" || motion == "ip" {
+                self.paragraph_lines(line, motion == "ap")
+            } else {
+                match self.motion_target(motion) {
+                    Some((target, _)) => (line.min(target), line.max(target)),
+                    None => return Ok(()),
+                }
+            };
+            if operator == "d" {
+                self.cut_lines(first, last);
+                let line = first.min(self.line_count().saturating_sub(1));
+                return self.move_cursor(line, self.first_non_blank(line));
+            }
+            // One line is left to type in, indented like the first was
+            let indent = if self.options.autoindent {
+                self.leading_whitespace(first)
+            } else {
+                String::new()
+            };
+            let start = self.text.line_to_char(first);
+            let end = self.text.line_to_char(last) + self.line_len(last);
+            let text = format!("{}\n", self.text.slice(start..end));
+            self.store_register(
+                Register {
+                    text,
+                    linewise: true,
+                },
+                false,
+            );
+            self.remove(start..end);
+            self.insert(start, &indent);
+            self.mode = "Insert";
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            return self.move_cursor(first, indent.chars().count());
+        }
+
+        let line_start = self.text.line_to_char(line);
+        let col = self.cursor_col as usize;
+        let chars = self.line_chars(line);
+        // Like vim, `cw` in a word only changes up to its end, as `ce` would
+        let range = match chars.get(col) {
+            Some(c) if operator == "c" && motion == "w" && !c.is_whitespace() => {
+                word_bounds(&chars, col).map(|(_, end)| (line_start + col, line_start + end))
+            }
+            _ => self.motion_range(motion),
+        };
+        let Some((start, end)) = range.filter(|(start, end)| start < end) else {
+            return Ok(());
+        };
+        // What is inside brackets on their own lines is whole lines, and `c`
+        // leaves one of them to type in
+        let linewise = BRACKET_OBJECTS.contains(&motion)
+            && start == self.text.line_to_char(self.text.char_to_line(start))
+            && end == self.text.line_to_char(self.text.char_to_line(end));
+        let indent = if linewise && operator == "c" && self.options.autoindent {
+            self.leading_whitespace(self.text.char_to_line(start))
+        } else {
+            String::new()
+        };
+        let text = self.text.slice(start..end).to_string();
+        self.store_register(Register { text, linewise }, false);
+        self.remove(start..end);
+        let line = self.text.char_to_line(start);
+        let mut col = start - self.text.line_to_char(line);
+        if linewise && operator == "c" {
+            self.insert(start, &format!("{}\n", indent));
+            col = indent.chars().count();
+        }
+        if operator == "c" {
+            self.mode = "Insert";
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            return self.move_cursor(line, col);
+        }
+        // On the line's last char when its end was deleted
+        self.move_cursor(line, col.min(self.line_len(line).saturating_sub(1)))
+    }
+    // Deletes lines `first..=last` into the register picked for the command
+    fn cut_lines(&mut self, first: usize, last: usize) {
+        let start = self.text.line_to_char(first);
+        let mut text = self
+            .text
+            .slice(start..self.text.line_to_char(last + 1))
+            .to_string();
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        self.store_register(
+            Register {
+                text,
+                linewise: true,
+            },
+            false,
+        );
+        self.delete_lines(first, last);
+    }
+    // Where `w` goes from char `pos`: the start of the next word, or the next
+    // empty line, whichever line that is on. The end of the text if there is
+    // neither.
+    fn next_word_start(&self, pos: usize) -> usize {
+        let mut chars = self.text.chars_at(pos).peekable();
+        let mut pos = pos;
+        if let Some(class) = chars
+            .peek()
+            .map(|c| char_class(*c))
+            .filter(|class| *class != 0)
+        {
+            while chars.next_if(|c| char_class(*c) == class).is_some() {
+                pos += 1;
+            }
+        }
+        let mut line_start = false;
+        for c in chars {
+            if c == '\n' {
+                if line_start {
+                    return pos;
+                }
+                line_start = true;
+            } else if !c.is_whitespace() {
+                return pos;
+            } else if c != '\r' {
+                line_start = false;
+            }
+            pos += 1;
+        }
+        pos
+    }
+    // Where `e` goes from char `pos`: the end of the word it is in, or of the
+    // next one when it is already there
+    fn word_end(&self, pos: usize) -> usize {
+        let len = self.text.len_chars();
+        let mut pos = pos + 1;
+        while pos < len && self.text.char(pos).is_whitespace() {
+            pos += 1;
+        }
+        if pos >= len {
+            return len.saturating_sub(1);
+        }
+        let class = char_class(self.text.char(pos));
+        while pos + 1 < len && char_class(self.text.char(pos + 1)) == class {
+            pos += 1;
+        }
+        pos
+    }
+    // Where `b` goes from char `pos`: the start of the word it is in, or of
+    // the one before when it is already there, or an empty line before that
+    fn prev_word_start(&self, pos: usize) -> usize {
+        if pos == 0 {
+            return 0;
+        }
+        let mut pos = pos - 1;
+        while pos > 0 && self.text.char(pos).is_whitespace() {
+            if self.text.char(pos) == '\n' && self.text.char(pos - 1) == '\n' {
+                return pos;
+            }
+            pos -= 1;
+        }
+        let class = char_class(self.text.char(pos));
+        while pos > 0 && char_class(self.text.char(pos - 1)) == class {
+            pos -= 1;
+        }
+        pos
+    }
+    // `w`, `b` and `e` as cursor motions, which go on to other lines
+    fn word_motion(&mut self, motion: char) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let cursor = self.text.line_to_char(line) + self.cursor_col as usize;
+        let target = match motion {
+            'w' => self.next_word_start(cursor),
+            'e' => self.word_end(cursor),
+            _ => self.prev_word_start(cursor),
+        };
+        // No word after the last one, `w` stops on the last char
+        let last_line = self.line_count().saturating_sub(1);
+        let last = self.text.line_to_char(last_line) + self.line_len(last_line);
+        let target = target.min(
+            last.saturating_sub(1)
+                .max(self.text.line_to_char(last_line)),
+        );
+        let line = self.text.char_to_line(target);
+        self.move_cursor(line, target - self.text.line_to_char(line))
+    }
+    // `ys<motion><char>`: waits for the motion and the surrounding char
+    fn surround_motion(&mut self, keys: String, rest: &str) -> std::io::Result<()> {
+        const MOTIONS: [&str; 15] = [
+            "s", "iw", "aw", "w", "e", "j", "k", "G", "gg", "h", "l", "0", "^", "$", "%",
+        ];
+        let mut motions = MOTIONS.iter().chain(&BRACKET_OBJECTS);
+        if rest.is_empty() || motions.clone().any(|m| m.starts_with(rest)) {
+            self.pending = keys;
+            return Ok(());
+        }
+        let Some(motion) =
+            motions.find(|m| rest.starts_with(**m) && rest[m.len()..].chars().count() == 1)
+        else {
+            return Ok(());
+        };
+        let c = rest[motion.len()..].chars().next().unwrap();
+        let (Some((open, close)), Some((start, end))) = (
+            surround_pair(c),
+            if *motion == "s" {
+                let line = self.cursor_line();
+                let line_start = self.text.line_to_char(line);
+                Some((
+                    line_start + self.first_non_blank(line),
+                    line_start + self.line_len(line),
+                ))
+            } else {
+                self.motion_range(motion)
+            },
+        ) else {
+            return Ok(());
+        };
+        // Like vim-surround, leave trailing whitespace outside the pair
+        let mut end = end;
+        while end > start && self.text.char(end - 1).is_whitespace() {
+            end -= 1;
+        }
+        self.insert(end, close);
+        self.insert(start, open);
+        let line = self.text.char_to_line(start);
+        self.move_cursor(line, start - self.text.line_to_char(line))
+    }
+    // `S<char>` in the visual modes. Whole lines get the pair on lines of
+    // their own.
+    fn surround_selection(&mut self, open: &str, close: &str) -> std::io::Result<()> {
+        let ((first, _), (last, _)) = self.selection();
+        let (start, end) = self.selection_chars();
+        let linewise = self.mode == "Visual Line";
+        self.exit_visual();
+        if linewise {
+            let indent = self.leading_whitespace(first);
+            let end = self.text.line_to_char(last) + self.line_len(last);
+            self.insert(end, &format!("\n{}{}", indent, close.trim()));
+            let start = self.text.line_to_char(first);
+            self.insert(start, &format!("{}{}\n", indent, open.trim()));
+            self.indent_lines(first + 1, last + 1, false);
+            self.move_cursor(first, self.first_non_blank(first))
+        } else {
+            self.insert(end, close);
+            self.insert(start, open);
+            let line = self.text.char_to_line(start);
+            self.move_cursor(line, start - self.text.line_to_char(line))
+        }
+    }
+    // Comments out lines `first..=last`, or uncomments them if every
+    // non-blank one already is
+    fn toggle_comment(&mut self, first: usize, last: usize) {
+        let Some(prefix) = comment_prefix(&self.filetype) else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, "No comment syntax for this filetype");
+            return;
+        };
+        let lines: Vec<usize> = (first..=last)
+            .filter(|line| self.first_non_blank(*line) < self.line_len(*line))
+            .collect();
+        let is_commented = |line: usize| {
+            let chars = self.line_chars(line);
+            let code: String = chars[self.first_non_blank(line)..].iter().collect();
+            code.starts_with(prefix)
+        };
+        let uncomment = !lines.is_empty() && lines.iter().all(|line| is_commented(*line));
+        for line in lines {
+            let indent = self.first_non_blank(line);
+            let start = self.text.line_to_char(line) + indent;
+            if uncomment {
+                let mut len = prefix.chars().count();
+                if self.line_chars(line).get(indent + len) == Some(&' ') {
+                    len += 1;
+                }
+                self.remove(start..start + len);
+            } else {
+                self.insert(start, &format!("{} ", prefix));
+            }
+        }
+    }
+    // Rejoins the words of each paragraph in lines `first..=last` and breaks
+    // them again to fit `textwidth` (79 when it is 0), keeping the indent of
+    // the paragraph's first line. Blank lines between paragraphs are kept.
+    fn reflow(&mut self, first: usize, last: usize) {
+        let width = match self.options.textwidth {
+            0 => 79,
+            textwidth => textwidth,
+        };
+        let is_blank =
+            |editor: &Editor, line: usize| editor.first_non_blank(line) == editor.line_len(line);
+        // Bottom up, so the lines above keep their numbers
+        let mut end = last;
+        loop {
+            while end > first && is_blank(self, end) {
+                end -= 1;
+            }
+            if is_blank(self, end) {
+                break;
+            }
+            let mut start = end;
+            while start > first && !is_blank(self, start - 1) {
+                start -= 1;
+            }
+
+            let indent = self.leading_whitespace(start);
+            let indent_width = self.display_col(start, indent.chars().count());
+            let range =
+                self.text.line_to_char(start)..self.text.line_to_char(end) + self.line_len(end);
+            let old = self.text.slice(range.clone()).to_string();
+            let mut lines: Vec<String> = Vec::new();
+            let mut line_width = 0;
+            for word in old.split_whitespace() {
+                let word_width = word.chars().count();
+                match lines.last_mut() {
+                    Some(line) if line_width + 1 + word_width <= width => {
+                        line.push(' ');
+                        line.push_str(word);
+                        line_width += 1 + word_width;
+                    }
+                    // A word longer than the width still gets a line
+                    _ => {
+                        lines.push(format!("{}{}", indent, word));
+                        line_width = indent_width + word_width;
                     }
                 }
-                (event::KeyCode::Char(':'), "Normal") => {
-                    editor.mode = "Command";
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                    editor.cmd_message.insert(0, ":");
-
-                    prev_cursor_col = editor.cursor_col;
-                    prev_cursor_row = editor.cursor_row;
-
-                    editor.cursor_row = rows - 1;
-                    editor.cursor_col = 1;
-                }
-                (event::KeyCode::Char(c), "Command") => {
-                    editor.cmd_message.insert_char(editor.cursor_col.into(), c);
-                    editor.cursor_col += 1;
-                }
-                (event::KeyCode::Backspace, "Command") => {
-                    if editor.cursor_col == 1 {
-                        editor.mode = "Normal";
-                        editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                        editor.cursor_col = prev_cursor_col;
-                        editor.cursor_row = prev_cursor_row;
+            }
+            let new = lines.join("\n");
+            if new != old {
+                self.remove(range.clone());
+                self.insert(range.start, &new);
+            }
+
+            if start == first {
+                break;
+            }
+            end = start - 1;
+        }
+    }
+    // Completes the word before the cursor with the next (or previous) buffer
+    // word starting with it, nearest ones first
+    fn complete_word(&mut self, backward: bool) {
+        let line = self.cursor_line();
+        let cursor = self.text.line_to_char(line) + self.cursor_col as usize;
+        let mut completion = match self.completion.take() {
+            Some(completion) => completion,
+            None => {
+                let chars = self.line_chars(line);
+                let col = self.cursor_col as usize;
+                let typed = chars[..col]
+                    .iter()
+                    .rev()
+                    .take_while(|c| regex::is_word_char(**c))
+                    .count();
+                let start = cursor - typed;
+                let prefix: String = chars[col - typed..col].iter().collect();
+
+                // Words in buffer order, rotated to begin at the cursor
+                let text: Vec<char> = self.text.chars().collect();
+                let mut words = Vec::new();
+                let mut pos = 0;
+                while pos < text.len() {
+                    let len = text[pos..]
+                        .iter()
+                        .take_while(|c| regex::is_word_char(**c))
+                        .count();
+                    if len == 0 {
+                        pos += 1;
                         continue;
                     }
-                    editor
-                        .cmd_message
-                        .remove((editor.cursor_col as usize - 1)..(editor.cursor_col as usize));
-                    editor.cursor_col -= 1;
+                    if pos != start {
+                        words.push((pos, text[pos..pos + len].iter().collect::<String>()));
+                    }
+                    pos += len;
+                }
+                let split = words.partition_point(|(pos, _)| *pos < cursor);
+                words.rotate_left(split);
+                let mut candidates: Vec<String> = Vec::new();
+                for (_, word) in words {
+                    if word.starts_with(&prefix) && word != prefix && !candidates.contains(&word) {
+                        candidates.push(word);
+                    }
+                }
+                let index = candidates.len();
+                Completion {
+                    start,
+                    prefix,
+                    candidates,
+                    index,
+                }
+            }
+        };
+
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        let count = completion.candidates.len();
+        if count == 0 {
+            self.cmd_message.insert(0, "Pattern not found");
+            return;
+        }
+        completion.index = if backward {
+            (completion.index + count) % (count + 1)
+        } else {
+            (completion.index + 1) % (count + 1)
+        };
+        let word = completion
+            .candidates
+            .get(completion.index)
+            .unwrap_or(&completion.prefix)
+            .clone();
+        self.remove(completion.start..cursor);
+        self.insert(completion.start, &word);
+        self.cursor_col =
+            (completion.start + word.chars().count() - self.text.line_to_char(line)) as u16;
+        if completion.index == count {
+            self.cmd_message.insert(0, "Back at original");
+        } else {
+            self.cmd_message
+                .insert(0, &format!("match {} of {}", completion.index + 1, count));
+        }
+        self.completion = Some(completion);
+    }
+    // Completes the command name typed on the command line, after any range,
+    // with the next (or previous) ex command starting with it
+    fn complete_command(&mut self, backward: bool) {
+        let cursor = self.cursor_col as usize;
+        let mut completion = match self.command_completion.take() {
+            Some(completion) => completion,
+            None => {
+                let line = self.cmd_message.to_string();
+                let Some(typed) = line.strip_prefix(':') else {
+                    return;
+                };
+                let Ok((_, rest)) = self.parse_range(typed) else {
+                    return;
+                };
+                let prefix = rest.trim_start();
+                let complete = cursor == line.chars().count()
+                    && prefix.chars().all(|c| c.is_ascii_alphabetic());
+                if !complete {
+                    return;
+                }
+                let candidates: Vec<String> = EX_COMMANDS
+                    .iter()
+                    .filter(|name| name.starts_with(prefix) && **name != prefix)
+                    .map(|name| name.to_string())
+                    .collect();
+                let index = candidates.len();
+                Completion {
+                    start: cursor - prefix.chars().count(),
+                    prefix: prefix.to_string(),
+                    candidates,
+                    index,
+                }
+            }
+        };
+
+        let count = completion.candidates.len();
+        if count == 0 {
+            return;
+        }
+        completion.index = if backward {
+            (completion.index + count) % (count + 1)
+        } else {
+            (completion.index + 1) % (count + 1)
+        };
+        let name = completion
+            .candidates
+            .get(completion.index)
+            .unwrap_or(&completion.prefix);
+        self.cmd_message.remove(completion.start..cursor);
+        self.cmd_message.insert(completion.start, name);
+        self.cursor_col = (completion.start + name.chars().count()) as u16;
+        self.command_completion = Some(completion);
+    }
+    // Goes to the first non-blank, or to column 0 if already there
+    fn smart_home(&mut self) {
+        let first_non_blank = self.first_non_blank(self.cursor_line()) as u16;
+        self.cursor_col = if self.cursor_col == first_non_blank {
+            0
+        } else {
+            first_non_blank
+        };
+    }
+    // Opens a line below (or above) the cursor's and starts Insert mode there,
+    // indented like the cursor line when autoindent is on
+    fn open_line(&mut self, above: bool) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let indent = if self.options.autoindent {
+            self.leading_whitespace(line)
+        } else {
+            String::new()
+        };
+        let new_line = if above {
+            self.insert(self.text.line_to_char(line), &format!("{}\n", indent));
+            line
+        } else {
+            let end = self.text.line_to_char(line) + self.line_len(line);
+            self.insert(end, &format!("\n{}", indent));
+            line + 1
+        };
+        self.mode = "Insert";
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.move_cursor(new_line, indent.chars().count())
+    }
+    // Scrolls the view by `delta` lines, dragging the cursor along when it
+    // would leave the screen
+    fn scroll_view(&mut self, delta: isize) -> std::io::Result<()> {
+        let size = terminal_size()?;
+        let text_rows = self.text_rows(size);
+        let line = self.cursor_line();
+        let max_shift = self.text.len_lines() - 1;
+        self.shift_row = self.shift_row.saturating_add_signed(delta).min(max_shift);
+        let line = line.clamp(self.shift_row, self.shift_row + text_rows - 1);
+        let col = self.cursor_col as usize;
+        self.move_cursor(line, col)
+    }
+    // Ctrl-F and Ctrl-B: scrolls a screenful forward or back, keeping two
+    // lines of the last one in view like vim
+    fn scroll_page(&mut self, back: bool) -> std::io::Result<()> {
+        let size = terminal_size()?;
+        let page = self.text_rows(size).saturating_sub(2).max(1) as isize;
+        self.scroll_view(if back { -page } else { page })
+    }
+    fn handle_mouse(&mut self, mouse: event::MouseEvent) -> std::io::Result<()> {
+        let size = terminal_size()?;
+        let at = self
+            .window_rects(size)
+            .into_iter()
+            .find(|(_, rect)| rect.contains(mouse.column, mouse.row));
+        let Some((index, rect)) = at else {
+            return Ok(());
+        };
+        let text_rows = rect.rows - self.has_status_line(rect, size) as u16;
+        if mouse.row >= rect.row + text_rows || self.mode == "Command" {
+            return Ok(());
+        }
+        if index != self.window {
+            // Only a click or the wheel goes to another window
+            if !matches!(
+                mouse.kind,
+                event::MouseEventKind::Down(event::MouseButton::Left)
+                    | event::MouseEventKind::ScrollDown
+                    | event::MouseEventKind::ScrollUp
+            ) {
+                return Ok(());
+            }
+            if self.mode == "Visual" || self.mode == "Visual Line" {
+                self.exit_visual();
+            }
+            self.go_to_window(index)?;
+        }
+        let gutter = self.gutter_width();
+        let column = (mouse.column - rect.col) as usize;
+        let in_gutter = column < gutter;
+        let text_cols = self.text_cols(rect.cols);
+        let (line, col) = self.screen_to_pos(
+            (mouse.row - rect.row) as usize,
+            column.saturating_sub(gutter),
+            text_cols,
+        );
+
+        match mouse.kind {
+            event::MouseEventKind::Down(event::MouseButton::Left) => {
+                if self.mode == "Visual" || self.mode == "Visual Line" {
+                    self.exit_visual();
+                }
+                if in_gutter && self.mode == "Normal" {
+                    // Clicking a line number selects the whole line
+                    self.visual_start = (line, 0);
+                    self.mode = "Visual Line";
+                    self.move_cursor(line, 0)?;
+                } else {
+                    self.move_cursor(line, col)?;
+                }
+            }
+            event::MouseEventKind::Drag(event::MouseButton::Left) => {
+                if self.mode == "Normal" {
+                    self.visual_start = (self.cursor_line(), self.cursor_col as usize);
+                    self.mode = "Visual";
+                }
+                self.move_cursor(line, col)?;
+            }
+            event::MouseEventKind::ScrollDown => self.scroll_view(3)?,
+            event::MouseEventKind::ScrollUp => self.scroll_view(-3)?,
+            _ => {}
+        }
+        Ok(())
+    }
+    fn exit_visual(&mut self) {
+        self.last_visual = Some((
+            self.mode,
+            self.visual_start,
+            (self.cursor_line(), self.cursor_col as usize),
+        ));
+        self.mode = "Normal";
+    }
+    // Keeps the selection in a register, for a yank or, if not `yank`, for
+    // the delete about to happen
+    fn yank_selection(&mut self, yank: bool) {
+        let (start, end) = self.selection_chars();
+        let mut text = self.text.slice(start..end).to_string();
+        let linewise = self.mode == "Visual Line";
+        if linewise && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        self.store_register(Register { text, linewise }, yank);
+    }
+    // Keeps yanked or deleted text in the register picked for the command,
+    // which an uppercase name appends to, and in the unnamed one. With
+    // `clipboard` set, picking none puts it on the system clipboard too.
+    fn store_register(&mut self, register: Register, yank: bool) {
+        match self.register_name {
+            Some('_') => {}
+            Some(name @ ('+' | '*')) => {
+                self.copy_to_clipboard(name, &register);
+                self.registers.insert('"', register);
+            }
+            Some(name) if name.is_ascii_uppercase() => {
+                let named = self.registers.entry(name.to_ascii_lowercase()).or_default();
+                // Lines go after the text on a line of their own
+                if register.linewise && !named.text.is_empty() && !named.text.ends_with('\n') {
+                    named.text.push('\n');
+                }
+                named.text.push_str(&register.text);
+                named.linewise |= register.linewise;
+                let named = named.clone();
+                self.registers.insert('"', named);
+            }
+            Some(name) if name != '"' => {
+                self.registers.insert(name, register.clone());
+                self.registers.insert('"', register);
+            }
+            _ => {
+                if yank {
+                    self.registers.insert('0', register.clone());
+                }
+                let name = self.default_register();
+                if name != '"' {
+                    self.copy_to_clipboard(name, &register);
+                }
+                self.registers.insert('"', register);
+            }
+        }
+    }
+    // The register used when none is picked: `*` with clipboard=unnamed, `+`
+    // with unnamedplus, the unnamed one otherwise
+    fn default_register(&self) -> char {
+        let clipboard: Vec<&str> = self.options.clipboard.split(',').collect();
+        if clipboard.contains(&"unnamedplus") {
+            '+'
+        } else if clipboard.contains(&"unnamed") {
+            '*'
+        } else {
+            '"'
+        }
+    }
+    // Puts `register` on the system clipboard for `+` or its primary
+    // selection for `*`. It is kept as register `name` too, for when the
+    // clipboard can't be used and to know whether what is on it is lines.
+    fn copy_to_clipboard(&mut self, name: char, register: &Register) {
+        if let Err(err) = clipboard::copy(&register.text, name == '*') {
+            self.clipboard_error(&err);
+        }
+        self.registers.insert(name, register.clone());
+    }
+    // What is on the system clipboard for a `+` or `*` register, its last
+    // copy when that can't be read
+    fn paste_from_clipboard(&mut self, name: char) -> Register {
+        let copied = self.registers.get(&name).cloned().unwrap_or_default();
+        match clipboard::paste(name == '*') {
+            // It is still what was put there, so it is known whether those
+            // are lines
+            Ok(text) if text == copied.text => copied,
+            Ok(text) => Register {
+                linewise: text.ends_with('\n'),
+                text,
+            },
+            Err(err) => {
+                self.clipboard_error(&err);
+                copied
+            }
+        }
+    }
+    // Says the first time that the clipboard didn't work, after that the
+    // internal registers quietly stand in for it
+    fn clipboard_error(&mut self, err: &str) {
+        if !self.clipboard_failed {
+            self.clipboard_failed = true;
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, &format!("{}, using internal registers", err));
+        }
+    }
+    // What register `name` holds, off the system clipboard for `+` and `*`
+    fn register(&mut self, name: char) -> Register {
+        match name.to_ascii_lowercase() {
+            name @ ('+' | '*') => self.paste_from_clipboard(name),
+            name => self.registers.get(&name).cloned().unwrap_or_default(),
+        }
+    }
+    // Keeps the message showing for `:messages`, once
+    fn log_message(&mut self) {
+        // In Command mode it is the command being typed
+        if self.mode == "Command" || self.cmd_message.len_chars() == 0 {
+            self.logged_message = None;
+            return;
+        }
+        let message = self.cmd_message.to_string();
+        if self.logged_message.as_ref() == Some(&message) {
+            return;
+        }
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((Instant::now(), message.clone()));
+        self.logged_message = Some(message);
+    }
+    // `:messages`: each message kept, with how long ago it was shown
+    fn message_listing(&self) -> String {
+        if self.messages.is_empty() {
+            return "No messages yet".to_string();
+        }
+        let listing: Vec<String> = self
+            .messages
+            .iter()
+            .map(|(shown, message)| {
+                let ago = time_ago(shown.elapsed().as_secs());
+                format!("{:>15}  {}", ago, message)
+            })
+            .collect();
+        listing.join("\n")
+    }
+    // `:registers`: a line for each register with something in it, marked
+    // `l` for lines or `c` for chars, showing as much as fits in `cols`
+    fn register_listing(&self, cols: usize) -> String {
+        let mut names: Vec<&char> = self
+            .registers
+            .iter()
+            .filter(|(_, register)| !register.text.is_empty())
+            .map(|(name, _)| name)
+            .collect();
+        if names.is_empty() {
+            return "No registers yet".to_string();
+        }
+        // The unnamed register first, then the yank one, then a to z
+        names.sort_by_key(|name| (**name != '"', **name != '0', **name));
+        let mut listing = "Type Name Content".to_string();
+        for name in names {
+            let register = &self.registers[name];
+            let mut line = format!(
+                "  {}  \"{}   ",
+                if register.linewise { 'l' } else { 'c' },
+                name
+            );
+            // Control chars as ^J and the like, so each is on one line
+            line.push_str(&caret_notation(&register.text));
+            listing.push('\n');
+            listing.extend(line.chars().take(cols.max(1) - 1));
+        }
+        listing
+    }
+    // What the register picked for the command holds, the default one if
+    // none was
+    fn picked_register(&mut self) -> Register {
+        let name = self.register_name.unwrap_or(self.default_register());
+        self.register(name)
+    }
+    fn delete_lines(&mut self, first: usize, last: usize) {
+        let mut start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last + 1);
+        // Deleting the last lines of a file also takes the newline before them
+        if start > 0 && (start == end || self.text.char(end - 1) != '\n') {
+            start -= 1;
+        }
+        self.remove(start..end);
+    }
+    fn delete_selection(&mut self) {
+        if self.mode == "Visual Line" {
+            let ((first, _), (last, _)) = self.selection();
+            self.delete_lines(first, last);
+        } else {
+            let (start, end) = self.selection_chars();
+            self.remove(start..end);
+        }
+    }
+    // Deletes every line in `first..=last` matching `pattern` (or not
+    // matching, if `invert`)
+    fn global_delete(
+        &mut self,
+        pattern: &str,
+        invert: bool,
+        first: usize,
+        last: usize,
+    ) -> Result<usize, String> {
+        let regex = Regex::new(pattern)?;
+        let matching: Vec<usize> = (first..=last)
+            .filter(|line| regex.find_at(&self.line_chars(*line), 0).is_some() != invert)
+            .collect();
+        // Bottom up, so the remaining line indices stay valid
+        for line in matching.iter().rev() {
+            self.delete_lines(*line, *line);
+        }
+        Ok(matching.len())
+    }
+    // Collects the first match of `pattern` on each line in `first..=last`
+    // into the quickfix list and opens it
+    fn grep(&mut self, pattern: &str, first: usize, last: usize) -> Result<(), String> {
+        let regex = Regex::new(pattern)?;
+        let mut matches = Vec::new();
+        for line in first..=last {
+            let chars = self.line_chars(line);
+            if let Some((start, _)) = regex.find_at(&chars, 0) {
+                matches.push(((line, start), chars.iter().collect()));
+            }
+        }
+        if matches.is_empty() {
+            return Err(format!("Pattern not found: {}", pattern));
+        }
+        self.quickfix = Quickfix {
+            matches,
+            index: 0,
+            top: 0,
+        };
+        self.mode = "Quickfix";
+        Ok(())
+    }
+    // Lists the entries of `dir` to pick from, with `selected` picked first
+    // when it is one of them
+    fn explore(&mut self, dir: PathBuf, selected: Option<&std::ffi::OsStr>) {
+        let dir = std::fs::canonicalize(&dir).unwrap_or(dir);
+        let read = std::fs::read_dir(&dir).and_then(|entries| {
+            entries
+                .map(|entry| {
+                    let entry = entry?;
+                    // A link to a directory is gone into like one
+                    let is_dir = entry.path().is_dir();
+                    Ok((is_dir, entry.file_name()))
+                })
+                .collect::<std::io::Result<Vec<_>>>()
+        });
+        let mut found = match read {
+            Ok(found) => found,
+            Err(err) => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message
+                    .insert(0, &format!("Cannot read {}: {}", dir.display(), err));
+                return;
+            }
+        };
+        found.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let index = found
+            .iter()
+            .position(|(_, name)| Some(name.as_os_str()) == selected)
+            .map_or(0, |index| index + dir.parent().is_some() as usize);
+        let up = dir.parent().map(|_| "../".to_string());
+        let entries = up
+            .into_iter()
+            .chain(found.into_iter().map(|(is_dir, name)| {
+                let name = name.to_string_lossy().into_owned();
+                if is_dir {
+                    format!("{}/", name)
+                } else {
+                    name
+                }
+            }))
+            .collect();
+        self.browser = Browser {
+            dir,
+            entries,
+            index,
+            top: 0,
+        };
+        if let Ok((_, rows)) = terminal_size() {
+            let Browser {
+                entries,
+                index,
+                top,
+                ..
+            } = &mut self.browser;
+            (*index, *top) =
+                move_selection(event::KeyCode::Null, *index, *top, entries.len(), rows);
+        }
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.mode = "Explore";
+    }
+    // With `autowrite`, saves a changed file that has a name before leaving
+    // it or running a shell command. False when that failed.
+    fn autowrite(&mut self) -> bool {
+        if self.dirty && self.options.autowrite && self.filename.is_some() {
+            return self.save();
+        }
+        true
+    }
+    // Opens the file picked in the browser or given to `:e`, unless that
+    // would lose changes
+    fn browse_to(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let same_file = self
+            .filename
+            .as_ref()
+            .and_then(|f| std::fs::canonicalize(f).ok())
+            == std::fs::canonicalize(&path).ok();
+        if same_file {
+            self.mode = "Normal";
+            return Ok(());
+        }
+        if !self.autowrite() {
+            return Ok(());
+        }
+        if self.dirty && !self.scratch {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, "No write since last change, write it first");
+            return Ok(());
+        }
+        // Named from the working directory, as it would be typed
+        let path = std::env::current_dir()
+            .ok()
+            .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf))
+            .unwrap_or(path);
+        match self.open_file(path) {
+            Ok(()) => self.mode = "Normal",
+            Err(err) => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(0, &err.to_string());
+            }
+        }
+        Ok(())
+    }
+    // Moves to quickfix match `index`, saying which one it is
+    fn jump_to_match(&mut self, index: usize) -> std::io::Result<()> {
+        self.quickfix.index = index;
+        let ((line, col), ref text) = self.quickfix.matches[index];
+        let message = format!(
+            "({} of {}): {}",
+            index + 1,
+            self.quickfix.matches.len(),
+            text.trim()
+        );
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(0, &message);
+        self.push_jump();
+        self.move_cursor(line, col)
+    }
+    // Moves to the next match of the last search, the other way from it if
+    // `reverse` (for `N`)
+    fn search_next(&mut self, reverse: bool) -> std::io::Result<()> {
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        let Some(pattern) = self.last_search.clone() else {
+            self.cmd_message.insert(0, "No previous search pattern");
+            return Ok(());
+        };
+        let regex = match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(err) => {
+                self.cmd_message.insert(0, &err);
+                return Ok(());
+            }
+        };
+        if !self.finish_loading() {
+            return Ok(());
+        }
+        let backward = self.search_backward != reverse;
+        let lines = self.line_count();
+        // On the empty line after a trailing newline the search goes from the
+        // end of the last line
+        let (line, col) = if self.cursor_line() < lines {
+            (self.cursor_line(), self.cursor_col as usize)
+        } else {
+            (lines - 1, self.line_len(lines - 1))
+        };
+        // Every line once, starting and ending with the cursor line, which is
+        // searched after the cursor first and before it last. Without
+        // wrapscan only the lines up to the end the search goes towards.
+        let steps = match (self.options.wrapscan, backward) {
+            (true, _) => lines,
+            (false, false) => lines - 1 - line,
+            (false, true) => line,
+        };
+        let mut progress = Progress::new("Searching");
+        let mut found = None;
+        for step in 0..=steps {
+            if self.show_progress(&mut progress, step, steps) {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(0, "Search interrupted");
+                return Ok(());
+            }
+            let index = if backward {
+                (line + lines - step % lines) % lines
+            } else {
+                (line + step) % lines
+            };
+            let chars = self.line_chars(index);
+            let mut matches = Vec::new();
+            let mut from = 0;
+            while let Some((start, end)) = regex.find_at(&chars, from) {
+                matches.push(start);
+                from = end.max(start + 1);
+            }
+            let wanted = |start: &&usize| match (step, backward) {
+                (0, false) => **start > col,
+                (0, true) => **start < col,
+                (_, false) if step == lines => **start <= col,
+                (_, true) if step == lines => **start >= col,
+                _ => true,
+            };
+            let start = if backward {
+                matches.iter().rev().find(wanted)
+            } else {
+                matches.iter().find(wanted)
+            };
+            if let Some(start) = start {
+                found = Some((step, index, *start));
+                break;
+            }
+        }
+        let Some((step, index, start)) = found else {
+            let message = match (self.options.wrapscan, backward) {
+                (true, _) => format!("Pattern not found: {}", pattern),
+                (false, false) => format!("search hit BOTTOM without match for: {}", pattern),
+                (false, true) => format!("search hit TOP without match for: {}", pattern),
+            };
+            self.cmd_message.insert(0, &message);
+            return Ok(());
+        };
+        let wrapped = if backward {
+            step > 0 && index > line
+        } else {
+            step > 0 && index < line
+        } || step == lines;
+        let message = match (wrapped, backward) {
+            (true, false) => "search hit BOTTOM, continuing at TOP".to_string(),
+            (true, true) => "search hit TOP, continuing at BOTTOM".to_string(),
+            _ => format!("{}{}", if backward { '?' } else { '/' }, pattern),
+        };
+        self.cmd_message.insert(0, &message);
+        self.push_jump();
+        self.move_cursor(index, start)
+    }
+    // `*` and `#`: searches for the word under the cursor, or the next one
+    // after it on the line
+    fn search_word(&mut self, backward: bool) -> std::io::Result<()> {
+        let line = self.line_chars(self.cursor_line());
+        let col = self.cursor_col as usize;
+        let Some(start) = line
+            .iter()
+            .skip(col)
+            .position(|c| regex::is_word_char(*c))
+            .map(|offset| col + offset)
+        else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No string under cursor");
+            return Ok(());
+        };
+        let (start, end) = word_bounds(&line, start).unwrap();
+        let word: String = line[start..end].iter().collect();
+        self.last_search = Some(format!("\\<{}\\>", word));
+        self.search_backward = backward;
+        // Start from the word itself, so `*` skips past it and `#` finds the
+        // one before it
+        self.cursor_col = start as u16;
+        self.search_next(false)
+    }
+    // File name, state and size, and where the cursor is in it
+    fn file_info(&self) -> String {
+        let name = match self.filename.as_ref() {
+            Some(filename) => format!("{:?}", filename),
+            None => "[No Name]".to_string(),
+        };
+        let metadata = self
+            .filename
+            .as_ref()
+            .and_then(|filename| std::fs::metadata(filename).ok());
+        let mut info = name;
+        if self.dirty {
+            info.push_str(" [Modified]");
+        }
+        if self.readonly {
+            info.push_str(" [readonly]");
+        }
+        if self.noeol() {
+            info.push_str(" [noeol]");
+        }
+        if metadata.is_none() && self.filename.is_some() {
+            info.push_str(" [New]");
+        }
+        let lines = self.line_count();
+        info.push_str(&format!(" {}", plural(lines, "line")));
+        if self.loader.is_some() {
+            info.push_str(" so far");
+        }
+        if let Some(metadata) = metadata {
+            info.push_str(&format!(", {} bytes", metadata.len()));
+        }
+        let line = self.cursor_line() + 1;
+        format!("{} --{}%-- line {}", info, line * 100 / lines.max(1), line)
+    }
+    // Line, word and character counts of the buffer, and of the selection
+    // when in a visual mode
+    fn count_message(&self) -> String {
+        let text = self.text.to_string();
+        let (words, chars) = count_words_and_chars(&text);
+        let lines = self.line_count();
+        if self.mode != "Visual" && self.mode != "Visual Line" {
+            return format!("{} lines, {} words, {} chars", lines, words, chars);
+        }
+        let ((first, _), (last, _)) = self.selection();
+        let (start, end) = self.selection_chars();
+        let (selected_words, selected_chars) =
+            count_words_and_chars(&self.text.slice(start..end).to_string());
+        format!(
+            "Selected {} of {} lines; {} of {} words; {} of {} chars",
+            last.min(lines.saturating_sub(1)) + 1 - first,
+            lines,
+            selected_words,
+            words,
+            selected_chars,
+            chars
+        )
+    }
+    fn spell_suggestions(&self) -> String {
+        let line = self.line_chars(self.cursor_line());
+        let Some((start, end)) = spell::word_under(&line, self.cursor_col as usize) else {
+            return "No word under cursor".to_string();
+        };
+        let word: String = line[start..end].iter().collect();
+        if self.dictionary.check(&word) {
+            return format!("\"{}\" is spelled correctly", word);
+        }
+        let suggestions = self.dictionary.suggest(&word, 5);
+        if suggestions.is_empty() {
+            format!("No suggestions for \"{}\"", word)
+        } else {
+            format!("Suggestions for \"{}\": {}", word, suggestions.join(", "))
+        }
+    }
+    // Start of the next misspelled word after the cursor (or before it, if
+    // `backward`), wrapping around the end of the file
+    fn next_misspelling(&self, backward: bool) -> Option<Pos> {
+        let (cursor_line, cursor_col) = (self.cursor_line(), self.cursor_col as usize);
+        let line_count = self.line_count().max(1);
+        for step in 0..=line_count {
+            let line = if backward {
+                (cursor_line + line_count * 2 - step) % line_count
+            } else {
+                (cursor_line + step) % line_count
+            };
+            let words = spell::misspellings(&self.dictionary, &self.line_chars(line));
+            let found = if backward {
+                words
+                    .iter()
+                    .rev()
+                    .find(|(start, _)| step > 0 || *start < cursor_col)
+            } else {
+                words
+                    .iter()
+                    .find(|(start, _)| step > 0 || *start > cursor_col)
+            };
+            if let Some((start, _)) = found {
+                return Some((line, *start));
+            }
+        }
+        None
+    }
+    // Parses a line range at the start of an ex command: `N`, `N,M`, `.`, `$`
+    // or `%`. Returns the 0-based inclusive range, if any, and the rest.
+    fn parse_range<'a>(&self, command: &'a str) -> Result<(Option<LineRange>, &'a str), String> {
+        let last_line = self.line_count().saturating_sub(1);
+        if let Some(rest) = command.strip_prefix('%') {
+            return Ok((Some((0, last_line)), rest));
+        }
+        let parse_address = |text: &'a str| -> Result<(Option<usize>, &'a str), String> {
+            if let Some(rest) = text.strip_prefix('.') {
+                return Ok((Some(self.cursor_line()), rest));
+            }
+            if let Some(rest) = text.strip_prefix('$') {
+                return Ok((Some(last_line), rest));
+            }
+            let digits = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            if digits == 0 {
+                return Ok((None, text));
+            }
+            let number: usize = text[..digits]
+                .parse()
+                .map_err(|_| format!("Invalid range {}", &text[..digits]))?;
+            Ok((Some(number.saturating_sub(1)), &text[digits..]))
+        };
+        let (first, rest) = parse_address(command)?;
+        let Some(first) = first else {
+            return Ok((None, command));
+        };
+        let (last, rest) = match rest.strip_prefix(',') {
+            Some(rest) => match parse_address(rest)? {
+                (Some(last), rest) => (last, rest),
+                (None, _) => return Err("Missing end of range".to_string()),
+            },
+            None => (first, rest),
+        };
+        if last < first {
+            return Err("Backwards range given".to_string());
+        }
+        if last > last_line {
+            return Err("Invalid range".to_string());
+        }
+        Ok((Some((first, last)), rest))
+    }
+    // The line `:move` and `:copy` put lines after, as a count of the lines
+    // before them, where unlike in a range 0 is above the first line
+    fn parse_destination(&self, address: &str) -> Result<usize, String> {
+        let line_count = self.line_count();
+        let dest = match address.trim() {
+            "" => return Err("Missing destination line".to_string()),
+            "." => self.cursor_line() + 1,
+            "$" => line_count,
+            number => number
+                .parse()
+                .map_err(|_| format!("Invalid destination {}", number))?,
+        };
+        if dest > line_count {
+            return Err("Invalid destination line".to_string());
+        }
+        Ok(dest)
+    }
+    // Moves (or copies) lines `first..=last` to after the first `dest` lines
+    fn move_lines(
+        &mut self,
+        first: usize,
+        last: usize,
+        dest: usize,
+        copy: bool,
+    ) -> Result<String, String> {
+        if !copy && first < dest && dest <= last {
+            return Err("Cannot move lines into themselves".to_string());
+        }
+        let count = last + 1 - first;
+        let mut lines = self
+            .text
+            .slice(self.text.line_to_char(first)..self.text.line_to_char(last + 1))
+            .to_string();
+        if !lines.ends_with('\n') {
+            lines.push('\n');
+        }
+        let error = |err: std::io::Error| err.to_string();
+        // Whichever of the two is further down goes first, so the other
+        // stays where it is
+        let start = if !copy && dest > last {
+            self.delete_lines(first, last);
+            dest - count
+        } else {
+            dest
+        };
+        self.move_cursor(start.saturating_sub(1), 0)
+            .map_err(error)?;
+        self.paste_lines(lines, start == 0).map_err(error)?;
+        if !copy && dest <= first {
+            self.delete_lines(first + count, last + count);
+        }
+        let line = start + count - 1;
+        self.move_cursor(line, self.first_non_blank(line))
+            .map_err(error)?;
+        Ok(format!(
+            "{} {}",
+            plural(count, "line"),
+            if copy { "copied" } else { "moved" }
+        ))
+    }
+    // Writes lines `first..=last` to `path`, or adds them at the end of the
+    // file already there
+    fn write_lines(
+        &self,
+        first: usize,
+        last: usize,
+        path: &str,
+        append: bool,
+    ) -> Result<String, String> {
+        let start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last + 1);
+        let text = self.text.slice(start..end);
+        // Only appending writes into the file itself
+        let write = || -> std::io::Result<()> {
+            if !append {
+                return self.write_file(Path::new(path), text);
+            }
+            let mut writer = BufWriter::new(OpenOptions::new().append(true).open(path)?);
+            for chunk in text.chunks() {
+                writer.write_all(chunk.as_bytes())?;
+            }
+            writer.flush()
+        };
+        write().map_err(|err| format!("Cannot write {}: {}", path, err))?;
+        Ok(format!(
+            "{:?} {} {}",
+            path,
+            plural(last + 1 - first, "line"),
+            if append { "appended" } else { "written" }
+        ))
+    }
+    // Runs `/pattern/replacement/flags` over lines `first..=last`, returning
+    // the report and the last line changed
+    fn substitute(
+        &mut self,
+        first: usize,
+        last: usize,
+        args: &str,
+    ) -> Result<(String, usize), String> {
+        let delimiter = args
+            .chars()
+            .next()
+            .ok_or("No previous substitute pattern")?;
+        let (pattern, rest) = split_delimited(&args[delimiter.len_utf8()..], delimiter);
+        let (replacement, flags) = split_delimited(rest, delimiter);
+        if pattern.is_empty() {
+            return Err("No previous substitute pattern".to_string());
+        }
+        let flags = flags.trim();
+        if let Some(flag) = flags.chars().find(|flag| !matches!(flag, 'g' | 'c')) {
+            return Err(format!("Unsupported :s flag {}", flag));
+        }
+        self.last_substitute = Some((pattern.clone(), replacement.clone(), flags.to_string()));
+        self.substitute_with(first, last, pattern, replacement, flags)
+    }
+    // The substitution `:s` asks for, once taken apart and its flags checked
+    fn substitute_with(
+        &mut self,
+        first: usize,
+        last: usize,
+        pattern: String,
+        replacement: String,
+        flags: &str,
+    ) -> Result<(String, usize), String> {
+        let global = flags.contains('g');
+        let regex = Regex::new(&pattern)?;
+        if flags.contains('c') {
+            self.confirm = Some(Confirm {
+                regex,
+                replacement,
+                global,
+                last,
+                line: first,
+                caps: Vec::new(),
+                substitutions: 0,
+                changed_lines: 0,
+                last_changed: None,
+            });
+            if !self.confirm_find(self.text.line_to_char(first))? {
+                self.confirm = None;
+                return Err(format!("Pattern not found: {}", pattern));
+            }
+            self.mode = "Confirm";
+            return Ok((self.confirm_prompt(), first));
+        }
+
+        let mut substitutions = 0;
+        let mut lines = Vec::new();
+        let mut progress = Progress::new("Substituting");
+        let mut interrupted = false;
+        // Bottom up, so replacements adding lines keep the indices above valid
+        for line in (first..=last).rev() {
+            if self.show_progress(&mut progress, last - line, last + 1 - first) {
+                interrupted = true;
+                break;
+            }
+            let chars = self.line_chars(line);
+            let (result, replaced) = substitute_line(&chars, &regex, &replacement, global);
+            let count = replaced.len();
+            if count == 0 {
+                continue;
+            }
+            let line_start = self.text.line_to_char(line);
+            self.remove(line_start..line_start + chars.len());
+            self.insert(line_start, &result);
+            substitutions += count;
+            lines.push(line);
+        }
+        // The lines done before an interruption stay done
+        let report = format!(
+            "{}{} on {}",
+            if interrupted { "Interrupted, " } else { "" },
+            plural(substitutions, "substitution"),
+            plural(lines.len(), "line")
+        );
+        match lines.first() {
+            Some(&line) => Ok((report, line)),
+            None if interrupted => Err("Interrupted".to_string()),
+            None => Err(format!("Pattern not found: {}", pattern)),
+        }
+    }
+    // `&` on the cursor line without the flags, or `g&` on every line with
+    // them
+    fn repeat_substitute(&mut self, everywhere: bool) -> std::io::Result<()> {
+        let Some((pattern, replacement, flags)) = self.last_substitute.clone() else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No previous substitute pattern");
+            return Ok(());
+        };
+        let line = self.cursor_line();
+        let result = if everywhere {
+            let last = self.line_count().saturating_sub(1);
+            self.substitute_with(0, last, pattern, replacement, &flags)
+        } else {
+            self.substitute_with(line, line, pattern, replacement, "")
+        };
+        self.show_substitute(result)
+    }
+    // Reports how a substitution went, and goes to the last line changed
+    fn show_substitute(&mut self, result: Result<(String, usize), String>) -> std::io::Result<()> {
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        match result {
+            // Confirming leaves the cursor on the first match
+            Ok((message, _)) if self.mode == "Confirm" => {
+                self.cmd_message.insert(0, &message);
+            }
+            Ok((message, line)) => {
+                self.cmd_message.insert(0, &message);
+                self.move_cursor(line, self.first_non_blank(line))?;
+            }
+            Err(err) => self.command_error(&err),
+        }
+        Ok(())
+    }
+    // Finds the first match for `:s///c` at or after char `from`, and puts
+    // the cursor on it. False when there are no more.
+    fn confirm_find(&mut self, from: usize) -> Result<bool, String> {
+        let Some(confirm) = self.confirm.as_ref() else {
+            return Ok(false);
+        };
+        let mut line = self.text.char_to_line(from);
+        let mut col = from - self.text.line_to_char(line);
+        while line <= confirm.last.min(self.line_count().saturating_sub(1)) {
+            let chars = self.line_chars(line);
+            if col <= chars.len() {
+                if let Some(caps) = confirm.regex.captures_at(&chars, col) {
+                    let (start, _) = caps[0].unwrap();
+                    let confirm = self.confirm.as_mut().unwrap();
+                    confirm.line = line;
+                    confirm.caps = caps;
+                    self.move_cursor(line, start)
+                        .map_err(|err| err.to_string())?;
+                    return Ok(true);
                 }
-                (event::KeyCode::Esc, "Command") => {
-                    editor.mode = "Normal";
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
+            }
+            line += 1;
+            col = 0;
+        }
+        Ok(false)
+    }
+    fn confirm_prompt(&self) -> String {
+        let replacement = self
+            .confirm
+            .as_ref()
+            .map_or("", |confirm| confirm.replacement.as_str());
+        format!("Replace with {} (y/n/a/q/l)?", replacement)
+    }
+    // The current `:s///c` match as a char range
+    fn confirm_range(&self) -> Option<std::ops::Range<usize>> {
+        let confirm = self.confirm.as_ref()?;
+        let (start, end) = confirm.caps.first().copied().flatten()?;
+        let line_start = self.text.line_to_char(confirm.line);
+        Some(line_start + start..line_start + end)
+    }
+    // Where to look for the next `:s///c` match after the current one, once
+    // it is replaced (or skipped, if not `replace`)
+    fn confirm_answer(&mut self, replace: bool) -> usize {
+        let confirm = self.confirm.as_ref().unwrap();
+        let (start, end) = confirm.caps[0].unwrap();
+        let line_start = self.text.line_to_char(confirm.line);
+        let mut next = line_start + end;
+        if replace {
+            let chars = self.line_chars(confirm.line);
+            let mut result = String::new();
+            expand_replacement(&confirm.replacement, &chars, &confirm.caps, &mut result);
+            let added_lines = result.matches('\n').count();
+            let line = confirm.line;
+            self.remove(line_start + start..line_start + end);
+            self.insert(line_start + start, &result);
+            next = line_start + start + result.chars().count();
+
+            let confirm = self.confirm.as_mut().unwrap();
+            confirm.last += added_lines;
+            confirm.substitutions += 1;
+            if confirm.last_changed != Some(line) {
+                confirm.changed_lines += 1;
+            }
+            confirm.last_changed = Some(line + added_lines);
+        }
+        let confirm = self.confirm.as_ref().unwrap();
+        if !confirm.global {
+            let line = self.text.char_to_line(next);
+            return self
+                .text
+                .line_to_char((line + 1).min(self.text.len_lines()));
+        }
+        // Step over a char after an empty match to make progress
+        if start == end {
+            next += 1;
+        }
+        next
+    }
+    // A key in Confirm mode, answering about the current match
+    fn confirm_key(&mut self, c: char) -> Result<(), String> {
+        let more = match c {
+            'y' | 'n' => {
+                let next = self.confirm_answer(c == 'y');
+                self.confirm_find(next)?
+            }
+            'a' => {
+                loop {
+                    let next = self.confirm_answer(true);
+                    if !self.confirm_find(next)? {
+                        break;
+                    }
+                }
+                false
+            }
+            'l' => {
+                self.confirm_answer(true);
+                false
+            }
+            'q' => false,
+            _ => return Ok(()),
+        };
+        if more {
+            let prompt = self.confirm_prompt();
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, &prompt);
+        } else {
+            self.confirm_done();
+        }
+        Ok(())
+    }
+    fn confirm_done(&mut self) {
+        self.mode = "Normal";
+        self.pending_write = None;
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        if let Some(confirm) = self.confirm.take() {
+            if confirm.substitutions > 0 {
+                self.cmd_message.insert(
+                    0,
+                    &format!(
+                        "{} on {}",
+                        plural(confirm.substitutions, "substitution"),
+                        plural(confirm.changed_lines, "line")
+                    ),
+                );
+            }
+        }
+    }
+    // Runs `command` through the shell, returning everything it printed
+    fn run_shell(&mut self, command: &str) -> std::io::Result<String> {
+        set_raw_mode(false)?;
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::null())
+            .output();
+        set_raw_mode(true)?;
+
+        Ok(match output {
+            Ok(output) => {
+                let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+                text.push_str(&String::from_utf8_lossy(&output.stderr));
+                if !output.status.success() {
+                    if !text.is_empty() && !text.ends_with('\n') {
+                        text.push('\n');
+                    }
+                    text.push_str(&format!(
+                        "shell returned {}",
+                        output.status.code().unwrap_or(-1)
+                    ));
+                }
+                text
+            }
+            Err(err) => format!("Cannot run {}: {}", command, err),
+        })
+    }
+    // Shows `text` in the command view if it fits on one row, otherwise in
+    // the full screen pager
+    fn show_output(&mut self, text: &str) -> std::io::Result<()> {
+        let (cols, _) = terminal_size()?;
+        let text = text.strip_suffix('\n').unwrap_or(text);
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        if !text.contains('\n') && text.chars().count() < cols as usize {
+            self.cmd_message.insert(0, text);
+            return Ok(());
+        }
+        let mut lines = Vec::new();
+        for line in text.lines() {
+            // Expand tabs and wrap long lines at the screen edge
+            let mut row = String::new();
+            for c in line.chars() {
+                if c == '\t' {
+                    let width = self.options.tabstop - row.chars().count() % self.options.tabstop;
+                    row.push_str(&" ".repeat(width));
+                } else {
+                    row.push(c);
+                }
+            }
+            let chars: Vec<char> = row.chars().collect();
+            if chars.is_empty() {
+                lines.push(String::new());
+            }
+            for chunk in chars.chunks(cols.max(1) as usize) {
+                lines.push(chunk.iter().collect());
+            }
+        }
+        self.pager = Pager { lines, top: 0 };
+        self.mode = "Pager";
+        Ok(())
+    }
+    // Replaces lines `first..=last` with the output of `command` run with them
+    // as its input. The buffer is left alone if the command fails.
+    fn filter_lines(&mut self, first: usize, last: usize, command: &str) -> Result<String, String> {
+        let start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last + 1);
+        let input = self.text.slice(start..end).to_string();
+
+        set_raw_mode(false).map_err(|err| err.to_string())?;
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                // Feed stdin from a thread so a command that writes before it
+                // has read everything can't deadlock us
+                let mut stdin = child.stdin.take().unwrap();
+                let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+                let output = child.wait_with_output();
+                let _ = writer.join();
+                output
+            });
+        set_raw_mode(true).map_err(|err| err.to_string())?;
+
+        let output = output.map_err(|err| format!("Cannot run {}: {}", command, err))?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(match stderr.lines().next() {
+                Some(line) => line.to_string(),
+                None => format!("shell returned {}", output.status.code().unwrap_or(-1)),
+            });
+        }
+        let mut replacement = String::from_utf8_lossy(&output.stdout).into_owned();
+        if end > start && self.text.char(end - 1) == '\n' && !replacement.ends_with('\n') {
+            replacement.push('\n');
+        }
+        self.remove(start..end);
+        self.insert(start, &replacement);
+        Ok(format!("{} filtered", plural(last + 1 - first, "line")))
+    }
+    fn reverse_lines(&mut self, first: usize, last: usize) {
+        let lines: Vec<String> = (first..=last)
+            .rev()
+            .map(|line| self.line_chars(line).into_iter().collect())
+            .collect();
+        let start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last) + self.line_len(last);
+        self.remove(start..end);
+        self.insert(start, &lines.join("\n"));
+    }
+    // `:sort`: sorts `first..=last` in place, backwards if `reverse`. Flag `n`
+    // sorts by the first number in each line, with lines that have none
+    // first, `i` ignores case and `u` keeps only the first of equal lines.
+    fn sort_lines(
+        &mut self,
+        first: usize,
+        last: usize,
+        reverse: bool,
+        flags: &str,
+    ) -> Result<String, String> {
+        if let Some(flag) = flags.chars().find(|flag| !matches!(flag, 'n' | 'i' | 'u')) {
+            return Err(format!("Invalid flag for :sort: {}", flag));
+        }
+        let (numeric, ignore_case) = (flags.contains('n'), flags.contains('i'));
+        let key = |line: &String| -> (Option<i64>, String) {
+            if numeric {
+                (first_number(line), String::new())
+            } else if ignore_case {
+                (None, line.to_lowercase())
+            } else {
+                (None, line.clone())
+            }
+        };
+        let mut lines: Vec<(_, String)> = (first..=last)
+            .map(|line| {
+                let text: String = self.line_chars(line).into_iter().collect();
+                (key(&text), text)
+            })
+            .collect();
+        lines.sort_by(|(a, _), (b, _)| if reverse { b.cmp(a) } else { a.cmp(b) });
+        let count = lines.len();
+        // Like vim, only lines that are the same throughout are duplicates,
+        // not ones with just the same number
+        if flags.contains('u') {
+            lines.dedup_by(|(_, a), (_, b)| {
+                if ignore_case {
+                    a.to_lowercase() == b.to_lowercase()
+                } else {
+                    a == b
+                }
+            });
+        }
+        let lines: Vec<String> = lines.into_iter().map(|(_, text)| text).collect();
+        let start = self.text.line_to_char(first);
+        let end = self.text.line_to_char(last) + self.line_len(last);
+        let sorted = lines.join("\n");
+        // Lines already in order are left alone, and the buffer unmodified
+        if self.text.slice(start..end) != sorted.as_str() {
+            self.remove(start..end);
+            self.insert(start, &sorted);
+        }
+        Ok(match count - lines.len() {
+            0 if flags.contains('u') => "No duplicate lines".to_string(),
+            0 => String::new(),
+            removed => format!("{} removed", plural(removed, "duplicate line")),
+        })
+    }
+    // Rewrites the indentation of `first..=last` with tabs or spaces as
+    // `expandtab` says, keeping its width. With `all`, whitespace with tabs in
+    // it after the indentation too. A new `tabstop` is set first, with the
+    // widths still worked out at the old one.
+    fn retab(&mut self, first: usize, last: usize, all: bool, tabstop: Option<usize>) -> usize {
+        let old_tabstop = self.options.tabstop;
+        let new_tabstop = tabstop.unwrap_or(old_tabstop);
+        self.options.tabstop = new_tabstop;
+        let mut changed = 0;
+        for line in first..=last {
+            let chars = self.line_chars(line);
+            let mut result = String::new();
+            let mut width = 0;
+            let mut col = 0;
+            while col < chars.len() {
+                if chars[col] != ' ' && chars[col] != '\t' {
+                    result.push(chars[col]);
+                    width += 1;
+                    col += 1;
+                    continue;
+                }
+                let run_start = col;
+                let start_width = width;
+                while col < chars.len() && (chars[col] == ' ' || chars[col] == '\t') {
+                    width += if chars[col] == '\t' {
+                        old_tabstop - width % old_tabstop
+                    } else {
+                        1
+                    };
+                    col += 1;
+                }
+                let run = &chars[run_start..col];
+                if run_start > 0 && !(all && run.contains(&'\t')) {
+                    result.extend(run);
+                } else if self.options.expandtab {
+                    result.push_str(&" ".repeat(width - start_width));
+                } else {
+                    let mut at = start_width;
+                    while (at / new_tabstop + 1) * new_tabstop <= width {
+                        result.push('\t');
+                        at = (at / new_tabstop + 1) * new_tabstop;
+                    }
+                    result.push_str(&" ".repeat(width - at));
+                }
+            }
+            if result.chars().eq(chars.iter().copied()) {
+                continue;
+            }
+            let line_start = self.text.line_to_char(line);
+            self.remove(line_start..line_start + chars.len());
+            self.insert(line_start, &result);
+            changed += 1;
+        }
+        changed
+    }
+    // Breaks the cursor line at the last blank that keeps it within
+    // `textwidth`, once typing has taken it past that
+    fn wrap_typed_line(&mut self) -> std::io::Result<()> {
+        let textwidth = self.options.textwidth;
+        let line = self.cursor_line();
+        let chars = self.line_chars(line);
+        if textwidth == 0 || self.display_col(line, chars.len()) <= textwidth {
+            return Ok(());
+        }
+        let is_blank = |c: char| c == ' ' || c == '\t';
+        let indent_len = self.first_non_blank(line);
+        let cursor = self.cursor_col as usize;
+        let Some(blank) = (indent_len..cursor.min(chars.len()))
+            .rev()
+            .find(|col| is_blank(chars[*col]) && self.display_col(line, *col) <= textwidth)
+        else {
+            return Ok(());
+        };
+        let mut start = blank;
+        while start > indent_len && is_blank(chars[start - 1]) {
+            start -= 1;
+        }
+        let end = blank + 1;
+        let indent = if self.options.autoindent {
+            self.leading_whitespace(line)
+        } else {
+            String::new()
+        };
+        let line_start = self.text.line_to_char(line);
+        self.remove(line_start + start..line_start + end);
+        self.insert(line_start + start, &format!("\n{}", indent));
+        self.move_cursor(line + 1, indent.chars().count() + cursor - end)
+    }
+    fn indent_lines(&mut self, first: usize, last: usize, dedent: bool) {
+        for line in first..=last.min(self.text.len_lines() - 1) {
+            if self.line_len(line) == 0 {
+                continue;
+            }
+            let width = self.display_col(line, self.first_non_blank(line));
+            let width = if dedent {
+                width.saturating_sub(self.options.shiftwidth)
+            } else {
+                width + self.options.shiftwidth
+            };
+            self.set_indent(line, width);
+        }
+    }
+    // Ctrl-T and Ctrl-D in Insert mode: indents the cursor line to the next
+    // multiple of shiftwidth (or dedents it to the one before), keeping the
+    // cursor on the same char
+    fn shift_insert_line(&mut self, dedent: bool) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let old_indent = self.first_non_blank(line);
+        let width = self.display_col(line, old_indent);
+        let shiftwidth = self.options.shiftwidth.max(1);
+        let width = if dedent {
+            (width.saturating_sub(1) / shiftwidth) * shiftwidth
+        } else {
+            (width / shiftwidth + 1) * shiftwidth
+        };
+        self.set_indent(line, width);
+        let new_indent = self.first_non_blank(line);
+        let col = (self.cursor_col as usize).max(old_indent) - old_indent + new_indent;
+        self.move_cursor(line, col)
+    }
+    fn set_option(&mut self, arg: &str) -> Result<(), String> {
+        match arg.split_once('=') {
+            Some(("filetype" | "ft", filetype)) => {
+                self.filetype = filetype.to_string();
+                self.config.apply(
+                    &self.filetype,
+                    self.indent,
+                    self.large_file,
+                    &mut self.options,
+                )
+            }
+            _ => self.options.set(arg),
+        }
+    }
+    // Whether the last line has no line ending, as it will be written. Not
+    // known until the whole file is loaded.
+    fn noeol(&self) -> bool {
+        self.loader.is_none()
+            && self.text.len_chars() > 0
+            && self.text.char(self.text.len_chars() - 1) != '\n'
+    }
+    // `dos` when the first line ends with CRLF, `unix` otherwise. The text
+    // keeps its line endings as they are, so they are written out the same.
+    fn fileformat(&self) -> &'static str {
+        if self.text.line(0).to_string().ends_with("\r\n") {
+            "dos"
+        } else {
+            "unix"
+        }
+    }
+    // Gives every line the line ending of `format`, saying how many changed
+    fn set_fileformat(&mut self, format: &str) -> Result<String, String> {
+        let dos = match format {
+            "unix" => false,
+            "dos" => true,
+            _ => return Err(format!("Invalid value for fileformat: {}", format)),
+        };
+        if !self.finish_loading() {
+            return Err("Interrupted, only part of the file is loaded".to_string());
+        }
+        let mut changed = 0;
+        // From the bottom up so the lines still to do stay where they are
+        for line in (0..self.line_count()).rev() {
+            let end = self.text.line_to_char(line) + self.line_len(line);
+            if self.text.get_char(end) != Some('\n') {
+                continue;
+            }
+            let crlf = end > 0 && self.text.char(end - 1) == '\r';
+            if dos && !crlf {
+                self.insert(end, "\r");
+            } else if !dos && crlf {
+                self.remove(end - 1..end);
+            } else {
+                continue;
+            }
+            changed += 1;
+        }
+        Ok(format!(
+            "Converted {} to {} line endings",
+            plural(changed, "line"),
+            format
+        ))
+    }
+    // Runs `:set` arguments, returning the values asked for with `name?`
+    fn set_options(&mut self, args: &[&str]) -> Result<String, String> {
+        let mut values = Vec::new();
+        for arg in args {
+            if let Some(name) = arg.strip_suffix('?') {
+                let value = if name == "filetype" || name == "ft" {
+                    Some(format!("filetype={}", self.filetype))
+                } else if name == "fileformat" || name == "ff" {
+                    Some(format!("fileformat={}", self.fileformat()))
+                } else {
+                    self.options.get(name)
+                };
+                values.push(value.ok_or(format!("Unknown option: {}", name))?);
+            } else if let Some(("fileformat" | "ff", format)) = arg.split_once('=') {
+                values.push(self.set_fileformat(format)?);
+            } else {
+                self.set_option(arg)?;
+            }
+        }
+        Ok(values.join(" "))
+    }
+    // Ctrl-R in Insert mode: types what register `name` holds at the cursor
+    fn insert_register(&mut self, name: char) -> std::io::Result<()> {
+        if !is_register_name(name) {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, &format!("Unknown register {}", name));
+            return Ok(());
+        }
+        let text = self.register(name).text;
+        self.insert_text(&text)
+    }
+    // Ctrl-V in Insert mode, with `typed` the keys after it so far: the next
+    // key goes in as it is, or a char by its code as 3 decimal digits, `o`
+    // and 3 octal ones, `x` and 2 hex ones, `u` and 4 or `U` and 8. A key
+    // that is not a digit ends the code early and then does what it does.
+    fn insert_literal(&mut self, keyev: event::KeyEvent, typed: &str) -> std::io::Result<bool> {
+        let key = literal_key(keyev);
+        let (radix, max, digits) = match typed.chars().next() {
+            Some('u') => (16, 4, &typed[1..]),
+            Some('U') => (16, 8, &typed[1..]),
+            Some('x' | 'X') => (16, 2, &typed[1..]),
+            Some('o' | 'O') => (8, 3, &typed[1..]),
+            Some(_) => (10, 3, typed),
+            None => {
+                match key {
+                    Some(c) if "uUxXoO".contains(c) || c.is_ascii_digit() => {
+                        self.pending = format!("\u{16}{}", c);
+                    }
+                    Some(c) => self.insert_typed(c)?,
+                    None => {}
+                }
+                return Ok(false);
+            }
+        };
+        if let Some(digit) = key.filter(|c| c.is_digit(radix)) {
+            if digits.len() + 1 < max {
+                self.pending = format!("\u{16}{}{}", typed, digit);
+            } else if let Some(c) = char_from_code(&format!("{}{}", digits, digit), radix) {
+                self.insert_typed(c)?;
+            }
+            return Ok(false);
+        }
+        // A letter with no digits after it was only itself
+        let c = if digits.is_empty() {
+            typed.chars().next()
+        } else {
+            char_from_code(digits, radix)
+        };
+        if let Some(c) = c {
+            self.insert_typed(c)?;
+        }
+        self.dispatch_key(keyev)
+    }
+    // Puts `c` at the cursor and the cursor after it
+    fn insert_typed(&mut self, c: char) -> std::io::Result<()> {
+        self.insert_text(&c.to_string())
+    }
+    // Puts `text` at the cursor and the cursor after it
+    fn insert_text(&mut self, text: &str) -> std::io::Result<()> {
+        let pos = self.text.line_to_char(self.cursor_line()) + self.cursor_col as usize;
+        self.insert(pos, text);
+        let end = pos + text.chars().count();
+        let end_line = self.text.char_to_line(end);
+        self.move_cursor(end_line, end - self.text.line_to_char(end_line))
+    }
+    // `:put`: pastes register `name` as whole lines below `line`, or above
+    fn put(&mut self, line: usize, above: bool, name: Option<char>) -> Result<(), String> {
+        let name = name
+            .filter(|name| is_register_name(*name))
+            .ok_or("Give one register name to :put")?;
+        let mut text = self.register(name).text;
+        if text.is_empty() {
+            return Err(format!("Nothing in register {}", name));
+        }
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        let error = |err: std::io::Error| err.to_string();
+        self.move_cursor(line, 0).map_err(error)?;
+        self.paste_lines(text, above).map_err(error)?;
+        let line = self.cursor_line();
+        self.move_cursor(line, self.first_non_blank(line))
+            .map_err(error)
+    }
+    fn paste(&mut self, before: bool) -> std::io::Result<()> {
+        let register = self.picked_register();
+        if register.text.is_empty() {
+            return Ok(());
+        }
+        let line = self.cursor_line();
+        if register.linewise {
+            self.paste_lines(register.text, before)?;
+        } else {
+            let col = if before || self.line_len(line) == 0 {
+                self.cursor_col as usize
+            } else {
+                self.cursor_col as usize + 1
+            };
+            let pos = self.text.line_to_char(line) + col;
+            let text = register.text;
+            let len = text.chars().count();
+            self.insert(pos, &text);
+            let end = pos + len - 1;
+            let end_line = self.text.char_to_line(end);
+            self.move_cursor(end_line, end - self.text.line_to_char(end_line))?;
+        }
+        Ok(())
+    }
+    // Puts whole `lines` (ending with a newline) below or above the cursor line
+    fn paste_lines(&mut self, mut lines: String, before: bool) -> std::io::Result<()> {
+        let line = self.cursor_line();
+        let target = if before { line } else { line + 1 };
+        let mut pos = self.text.line_to_char(target);
+        // Pasting below a last line without a newline needs one first
+        if target == self.text.len_lines() {
+            pos = self.text.len_chars();
+            lines.insert(0, '\n');
+            lines.pop();
+        }
+        self.insert(pos, &lines);
+        self.move_cursor(target, 0)
+    }
+    // `]p` and `[p`: pastes lines reindented to match the cursor line
+    fn paste_indented(&mut self, before: bool) -> std::io::Result<()> {
+        let register = self.picked_register();
+        if !register.linewise {
+            return self.paste(before);
+        }
+        let line = self.cursor_line();
+        let target_width = self.display_col(line, self.first_non_blank(line));
+        let tabstop = self.options.tabstop;
+        let indent_width = |line: &str| {
+            line.chars()
+                .take_while(|c| *c == ' ' || *c == '\t')
+                .fold(0, |width, c| {
+                    if c == '\t' {
+                        width + tabstop - width % tabstop
+                    } else {
+                        width + 1
+                    }
+                })
+        };
+        let lines: Vec<&str> = register.text.lines().collect();
+        let common = lines
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| indent_width(line))
+            .min()
+            .unwrap_or(0);
+        let mut text = String::new();
+        for line in lines {
+            let rest = line.trim_start_matches([' ', '\t']);
+            if !rest.is_empty() {
+                let width = indent_width(line) - common + target_width;
+                text.push_str(&self.indent_string(width));
+                text.push_str(rest);
+            }
+            text.push('\n');
+        }
+        self.paste_lines(text, before)?;
+        let line = self.cursor_line();
+        self.move_cursor(line, self.first_non_blank(line))
+    }
+    // Where `backup` keeps the copy of `path` made before the first write
+    fn backup_path(&self, path: &std::path::Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(&self.options.backupext);
+        if self.options.backupdir.is_empty() {
+            path.with_file_name(name)
+        } else {
+            PathBuf::from(&self.options.backupdir).join(name)
+        }
+    }
+    // Writes `text` to a temporary file next to `path` and renames it over
+    // `path`, so an interrupted write never leaves a truncated file
+    fn write_file(&self, path: &std::path::Path, text: ropey::RopeSlice) -> std::io::Result<()> {
+        // Write through symlinks rather than replacing them
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{}.owl-{}", name, std::process::id()));
+        let write = || -> std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&temp)?);
+            for chunk in text.chunks() {
+                writer.write_all(chunk.as_bytes())?;
+            }
+            let file = writer.into_inner().map_err(|err| err.into_error())?;
+            if let Ok(metadata) = std::fs::metadata(&path) {
+                file.set_permissions(metadata.permissions())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::MetadataExt;
+                    // Only root can give the file away, keep going if that fails
+                    let _ = std::os::unix::fs::fchown(
+                        &file,
+                        Some(metadata.uid()),
+                        Some(metadata.gid()),
+                    );
+                }
+            }
+            file.sync_all()?;
+            std::fs::rename(&temp, &path)
+        };
+        let result = write();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result
+    }
+    // Reads more of a lazily loaded file, until it has `line` or runs out.
+    // False when that was interrupted.
+    fn load_until(&mut self, line: usize) -> bool {
+        let mut progress = Progress::new("Loading");
+        while self.text.len_lines() <= line {
+            let Some(size) = self.loader.as_ref().map(|loader| loader.size) else {
+                return true;
+            };
+            if self.show_progress(&mut progress, self.text.len_bytes(), size as usize) {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message
+                    .insert(0, "Interrupted, only part of the file is loaded");
+                return false;
+            }
+            match self.loader.as_ref().and_then(Loader::next) {
+                Some(Ok(chunk)) => {
+                    let end = self.text.len_chars();
+                    self.text.insert(end, &chunk);
+                }
+                Some(Err(err)) => {
+                    // Writing now would cut the file short
+                    self.readonly = true;
+                    self.loader = None;
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert(
+                        0,
+                        &format!("Cannot read the rest of the file, it is readonly: {}", err),
+                    );
+                }
+                None => self.loader = None,
+            }
+        }
+        true
+    }
+    // For whatever needs the whole file, like `G`, ex commands and saving.
+    // False when Esc or Ctrl-C stopped it short.
+    fn finish_loading(&mut self) -> bool {
+        self.load_until(usize::MAX)
+    }
+    // Shows now and then how far `progress` has got, `done` of `total`, once
+    // it has gone on for a moment. True when Esc or Ctrl-C asks to stop it,
+    // other events wait until it is done.
+    fn show_progress(&mut self, progress: &mut Progress, done: usize, total: usize) -> bool {
+        if progress.started.elapsed() < PROGRESS_DELAY
+            || progress.checked.elapsed() < PROGRESS_INTERVAL
+        {
+            return false;
+        }
+        progress.checked = Instant::now();
+        while event::poll(Duration::ZERO).unwrap_or(false) {
+            match event::read() {
+                Ok(event::Event::Key(keyev))
+                    if keyev.code == event::KeyCode::Esc || is_ctrl(keyev, 'c') =>
+                {
+                    // What was typed ahead was meant for after it
+                    self.typeahead.clear();
+                    return true;
+                }
+                Ok(ev) => self.typeahead.push_back(ev),
+                Err(_) => break,
+            }
+        }
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(
+            0,
+            &format!("{}... {}%", progress.what, done * 100 / total.max(1)),
+        );
+        // Only a glimpse of where it has got, a render that fails is no
+        // reason to stop
+        let _ = self.render();
+        false
+    }
+    // Whether `path` is a file that exists other than the one being edited
+    fn is_other_file(&self, path: &str) -> bool {
+        let Ok(path) = std::fs::canonicalize(path) else {
+            return false;
+        };
+        self.filename
+            .as_ref()
+            .and_then(|filename| std::fs::canonicalize(filename).ok())
+            != Some(path)
+    }
+    // `:w` with its file name and range, if it was given them. False when
+    // nothing was written.
+    fn write_to(&mut self, path: Option<String>, lines: Option<(usize, usize)>) -> bool {
+        if let (Some(path), Some((first, last))) = (path.as_ref(), lines) {
+            let result = self.write_lines(first, last, path, false);
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            return match result {
+                Ok(message) => {
+                    self.cmd_message.insert(0, &message);
+                    true
+                }
+                Err(err) => {
+                    self.cmd_message.insert(0, &err);
+                    false
+                }
+            };
+        }
+        if let Some(path) = path {
+            // Readonly is about the file that was opened
+            self.filename = Some(PathBuf::from(path));
+            self.readonly = false;
+        }
+        self.save()
+    }
+    fn save(&mut self) -> bool {
+        if !self.finish_loading() {
+            return false;
+        }
+        if self.readonly {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, "The file is readonly, give another name to write it");
+            return false;
+        }
+        if self.options.fixendofline && self.noeol() {
+            let ending = if self.fileformat() == "dos" {
+                "\r\n"
+            } else {
+                "\n"
+            };
+            self.insert(self.text.len_chars(), ending);
+        }
+        let mut backup_error = None;
+        if let Some(pathbuf) = self.filename.as_ref() {
+            if self.options.backup && !self.backed_up && pathbuf.exists() {
+                let backup = self.backup_path(pathbuf);
+                match std::fs::copy(pathbuf, &backup) {
+                    Ok(_) => self.backed_up = true,
+                    Err(err) => {
+                        backup_error = Some(format!("Cannot write backup {:?}: {}", backup, err))
+                    }
+                }
+            }
+        }
+        if let Some(pathbuf) = self.filename.as_ref() {
+            if let Err(err) = self.write_file(pathbuf, self.text.slice(..)) {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message
+                    .insert(0, &format!("Cannot write {:?}: {}", pathbuf, err));
+                return false;
+            }
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, &format!("{:?} written", self.filename.as_ref().unwrap()));
+            self.dirty = false;
+            self.history.commit();
+            if let Err(err) = self.history.save(pathbuf, &self.text.to_string()) {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message
+                    .insert(0, &format!("Cannot save undo history: {}", err));
+            }
+            self.sync_lsp();
+            if let Some(Err(err)) = self
+                .lsp
+                .as_mut()
+                .filter(|client| client.uri.is_some())
+                .map(|client| client.save())
+            {
+                self.stop_lsp(err);
+            }
+            // The file is written either way, but say why there's no backup
+            if let Some(err) = backup_error {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(0, &err);
+            }
+            true
+        } else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, "Cannot save file without a name");
+            false
+        }
+    }
+    // How much longer the editor can sit idle, after `idle` already, before
+    // the `autosave` option saves the changes, if it would
+    fn autosave_in(&self, idle: Duration) -> Option<Duration> {
+        let wanted = self.options.autosave > 0
+            && self.dirty
+            && self.filename.is_some()
+            && !self.readonly
+            && !matches!(self.mode, "Insert" | "Command");
+        wanted.then(|| Duration::from_millis(self.options.autosave as u64).saturating_sub(idle))
+    }
+    // Starts, stops or restarts the language server to follow the `lsp`
+    // option, then sends it the text if that has changed
+    fn sync_lsp(&mut self) {
+        // Lazily loaded files are too big to hand over
+        let command = match self.filename {
+            Some(_) if self.loader.is_none() && !self.large_file => self.options.lsp.clone(),
+            _ => String::new(),
+        };
+        if self
+            .lsp
+            .as_ref()
+            .map_or("", |client| client.command.as_str())
+            != command
+        {
+            self.lsp = None;
+            if !command.is_empty() {
+                match lsp::Client::start(&command) {
+                    Ok(client) => self.lsp = Some(client),
+                    Err(err) => {
+                        // Not tried again until the option is set again
+                        self.options.lsp.clear();
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message.insert(
+                            0,
+                            &format!("Cannot start language server {}: {}", command, err),
+                        );
+                    }
+                }
+            }
+        }
+        if let Err(err) = self.send_text_to_lsp() {
+            self.stop_lsp(err);
+        }
+    }
+    fn send_text_to_lsp(&mut self) -> std::io::Result<()> {
+        let (Some(client), Some(filename)) = (self.lsp.as_mut(), self.filename.as_ref()) else {
+            return Ok(());
+        };
+        if !client.ready {
+            return Ok(());
+        }
+        let uri = lsp::path_to_uri(filename);
+        if client.uri.as_ref() != Some(&uri) {
+            let language = lsp::language_id(&self.filetype);
+            client.open(uri, language, &self.text.to_string())?;
+        } else if client.synced != self.changes {
+            client.change(&self.text.to_string())?;
+        }
+        client.synced = self.changes;
+        Ok(())
+    }
+    fn stop_lsp(&mut self, err: std::io::Error) {
+        self.lsp = None;
+        // Or it would be started again straight away
+        self.options.lsp.clear();
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message
+            .insert(0, &format!("Language server stopped: {}", err));
+    }
+    // Takes in what the language server has sent, returning whether there
+    // was anything
+    fn handle_lsp_messages(&mut self) -> bool {
+        let mut handled = false;
+        loop {
+            let Some(client) = self.lsp.as_mut() else {
+                return handled;
+            };
+            let message = match client.next() {
+                Ok(Some(message)) => message,
+                Ok(None) => return handled,
+                Err(err) => {
+                    self.stop_lsp(err);
+                    return true;
+                }
+            };
+            handled = true;
+            match message {
+                lsp::Message::Response {
+                    method: "initialize",
+                    error: Some(err),
+                    ..
+                } => {
+                    self.stop_lsp(std::io::Error::other(err));
+                    return true;
+                }
+                lsp::Message::Response {
+                    method: "textDocument/definition",
+                    result,
+                    error,
+                } => {
+                    if let Err(err) = self.jump_to_definition(&result, error) {
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message
+                            .insert(0, &format!("Cannot open the definition: {}", err));
+                    }
+                }
+                lsp::Message::Response {
+                    method: "textDocument/hover",
+                    result,
+                    error,
+                } => {
+                    if let Err(err) = self.show_hover(&result, error) {
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message.insert(0, &err.to_string());
+                    }
+                }
+                lsp::Message::Notification { method, params }
+                    if method == "textDocument/publishDiagnostics" =>
+                {
+                    self.set_diagnostics(&params);
+                }
+                _ => {}
+            }
+        }
+    }
+    fn set_diagnostics(&mut self, params: &Json) {
+        let Some(client) = self.lsp.as_ref() else {
+            return;
+        };
+        if params.get("uri").as_str() != client.uri.as_deref() {
+            return;
+        }
+        let diagnostics = params
+            .get("diagnostics")
+            .as_array()
+            .iter()
+            .map(|diagnostic| lsp::Diagnostic {
+                start: self.lsp_pos(diagnostic.get("range").get("start")),
+                end: self.lsp_pos(diagnostic.get("range").get("end")),
+                severity: diagnostic.get("severity").as_usize().unwrap_or(lsp::ERROR),
+                message: diagnostic
+                    .get("message")
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            })
+            .collect();
+        self.lsp.as_mut().unwrap().diagnostics = diagnostics;
+    }
+    // The first diagnostic on `line`
+    fn diagnostic_at(&self, line: usize) -> Option<&lsp::Diagnostic> {
+        self.lsp
+            .as_ref()?
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.start.0 <= line && line <= diagnostic.end.0)
+    }
+    // Language servers count columns in UTF-16 units
+    fn lsp_position(&self, (line, col): Pos) -> Json {
+        let chars = self.line_chars(line);
+        let character: usize = chars[..col.min(chars.len())]
+            .iter()
+            .map(|c| c.len_utf16())
+            .sum();
+        Json::object([("line", line.into()), ("character", character.into())])
+    }
+    fn lsp_pos(&self, position: &Json) -> Pos {
+        let line = position.get("line").as_usize().unwrap_or(0);
+        if line >= self.text.len_lines() {
+            return (line, 0);
+        }
+        let character = position.get("character").as_usize().unwrap_or(0);
+        let mut units = 0;
+        let col = self
+            .line_chars(line)
+            .iter()
+            .take_while(|c| {
+                units += c.len_utf16();
+                units <= character
+            })
+            .count();
+        (line, col)
+    }
+    // `gd`: asks the language server where the word under the cursor is
+    // defined, and goes there once it answers
+    fn goto_definition(&mut self) {
+        let position = self.lsp_position((self.cursor_line(), self.cursor_col as usize));
+        let message = match self.lsp.as_mut() {
+            Some(client) if client.ready => {
+                let params =
+                    Json::object([("textDocument", client.document()), ("position", position)]);
+                if let Err(err) = client.request("textDocument/definition", params) {
+                    self.stop_lsp(err);
+                }
+                return;
+            }
+            Some(_) => "The language server is still starting",
+            None => "No language server for this file",
+        };
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(0, message);
+    }
+    fn cursor_word(&self) -> Option<String> {
+        let chars = self.line_chars(self.cursor_line());
+        let col = self.cursor_col as usize;
+        if !chars.get(col).is_some_and(|c| regex::is_word_char(*c)) {
+            return None;
+        }
+        let (start, end) = word_bounds(&chars, col)?;
+        Some(chars[start..end].iter().collect())
+    }
+    // `K`: what the language server says about the symbol under the cursor,
+    // or without one the man page for the word
+    fn show_documentation(&mut self) -> std::io::Result<()> {
+        let Some(word) = self.cursor_word() else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No word under the cursor");
+            return Ok(());
+        };
+        let position = self.lsp_position((self.cursor_line(), self.cursor_col as usize));
+        if let Some(client) = self.lsp.as_mut().filter(|client| client.ready) {
+            let params =
+                Json::object([("textDocument", client.document()), ("position", position)]);
+            if let Err(err) = client.request("textDocument/hover", params) {
+                self.stop_lsp(err);
+            }
+            return Ok(());
+        }
+        let (cols, _) = terminal_size()?;
+        let output = std::process::Command::new("man")
+            .arg(&word)
+            .env("MANWIDTH", cols.to_string())
+            .env("MANPAGER", "cat")
+            .stdin(std::process::Stdio::null())
+            .output();
+        match output {
+            Ok(output) if output.status.success() && !output.stdout.is_empty() => {
+                self.show_output(&strip_overstrike(&String::from_utf8_lossy(&output.stdout)))
+            }
+            _ => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message
+                    .insert(0, &format!("No documentation for {}", word));
+                Ok(())
+            }
+        }
+    }
+    fn show_hover(&mut self, result: &Json, error: Option<String>) -> std::io::Result<()> {
+        // MarkupContent, a MarkedString, or a list of MarkedStrings, where
+        // each is either the text or the text with its language
+        let marked_text = |content: &Json| {
+            content
+                .as_str()
+                .or(content.get("value").as_str())
+                .map(str::to_string)
+        };
+        let text = match result.get("contents") {
+            Json::Array(contents) => contents
+                .iter()
+                .filter_map(marked_text)
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+            contents => marked_text(contents).unwrap_or_default(),
+        };
+        if text.trim().is_empty() {
+            let message = error.unwrap_or(match self.cursor_word() {
+                Some(word) => format!("No documentation for {}", word),
+                None => "No documentation found".to_string(),
+            });
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, &message);
+            return Ok(());
+        }
+        self.show_output(text.trim())
+    }
+    fn jump_to_definition(&mut self, result: &Json, error: Option<String>) -> std::io::Result<()> {
+        // A Location, or a list of Locations or LocationLinks
+        let location = match result {
+            Json::Array(locations) => locations.first(),
+            Json::Null => None,
+            location => Some(location),
+        };
+        let (uri, range) = match location {
+            Some(location) if location.get("targetUri") != &Json::Null => (
+                location.get("targetUri"),
+                location.get("targetSelectionRange"),
+            ),
+            Some(location) => (location.get("uri"), location.get("range")),
+            None => (&Json::Null, &Json::Null),
+        };
+        let Some(path) = uri.as_str().and_then(lsp::uri_to_path) else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, &error.unwrap_or("No definition found".to_string()));
+            return Ok(());
+        };
+        let start = range.get("start");
+        let same_file = self.lsp.as_ref().and_then(|client| client.uri.as_deref()) == uri.as_str();
+        if !same_file {
+            if !self.autowrite() {
+                return Ok(());
+            }
+            if self.dirty {
+                let line = start.get("line").as_usize().unwrap_or(0) + 1;
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(
+                    0,
+                    &format!(
+                        "No write since last change, the definition is in {}:{}",
+                        path.display(),
+                        line
+                    ),
+                );
+                return Ok(());
+            }
+            self.open_file(path)?;
+        } else {
+            self.push_jump();
+        }
+        let (line, col) = self.lsp_pos(start);
+        self.move_cursor(line, col)
+    }
+    // Replaces the buffer with the saved file at `path`
+    fn open_file(&mut self, path: PathBuf) -> std::io::Result<()> {
+        let text = Rope::from_reader(BufReader::new(File::open(&path)?))?;
+        self.filetype = detect_filetype(Some(&path), &text.line(0).to_string());
+        self.indent = detect_indent(text.lines().map(|line| line.to_string()));
+        self.history = History::load(&path, &text.to_string()).unwrap_or_default();
+        self.text = text;
+        self.filename = Some(path);
+        self.dirty = false;
+        self.changes += 1;
+        self.backed_up = false;
+        self.large_file = false;
+        self.scratch = false;
+        // What is still to come of a large file opened before is not this one
+        self.loader = None;
+        self.last_visual = None;
+        self.last_insert = None;
+        self.folds.clear();
+        self.jumps.clear();
+        self.jump = 0;
+        self.change_list.clear();
+        self.change_at = 0;
+        self.shift_row = 0;
+        self.cursor_row = 0;
+        self.cursor_col = 0;
+        self.apply_config();
+        Ok(())
+    }
+    // Settings for the file now being edited, from the config file, and the
+    // language server to go with them
+    fn apply_config(&mut self) {
+        // The server command stays whatever the new filetype sets
+        if let Err(err) = self.config.apply(
+            &self.filetype,
+            self.indent,
+            self.large_file,
+            &mut self.options,
+        ) {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, &err);
+        } else {
+            let info = self.file_info();
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, &info);
+        }
+        self.sync_lsp();
+    }
+    // Trades the file being edited for the one kept in `tabs[index]`
+    fn swap_tab(&mut self, index: usize) {
+        let tab = &mut self.tabs[index];
+        std::mem::swap(&mut self.text, &mut tab.text);
+        std::mem::swap(&mut self.filename, &mut tab.filename);
+        std::mem::swap(&mut self.cursor_col, &mut tab.cursor_col);
+        std::mem::swap(&mut self.cursor_row, &mut tab.cursor_row);
+        std::mem::swap(&mut self.shift_row, &mut tab.shift_row);
+        std::mem::swap(&mut self.dirty, &mut tab.dirty);
+        std::mem::swap(&mut self.readonly, &mut tab.readonly);
+        std::mem::swap(&mut self.large_file, &mut tab.large_file);
+        std::mem::swap(&mut self.scratch, &mut tab.scratch);
+        std::mem::swap(&mut self.backed_up, &mut tab.backed_up);
+        std::mem::swap(&mut self.loader, &mut tab.loader);
+        std::mem::swap(&mut self.filetype, &mut tab.filetype);
+        std::mem::swap(&mut self.indent, &mut tab.indent);
+        std::mem::swap(&mut self.history, &mut tab.history);
+        std::mem::swap(&mut self.folds, &mut tab.folds);
+        std::mem::swap(&mut self.jumps, &mut tab.jumps);
+        std::mem::swap(&mut self.jump, &mut tab.jump);
+        std::mem::swap(&mut self.change_list, &mut tab.change_list);
+        std::mem::swap(&mut self.change_at, &mut tab.change_at);
+        std::mem::swap(&mut self.last_visual, &mut tab.last_visual);
+        std::mem::swap(&mut self.last_insert, &mut tab.last_insert);
+        std::mem::swap(&mut self.windows, &mut tab.windows);
+        std::mem::swap(&mut self.window, &mut tab.window);
+        std::mem::swap(&mut self.previous_window, &mut tab.previous_window);
+        std::mem::swap(&mut self.layout, &mut tab.layout);
+    }
+    fn go_to_tab(&mut self, index: usize) {
+        if index == self.tab {
+            return;
+        }
+        self.swap_tab(self.tab);
+        self.swap_tab(index);
+        self.tab = index;
+        // Positions in the text left behind mean nothing here
+        self.snippet = None;
+        self.cursors.clear();
+        self.changes += 1;
+        self.apply_config();
+    }
+    // `:tabnew`: a tab page after this one, with `path` or an empty buffer
+    fn tab_new(&mut self, path: Option<PathBuf>) -> std::io::Result<()> {
+        if self.tabs.is_empty() {
+            self.tabs.push(Tab::default());
+        }
+        self.tabs.insert(self.tab + 1, Tab::default());
+        self.go_to_tab(self.tab + 1);
+        match path {
+            Some(path) if path.exists() => self.open_file(path)?,
+            path => {
+                self.filetype = detect_filetype(path.as_deref(), "");
+                self.filename = path;
+                self.apply_config();
+            }
+        }
+        Ok(())
+    }
+    // `:tabnew !command`: a new tab page with a scratch buffer of what
+    // `command` printed
+    fn tab_new_scratch(&mut self, command: &str) -> std::io::Result<()> {
+        let mut output = self.run_shell(command)?;
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+        self.tab_new(None)?;
+        self.text = Rope::from_str(&output);
+        self.scratch = true;
+        self.changes += 1;
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(
+            0,
+            &format!("{} from {}", plural(self.line_count(), "line"), command),
+        );
+        Ok(())
+    }
+    // `:tabclose`, going to the tab page after it, or before it for the last
+    fn tab_close(&mut self, force: bool) -> Result<(), String> {
+        if self.tabs.is_empty() {
+            return Err("Cannot close last tab page".to_string());
+        }
+        if self.dirty && !self.scratch && !force {
+            return Err("Unsaved changes! Save file with :w or force close :tabclose!".to_string());
+        }
+        let closing = self.tab;
+        if closing + 1 < self.tabs.len() {
+            self.go_to_tab(closing + 1);
+            self.tab -= 1;
+        } else {
+            self.go_to_tab(closing - 1);
+        }
+        self.tabs.remove(closing);
+        if self.tabs.len() == 1 {
+            self.tabs.clear();
+        }
+        Ok(())
+    }
+    // Rows the tab bar takes at the top, when there is more than one tab page
+    fn tabline_rows(&self) -> u16 {
+        if self.tabs.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+    // The last window's status line shows with `laststatus` at 2, and at 1
+    // while there are other windows
+    fn status_rows(&self) -> u16 {
+        match self.options.laststatus {
+            2 => 1,
+            1 if !self.windows.is_empty() => 1,
+            _ => 0,
+        }
+    }
+    // Where the windows go on a terminal of `size`: between the tab bar and
+    // the command line
+    fn text_area(&self, (cols, rows): (u16, u16)) -> Rect {
+        let top = self.tabline_rows();
+        Rect {
+            col: 0,
+            row: top,
+            cols,
+            rows: rows.saturating_sub(top + 1),
+        }
+    }
+    // Every window with where it is on a terminal of `size`
+    fn window_rects(&self, size: (u16, u16)) -> Vec<(usize, Rect)> {
+        let area = self.text_area(size);
+        if self.windows.is_empty() {
+            vec![(0, area)]
+        } else {
+            self.layout.rects(area)
+        }
+    }
+    fn window_rect(&self, size: (u16, u16)) -> Rect {
+        self.window_rects(size)
+            .into_iter()
+            .find(|(index, _)| *index == self.window)
+            .map_or(self.text_area(size), |(_, rect)| rect)
+    }
+    // A window has a status line under it, but at the bottom that is up to
+    // `laststatus`
+    fn has_status_line(&self, rect: Rect, size: (u16, u16)) -> bool {
+        let area = self.text_area(size);
+        rect.row + rect.rows < area.row + area.rows || self.status_rows() > 0
+    }
+    // Rows of the current window showing text, above its status line.
+    // Always at least one, however small the terminal.
+    fn text_rows(&self, size: (u16, u16)) -> usize {
+        let rect = self.window_rect(size);
+        let status = self.has_status_line(rect, size) as u16;
+        rect.rows.saturating_sub(status).max(1) as usize
+    }
+    // Trades the view of the current window for the one kept in
+    // `windows[index]`
+    fn swap_window(&mut self, index: usize) {
+        let window = &mut self.windows[index];
+        std::mem::swap(&mut self.cursor_col, &mut window.cursor_col);
+        std::mem::swap(&mut self.cursor_row, &mut window.cursor_row);
+        std::mem::swap(&mut self.shift_row, &mut window.shift_row);
+    }
+    fn go_to_window(&mut self, index: usize) -> std::io::Result<()> {
+        if index == self.window {
+            return Ok(());
+        }
+        self.swap_window(self.window);
+        self.swap_window(index);
+        self.previous_window = Some(self.window);
+        self.window = index;
+        // The text may have changed from another window
+        let line = self.cursor_line().min(self.line_count() - 1);
+        self.move_cursor(line, self.cursor_col as usize)
+    }
+    // Scrolls every window to its cursor, for after their sizes changed
+    fn fit_windows(&mut self) -> std::io::Result<()> {
+        let (current, previous) = (self.window, self.previous_window);
+        for index in 0..self.windows.len() {
+            self.go_to_window(index)?;
+        }
+        self.go_to_window(current)?;
+        self.previous_window = previous;
+        self.move_cursor(self.cursor_line(), self.cursor_col as usize)
+    }
+    // `:split` and `:vsplit`: a window on the buffer above this one, or left
+    // of it when `vertical`, which is gone to
+    fn split_window(&mut self, vertical: bool) -> std::io::Result<()> {
+        let area = self.text_area(terminal_size()?);
+        if self.windows.is_empty() {
+            self.windows.push(Window::default());
+        }
+        let new = self.windows.len();
+        self.windows.push(Window {
+            cursor_col: self.cursor_col,
+            cursor_row: self.cursor_row,
+            shift_row: self.shift_row,
+        });
+        self.layout.split(self.window, new, vertical, area);
+        self.go_to_window(new)?;
+        self.fit_windows()
+    }
+    // Closes the current window, going to the one next to it that gets its
+    // space. There has to be another one.
+    fn close_window(&mut self) -> std::io::Result<()> {
+        let area = self.text_area(terminal_size()?);
+        let closing = self.window;
+        let next = self.layout.close(closing, area);
+        // Where that one is until the closed one is taken out
+        self.swap_window(if next >= closing { next + 1 } else { next });
+        self.windows.remove(closing);
+        self.window = next;
+        self.previous_window = match self.previous_window {
+            Some(previous) if previous == closing => None,
+            Some(previous) if previous > closing => Some(previous - 1),
+            previous => previous,
+        };
+        if self.windows.len() == 1 {
+            return self.only_window();
+        }
+        self.fit_windows()
+    }
+    // `:only`: closes every window but the current one
+    fn only_window(&mut self) -> std::io::Result<()> {
+        self.windows.clear();
+        self.window = 0;
+        self.previous_window = None;
+        self.layout = Layout::default();
+        self.fit_windows()
+    }
+    // Ctrl-W w and W: the next window from the top left, or the one before,
+    // going round. With a count, the window that many from the top left.
+    fn cycle_window(&mut self, back: bool) -> std::io::Result<()> {
+        let order: Vec<usize> = self
+            .window_rects(terminal_size()?)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        let at = order
+            .iter()
+            .position(|index| *index == self.window)
+            .unwrap_or(0);
+        let to = match self.count {
+            Some(count) => count.clamp(1, order.len()) - 1,
+            None if back => (at + order.len() - 1) % order.len(),
+            None => (at + 1) % order.len(),
+        };
+        self.go_to_window(order[to])
+    }
+    // Ctrl-W h, j, k and l: the window beside this one that way, or the one
+    // that many over with a count
+    fn window_beside(&mut self, key: char) -> std::io::Result<()> {
+        for _ in 0..self.count.unwrap_or(1) {
+            let size = terminal_size()?;
+            let rect = self.window_rect(size);
+            // About where the cursor is, for which of several to go to
+            let col = self.gutter_width() as u16 + self.cursor_col;
+            let at = (
+                rect.col + col.min(rect.cols.saturating_sub(1)),
+                rect.row + self.cursor_row,
+            );
+            match window::neighbour(&self.window_rects(size), rect, at, key) {
+                Some(index) => self.go_to_window(index)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+    // Returns true when the editor should quit
+    fn handle_key(&mut self, keyev: event::KeyEvent) -> std::io::Result<bool> {
+        // Ctrl-C does what Esc does, and clears the message too in Normal
+        // mode. Right after Ctrl-V in Insert mode it is typed as it is.
+        let keyev = if is_ctrl(keyev, 'c') && self.pending != "\u{16}" {
+            if self.mode == "Normal" {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            event::KeyEvent::from(event::KeyCode::Esc)
+        } else {
+            keyev
+        };
+        // Keys are collected from the start of each Normal mode command, so
+        // `.` can replay the last one that changed the text
+        if !self.replaying {
+            if self.mode == "Normal" && self.pending.is_empty() {
+                self.change_keys.clear();
+                self.change_start = self.changes;
+            }
+            self.change_keys.push(keyev);
+        }
+
+        let was_insert = self.mode == "Insert";
+        let pending = self.pending.clone();
+        let register_name = self.register_name;
+        let count = self.count;
+        let quit = self.dispatch_key(keyev)?;
+        // A register picked with `"x`, or a count, is only for the command
+        // after it
+        if self.pending.is_empty() && self.register_name == register_name {
+            self.register_name = None;
+        }
+        if self.pending.is_empty() && self.count == count {
+            self.count = None;
+        }
+        let is_edit = match keyev.code {
+            event::KeyCode::Char(_) => !keyev.modifiers.contains(event::KeyModifiers::CONTROL),
+            event::KeyCode::Backspace | event::KeyCode::Enter | event::KeyCode::Tab => true,
+            _ => false,
+        };
+        // Not while filling in a snippet, whose stops are at one cursor
+        if was_insert
+            && self.mode == "Insert"
+            && is_edit
+            && !self.cursors.is_empty()
+            && self.snippet.is_none()
+        {
+            self.repeat_at_cursors(&pending, keyev)?;
+        }
+
+        if !self.replaying
+            && self.mode == "Normal"
+            && self.pending.is_empty()
+            && self.changes != self.change_start
+            && !matches!(
+                self.change_keys[0].code,
+                event::KeyCode::Char(':' | '.' | 'u')
+            )
+            && !is_ctrl(self.change_keys[0], 'r')
+            && !matches!(
+                self.change_keys.get(..2),
+                Some([g, event::KeyEvent { code: event::KeyCode::Char('-' | '+'), .. }])
+                    if g.code == event::KeyCode::Char('g')
+            )
+        {
+            self.last_change = std::mem::take(&mut self.change_keys);
+        }
+        if self.mode == "Normal" && self.pending.is_empty() {
+            self.history.commit();
+        }
+        Ok(quit)
+    }
+    // Does `keyev`, just done at the cursor after the `pending` keys, again
+    // at each extra cursor from the bottom up
+    fn repeat_at_cursors(&mut self, pending: &str, keyev: event::KeyEvent) -> std::io::Result<()> {
+        let shift_row = self.shift_row;
+        // The real cursor goes in the list too, to be moved by the edits
+        let line = self.cursor_line();
+        self.cursors
+            .push(self.text.line_to_char(line) + self.cursor_col as usize);
+        let primary = self.cursors.len() - 1;
+        let mut order: Vec<usize> = (0..primary).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(self.cursors[*i]));
+        for i in order {
+            let pos = self.cursors[i];
+            let line = self.text.char_to_line(pos);
+            self.move_cursor(line, pos - self.text.line_to_char(line))?;
+            self.pending = pending.to_string();
+            self.dispatch_key(keyev)?;
+            let line = self.cursor_line();
+            self.cursors[i] = self.text.line_to_char(line) + self.cursor_col as usize;
+        }
+        let pos = self.cursors.pop().unwrap();
+        // Cursors that backspacing has run together are one now
+        self.cursors.sort_unstable();
+        self.cursors.dedup();
+        self.cursors.retain(|cursor| *cursor != pos);
+        self.shift_row = shift_row;
+        let line = self.text.char_to_line(pos);
+        self.move_cursor(line, pos - self.text.line_to_char(line))
+    }
+    // Moves each extra cursor to `col(cursor col, line length)` on its line
+    fn move_cursors(&mut self, col: impl Fn(usize, usize) -> usize) {
+        for i in 0..self.cursors.len() {
+            let line = self.text.char_to_line(self.cursors[i]);
+            let line_start = self.text.line_to_char(line);
+            self.cursors[i] = line_start + col(self.cursors[i] - line_start, self.line_len(line));
+        }
+    }
+    // Ctrl-D: adds a cursor at the next whole-word match of the word under
+    // the cursor, as far into it as the cursor is into its word
+    fn add_cursor_at_next_match(&mut self) {
+        let line = self.cursor_line();
+        let chars = self.line_chars(line);
+        let col = self.cursor_col as usize;
+        let word = self
+            .cursor_word()
+            .zip(word_bounds(&chars, col))
+            .map(|(word, (start, _))| (word.chars().collect::<Vec<char>>(), col - start));
+        let Some((word, offset)) = word else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No word under the cursor");
+            return;
+        };
+        self.finish_loading();
+        let text: Vec<char> = self.text.chars().collect();
+        let primary = self.text.line_to_char(line) + col;
+        let is_match = |start: usize| {
+            text[start..].starts_with(&word)
+                && (start == 0 || !regex::is_word_char(text[start - 1]))
+                && text
+                    .get(start + word.len())
+                    .is_none_or(|c| !regex::is_word_char(*c))
+        };
+        // From the last cursor added, round to the start of the file
+        let from = self.cursors.last().copied().unwrap_or(primary) - offset + 1;
+        let found = (from..text.len())
+            .chain(0..from)
+            .filter(|start| *start + word.len() <= text.len())
+            .map(|start| (start, start + offset))
+            .find(|&(start, pos)| {
+                pos != primary && !self.cursors.contains(&pos) && is_match(start)
+            });
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        match found {
+            Some((_, pos)) => {
+                self.cursors.push(pos);
+                let message = format!("{} cursors", self.cursors.len() + 1);
+                self.cmd_message.insert(0, &message);
+            }
+            None => {
+                let word: String = word.iter().collect();
+                self.cmd_message
+                    .insert(0, &format!("No more matches for {}", word));
+            }
+        }
+    }
+    // Ctrl-D in the visual modes: a cursor on every selected line, in the
+    // selection's first column (the cursor's for whole lines)
+    fn cursors_from_selection(&mut self) -> std::io::Result<()> {
+        let ((first, start_col), (last, _)) = self.selection();
+        let col = if self.mode == "Visual Line" {
+            self.cursor_col as usize
+        } else {
+            start_col
+        };
+        self.exit_visual();
+        self.cursors = (first + 1..=last)
+            .map(|line| self.text.line_to_char(line) + col.min(self.line_len(line)))
+            .collect();
+        self.move_cursor(first, col)?;
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        let message = format!("{} cursors", self.cursors.len() + 1);
+        self.cmd_message.insert(0, &message);
+        Ok(())
+    }
+    // `:q`, and `:q!` if `force`: closes the window, the tab page with the
+    // last of them, or the editor with the last one. Refused while there
+    // are unsaved changes that no other window shows.
+    fn quit(&mut self, force: bool) -> std::io::Result<bool> {
+        if !self.windows.is_empty() {
+            self.close_window()?;
+            return Ok(false);
+        }
+        if self.dirty && !self.scratch && !force {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message
+                .insert(0, "Unsaved changes! Save file with :w or force quit :q!");
+            return Ok(false);
+        }
+        if !self.tabs.is_empty() {
+            let _ = self.tab_close(true);
+            return Ok(false);
+        }
+        stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
+        Ok(true)
+    }
+    fn repeat_last_change(&mut self) -> std::io::Result<bool> {
+        self.replaying = true;
+        let mut quit = false;
+        for keyev in self.last_change.clone() {
+            quit = self.dispatch_key(keyev)?;
+            if quit {
+                break;
+            }
+        }
+        self.replaying = false;
+        Ok(quit)
+    }
+    // Shows what went wrong with a command line
+    fn command_error(&mut self, err: &str) {
+        self.cmd_message.remove(0..self.cmd_message.len_chars());
+        self.cmd_message.insert(0, err);
+        self.command_failed = true;
+    }
+    // `@:`: types the last command line again, `count` times unless it goes
+    // wrong
+    fn repeat_command(&mut self, count: usize) -> std::io::Result<bool> {
+        let Some(command) = self.last_command.clone() else {
+            self.cmd_message.remove(0..self.cmd_message.len_chars());
+            self.cmd_message.insert(0, "No previous command line");
+            return Ok(false);
+        };
+        let keys = std::iter::once(':')
+            .chain(command.chars())
+            .map(event::KeyCode::Char)
+            .chain([event::KeyCode::Enter]);
+        for _ in 0..count {
+            self.command_failed = false;
+            for key in keys.clone() {
+                if self.dispatch_key(key.into())? {
+                    return Ok(true);
+                }
+            }
+            if self.command_failed {
+                break;
+            }
+        }
+        Ok(false)
+    }
+    fn dispatch_key(&mut self, keyev: event::KeyEvent) -> std::io::Result<bool> {
+        let (cols, rows) = terminal_size()?;
+
+        if let event::KeyCode::Char(c) = keyev.code {
+            if c != 'j' && c != 'k' {
+                self.prefered_col = None;
+            }
+        }
+        if self.virtual_cols > 0
+            && !matches!(keyev.code, event::KeyCode::Char('h' | 'j' | 'k' | 'l'))
+        {
+            self.leave_virtual_cols(keyev)?;
+        }
+
+        if !is_ctrl(keyev, 'n') && !is_ctrl(keyev, 'p') {
+            self.completion = None;
+        }
+        if !matches!(keyev.code, event::KeyCode::Tab | event::KeyCode::BackTab) {
+            self.command_completion = None;
+        }
+
+        // Ctrl-R in Insert mode waits for the name of the register to insert,
+        // or `=` to ask for an expression, Ctrl-V for the key or code of the
+        // char to insert
+        if !self.pending.is_empty() && self.mode == "Insert" {
+            let keys = std::mem::take(&mut self.pending);
+            if let Some(typed) = keys.strip_prefix('\u{16}') {
+                return self.insert_literal(keyev, typed);
+            }
+            match (keyev.code, keys.as_str()) {
+                (event::KeyCode::Char('='), "\u{12}") => {
+                    self.command_from = self.mode;
+                    self.mode = "Command";
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert_char(0, '=');
+
+                    self.prev_cursor_col = self.cursor_col;
+                    self.prev_cursor_row = self.cursor_row;
+
+                    self.cursor_row = rows - 1;
+                    self.cursor_col = 1;
+                }
+                (event::KeyCode::Char(name), "\u{12}") => self.insert_register(name)?,
+                _ => {}
+            }
+            return Ok(false);
+        }
+
+        if !self.pending.is_empty() && matches!(self.mode, "Normal" | "Visual" | "Visual Line") {
+            // Esc gives up on the keys typed so far
+            if keyev.code == event::KeyCode::Esc {
+                self.pending.clear();
+                return Ok(false);
+            }
+            if let event::KeyCode::Char(c) = keyev.code {
+                if keyev.modifiers.contains(event::KeyModifiers::CONTROL) {
+                    // Control keys go in as their ASCII control character
+                    self.pending
+                        .push(char::from(c.to_ascii_uppercase() as u8 & 0x1f));
+                } else {
+                    self.pending.push(c);
+                }
+            }
+            let keys = std::mem::take(&mut self.pending);
+            match keys.as_str() {
+                "gg" => {
+                    self.push_jump();
+                    self.move_cursor(0, self.first_non_blank(0))?;
+                }
+                "gv" => {
+                    if let Some((mode, start, end)) = self.last_visual {
+                        let last_line = self.text.len_lines() - 1;
+                        let start_line = start.0.min(last_line);
+                        self.visual_start = (start_line, start.1.min(self.line_len(start_line)));
+                        self.move_cursor(end.0, end.1)?;
+                        self.mode = mode;
+                    }
+                }
+                // g Ctrl-G
+                "g\u{7}" => {
+                    let message = self.count_message();
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert(0, &message);
+                }
+                "gi" if self.mode == "Normal" => {
+                    // move_cursor keeps it inside text that has shrunk since
+                    if let Some((line, col)) = self.last_insert {
+                        self.move_cursor(line, col)?;
+                    }
+                    self.mode = "Insert";
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                }
+                "gd" if self.mode == "Normal" => self.goto_definition(),
+                "g&" if self.mode == "Normal" => self.repeat_substitute(true)?,
+                "g;" | "g," if self.mode == "Normal" => self.step_change_list(keys == "g,")?,
+                "gt" | "gT" if self.mode == "Normal" && !self.tabs.is_empty() => {
+                    let count = self.tabs.len();
+                    let step = if keys == "gt" { 1 } else { count - 1 };
+                    self.go_to_tab((self.tab + step) % count);
+                }
+                // With no macros, `@@` can only have been `@:`
+                "@:" | "@@" if self.mode == "Normal" => {
+                    return self.repeat_command(self.count.unwrap_or(1))
+                }
+                "g-" | "g+" if self.mode == "Normal" => self.undo_in_time(keys == "g+")?,
+                "]p" | "[p" => self.paste_indented(keys == "[p")?,
+                // Ctrl-W commands, on the windows. Closing the last one is
+                // quitting.
+                "\u{17}q" | "\u{17}\u{11}" => return self.quit(false),
+                "\u{17}c" if self.windows.is_empty() => return self.quit(false),
+                "\u{17}c" => self.close_window()?,
+                "\u{17}o" | "\u{17}\u{f}" => self.only_window()?,
+                "\u{17}s" | "\u{17}S" | "\u{17}\u{13}" => self.split_window(false)?,
+                "\u{17}v" | "\u{17}\u{16}" => self.split_window(true)?,
+                "\u{17}w" | "\u{17}W" | "\u{17}\u{17}" => self.cycle_window(keys == "\u{17}W")?,
+                "\u{17}p" | "\u{17}\u{10}" => {
+                    if let Some(previous) = self.previous_window {
+                        self.go_to_window(previous)?;
+                    }
+                }
+                "\u{17}h" | "\u{17}j" | "\u{17}k" | "\u{17}l" => {
+                    self.window_beside(keys.chars().last().unwrap_or_default())?;
+                }
+                // With Ctrl held down for the direction too
+                "\u{17}\u{8}" | "\u{17}\n" | "\u{17}\u{b}" | "\u{17}\u{c}" => {
+                    let key = keys.chars().last().unwrap_or_default() as u8 | 0x60;
+                    self.window_beside(key as char)?;
+                }
+                "\u{17}+" | "\u{17}-" | "\u{17}<" | "\u{17}>" => {
+                    let count = self.count.unwrap_or(1).min(u16::MAX as usize) as i32;
+                    let grow = keys.ends_with('+') || keys.ends_with('>');
+                    let vertical = keys.ends_with('<') || keys.ends_with('>');
+                    let area = self.text_area((cols, rows));
+                    let delta = if grow { count } else { -count };
+                    self.layout.resize(self.window, vertical, delta, area);
+                    self.fit_windows()?;
+                }
+                "zf" if self.mode != "Normal" => {
+                    let ((start, _), (end, _)) = self.selection();
+                    self.exit_visual();
+                    self.folds.push(Fold {
+                        start,
+                        end,
+                        closed: true,
+                    });
+                    self.move_cursor(start, 0)?;
+                }
+                "zo" | "zc" | "za" | "zR" | "zM" | "zd" if self.mode == "Normal" => {
+                    self.fold_command(&keys)?
+                }
+                "z=" => {
+                    let message = self.spell_suggestions();
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert(0, &message);
+                }
+                "]s" | "[s" if !self.options.spell => {
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert(0, "Spell checking is not enabled");
+                }
+                "]s" | "[s" => {
+                    if let Some((line, col)) = self.next_misspelling(keys == "[s") {
+                        self.move_cursor(line, col)?;
+                    } else {
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message.insert(0, "No misspelled words");
+                    }
+                }
+                _ if keys.starts_with('"') => match keys.chars().nth(1) {
+                    Some(name) if is_register_name(name) => self.register_name = Some(name),
+                    Some(name) => {
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message
+                            .insert(0, &format!("Unknown register {}", name));
+                    }
+                    None => self.pending = keys,
+                },
+                _ if keys.starts_with('S') && self.mode != "Normal" => {
+                    let Some(c) = keys.chars().nth(1) else {
+                        self.pending = keys;
+                        return Ok(false);
+                    };
+                    if let Some((open, close)) = surround_pair(c) {
+                        self.surround_selection(open, close)?;
+                    }
+                }
+                "gc" | "gq" if self.mode != "Normal" => {
+                    let ((first, _), (last, _)) = self.selection();
+                    self.exit_visual();
+                    if keys == "gc" {
+                        self.toggle_comment(first, last);
+                    } else {
+                        self.reflow(first, last);
+                    }
+                    self.move_cursor(first, self.first_non_blank(first))?;
+                }
+                _ => self.run_operator(keys)?,
+            }
+            return Ok(false);
+        }
+
+        match (keyev.code, self.mode) {
+            (event::KeyCode::Char('q'), "Normal") => {}
+            (event::KeyCode::Char('K'), "Normal") => self.show_documentation()?,
+            (event::KeyCode::Char('l'), "Normal") if is_ctrl(keyev, 'l') => {
+                // Forget what is on screen, so render clears it of any stray
+                // output and draws everything again
+                self.screen = None;
+            }
+            (event::KeyCode::Char('e'), "Normal" | "Visual" | "Visual Line")
+                if is_ctrl(keyev, 'e') =>
+            {
+                self.scroll_view(1)?;
+            }
+            (event::KeyCode::Char('y'), "Normal" | "Visual" | "Visual Line")
+                if is_ctrl(keyev, 'y') =>
+            {
+                self.scroll_view(-1)?;
+            }
+            (event::KeyCode::Char(c @ ('f' | 'b')), "Normal" | "Visual" | "Visual Line")
+                if is_ctrl(keyev, c) =>
+            {
+                self.scroll_page(c == 'b')?;
+            }
+            (
+                event::KeyCode::PageDown | event::KeyCode::PageUp,
+                "Normal" | "Visual" | "Visual Line",
+            ) => {
+                self.scroll_page(keyev.code == event::KeyCode::PageUp)?;
+            }
+            (event::KeyCode::Char(c @ ('a' | 'x')), "Normal") if is_ctrl(keyev, c) => {
+                self.increment(if c == 'a' { 1 } else { -1 })?;
+            }
+            (event::KeyCode::Char('d'), "Normal") if is_ctrl(keyev, 'd') => {
+                self.add_cursor_at_next_match();
+            }
+            (event::KeyCode::Char('d'), "Visual" | "Visual Line") if is_ctrl(keyev, 'd') => {
+                self.cursors_from_selection()?;
+            }
+            (event::KeyCode::Esc, "Normal") if !self.cursors.is_empty() => {
+                self.cursors.clear();
+            }
+            (event::KeyCode::Char('h'), "Normal" | "Visual" | "Visual Line") => {
+                if self.virtual_cols > 0 {
+                    self.virtual_cols -= 1;
+                } else if self.cursor_col != 0 {
+                    self.cursor_col -= 1;
+                }
+            }
+            (event::KeyCode::Char('j'), "Normal" | "Visual" | "Visual Line") => {
+                if self.prefered_col.is_none() {
+                    self.prefered_col = Some(self.cursor_col + self.virtual_cols as u16);
+                }
+
+                if let Some((_, end)) = self.closed_fold(self.cursor_line()) {
+                    // Down past the lines folded away
+                    if end + 1 < self.line_count() {
+                        self.move_cursor(end + 1, 0)?;
+                    }
+                } else if (self.cursor_row as usize + 1 != self.text_rows((cols, rows)))
+                    && (((self.cursor_row + 1) as usize) < self.text.len_lines())
+                {
+                    self.cursor_row += 1;
+                } else {
+                    let text_rows = self.text_rows((cols, rows));
+                    let line = self.shift_row + text_rows;
+                    if line < self.text.len_lines() - 1 {
+                        let max_shift = self.text.len_lines() - 1 - text_rows;
+                        let jump = self.scroll_jump(text_rows);
+                        self.shift_row = (self.shift_row + jump).min(max_shift);
+                        self.cursor_row = (line - self.shift_row) as u16;
+                    }
+                }
+
+                self.set_col(self.prefered_col.unwrap());
+            }
+            (event::KeyCode::Char('k'), "Normal" | "Visual" | "Visual Line") => {
+                if self.prefered_col.is_none() {
+                    self.prefered_col = Some(self.cursor_col + self.virtual_cols as u16);
+                }
+
+                let above = self.cursor_line().checked_sub(1);
+                if let Some((start, _)) = above.and_then(|line| self.closed_fold(line)) {
+                    self.move_cursor(start, 0)?;
+                } else if self.cursor_row != 0 {
+                    self.cursor_row -= 1;
+                } else {
+                    if self.shift_row != 0 {
+                        let text_rows = self.text_rows((cols, rows));
+                        let line = self.shift_row - 1;
+                        let jump = self.scroll_jump(text_rows);
+                        self.shift_row = self.shift_row.saturating_sub(jump);
+                        self.cursor_row = (line - self.shift_row) as u16;
+                    }
+                }
+
+                self.set_col(self.prefered_col.unwrap());
+            }
+            (event::KeyCode::Char('l'), "Normal" | "Visual" | "Visual Line") => {
+                if (self.cursor_col != cols - 1) && (self.cursor_col < self.line_max()) {
+                    self.cursor_col += 1;
+                } else if self.options.virtualedit == "all"
+                    && self.cursor_col as usize + self.virtual_cols + 1
+                        < self.text_cols(self.window_rect((cols, rows)).cols)
+                {
+                    self.virtual_cols += 1;
+                }
+            }
+            (event::KeyCode::Home, "Normal" | "Visual" | "Visual Line" | "Insert") => {
+                self.smart_home();
+            }
+            // A `0` after other digits is part of the count
+            (event::KeyCode::Char(c @ '0'..='9'), "Normal") if c != '0' || self.count.is_some() => {
+                let digit = c.to_digit(10).unwrap_or_default() as usize;
+                let count = self.count.unwrap_or_default();
+                self.count = Some(count.saturating_mul(10).saturating_add(digit));
+            }
+            (event::KeyCode::Char('0'), "Normal" | "Visual" | "Visual Line") => {
+                if self.options.smarthome {
+                    self.smart_home();
+                } else {
+                    self.cursor_col = 0;
+                }
+            }
+            (event::KeyCode::Char(c @ ('w' | 'b' | 'e')), "Normal" | "Visual" | "Visual Line")
+                if !keyev.modifiers.contains(event::KeyModifiers::CONTROL) =>
+            {
+                self.word_motion(c)?;
+            }
+            (event::KeyCode::Char('G'), "Normal" | "Visual" | "Visual Line") => {
+                if !self.finish_loading() {
+                    return Ok(false);
+                }
+                let last_line = self.line_count().saturating_sub(1);
+                self.push_jump();
+                self.move_cursor(last_line, self.first_non_blank(last_line))?;
+            }
+            (event::KeyCode::Char('o' | 'O'), "Visual" | "Visual Line") => {
+                // Swap ends, so moving changes the other side of the selection
+                let (line, col) = self.visual_start;
+                self.visual_start = (self.cursor_line(), self.cursor_col as usize);
+                self.move_cursor(line, col)?;
+            }
+            (event::KeyCode::Char(c @ ('n' | 'N')), "Normal" | "Visual" | "Visual Line") => {
+                self.search_next(c == 'N')?;
+            }
+            (event::KeyCode::Char(c @ ('*' | '#')), "Normal" | "Visual" | "Visual Line") => {
+                self.search_word(c == '#')?;
+            }
+            (event::KeyCode::Char('%'), "Normal" | "Visual" | "Visual Line") => {
+                if let Some((line, col)) = self.motion_target("%") {
+                    self.push_jump();
+                    self.move_cursor(line, col)?;
+                }
+            }
+            (event::KeyCode::Char('o'), "Normal") if is_ctrl(keyev, 'o') => {
+                self.step_jump_list(false)?;
+            }
+            // Ctrl-I, which terminals send as Tab
+            (event::KeyCode::Tab, "Normal") => self.step_jump_list(true)?,
+            (event::KeyCode::Char('^'), "Normal" | "Visual" | "Visual Line") => {
+                self.cursor_col = self.first_non_blank(self.cursor_line()) as u16;
+            }
+            (
+                event::KeyCode::Char('$') | event::KeyCode::End,
+                "Normal" | "Visual" | "Visual Line",
+            ) => {
+                self.cursor_col = self.line_max().saturating_sub(1);
+            }
+            (event::KeyCode::End, "Insert") => {
+                self.cursor_col = self.line_max();
+            }
+            (event::KeyCode::Char('v'), "Normal" | "Visual Line") => {
+                if self.mode == "Normal" {
+                    self.visual_start = (self.cursor_line(), self.cursor_col as usize);
+                }
+                self.mode = "Visual";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char('V'), "Normal" | "Visual") => {
+                if self.mode == "Normal" {
+                    self.visual_start = (self.cursor_line(), self.cursor_col as usize);
+                }
+                self.mode = "Visual Line";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Esc, "Visual" | "Visual Line")
+            | (event::KeyCode::Char('v'), "Visual")
+            | (event::KeyCode::Char('V'), "Visual Line") => {
+                self.exit_visual();
+            }
+            (event::KeyCode::Char('y'), "Visual" | "Visual Line") => {
+                let ((line, col), _) = self.selection();
+                self.yank_selection(true);
+                if self.options.yankflash > 0 {
+                    let (start, end) = self.selection_chars();
+                    self.flash = Some((start..end, Instant::now()));
+                }
+                self.exit_visual();
+                self.move_cursor(line, col)?;
+            }
+            (event::KeyCode::Char('d' | 'x'), "Visual" | "Visual Line") => {
+                let ((line, col), _) = self.selection();
+                self.yank_selection(false);
+                self.delete_selection();
+                self.exit_visual();
+                // Deleting the last lines leaves the cursor on the new last one
+                let line = line.min(self.line_count() - 1);
+                self.move_cursor(line, col)?;
+            }
+            (event::KeyCode::Char(c @ ('g' | 'S' | '"' | 'z')), "Visual" | "Visual Line") => {
+                self.pending.push(c);
+            }
+            (event::KeyCode::Char(c @ ('>' | '<')), "Visual" | "Visual Line") => {
+                let ((first, _), (last, _)) = self.selection();
+                self.indent_lines(first, last, c == '<');
+                self.exit_visual();
+                self.move_cursor(first, 0)?;
+            }
+            (event::KeyCode::Char('w'), "Normal") if is_ctrl(keyev, 'w') => {
+                self.pending.push('\u{17}');
+            }
+            (event::KeyCode::Char('g'), "Normal") if is_ctrl(keyev, 'g') => {
+                let message = self.file_info();
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert(0, &message);
+            }
+            (
+                event::KeyCode::Char(
+                    c @ ('d' | 'c' | '>' | '<' | 'g' | 'z' | ']' | '[' | 'y' | '"' | '@'),
+                ),
+                "Normal",
+            ) => {
+                self.pending.push(c);
+            }
+            (event::KeyCode::Char('&'), "Normal") => self.repeat_substitute(false)?,
+            (event::KeyCode::Char(c @ ('y' | 'n' | 'q')), "Confirm")
+                if self.pending_write.is_some() =>
+            {
+                let write = self.pending_write.take().unwrap();
+                self.mode = "Normal";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                if c == 'y' && self.write_to(Some(write.path), write.lines) && write.quit {
+                    return self.quit(false);
+                }
+            }
+            (event::KeyCode::Char(_), "Confirm") if self.pending_write.is_some() => {}
+            (event::KeyCode::Esc, "Confirm") => self.confirm_done(),
+            (event::KeyCode::Char(c), "Confirm") => {
+                if let Err(err) = self.confirm_key(c) {
+                    self.confirm_done();
+                    self.cmd_message.insert(0, &err);
+                }
+            }
+            (event::KeyCode::Char('q') | event::KeyCode::Esc, "Pager") => {
+                self.pager = Pager::default();
+                self.mode = "Normal";
+            }
+            (event::KeyCode::Char('q') | event::KeyCode::Esc, "Quickfix") => {
+                self.mode = "Normal";
+            }
+            (event::KeyCode::Enter, "Quickfix") => {
+                self.mode = "Normal";
+                self.jump_to_match(self.quickfix.index)?;
+            }
+            (_, "Quickfix") => {
+                let Quickfix {
+                    matches,
+                    index,
+                    top,
+                } = &mut self.quickfix;
+                (*index, *top) = move_selection(keyev.code, *index, *top, matches.len(), rows);
+            }
+            (event::KeyCode::Char('q') | event::KeyCode::Esc, "Explore") => {
+                self.mode = "Normal";
+            }
+            (event::KeyCode::Char('-'), "Explore") => {
+                let dir = self.browser.dir.clone();
+                if let Some(parent) = dir.parent() {
+                    self.explore(parent.to_path_buf(), dir.file_name());
+                }
+            }
+            (event::KeyCode::Enter, "Explore") => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                let Some(name) = self.browser.entries.get(self.browser.index) else {
+                    return Ok(false);
+                };
+                let path = self.browser.dir.join(name);
+                if name == "../" {
+                    let dir = self.browser.dir.clone();
+                    self.explore(
+                        self.browser.dir.parent().unwrap().to_path_buf(),
+                        dir.file_name(),
+                    );
+                } else if name.ends_with('/') {
+                    self.explore(path, None);
+                } else {
+                    self.browse_to(path)?;
+                }
+            }
+            (_, "Explore") => {
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                let Browser {
+                    entries,
+                    index,
+                    top,
+                    ..
+                } = &mut self.browser;
+                (*index, *top) = move_selection(keyev.code, *index, *top, entries.len(), rows);
+            }
+            (_, "Pager") => {
+                let page = (rows - 1) as usize;
+                let max_top = self.pager.lines.len().saturating_sub(page);
+                let top = self.pager.top;
+                self.pager.top = match keyev.code {
+                    _ if is_ctrl(keyev, 'd') => top + page / 2,
+                    _ if is_ctrl(keyev, 'u') => top.saturating_sub(page / 2),
+                    _ if is_ctrl(keyev, 'f') => top + page,
+                    _ if is_ctrl(keyev, 'b') => top.saturating_sub(page),
+                    event::KeyCode::Char('j') | event::KeyCode::Down | event::KeyCode::Enter => {
+                        top + 1
+                    }
+                    event::KeyCode::Char('k') | event::KeyCode::Up => top.saturating_sub(1),
+                    event::KeyCode::Char(' ') | event::KeyCode::PageDown => top + page,
+                    event::KeyCode::PageUp => top.saturating_sub(page),
+                    event::KeyCode::Char('g') => 0,
+                    event::KeyCode::Char('G') => max_top,
+                    _ => top,
+                }
+                .min(max_top);
+            }
+            (event::KeyCode::Char('r'), "Normal") if is_ctrl(keyev, 'r') => {
+                self.undo(true)?;
+            }
+            (event::KeyCode::Char('u'), "Normal") => {
+                self.undo(false)?;
+            }
+            (event::KeyCode::Char('.'), "Normal") => {
+                return self.repeat_last_change();
+            }
+            (event::KeyCode::Char(c @ ('p' | 'P')), "Normal") => {
+                self.paste(c == 'P')?;
+            }
+            (event::KeyCode::Char(c @ (':' | '/' | '?')), "Normal")
+            | (event::KeyCode::Char(c @ ('/' | '?')), "Visual" | "Visual Line") => {
+                self.command_from = self.mode;
+                self.mode = "Command";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+                self.cmd_message.insert_char(0, c);
+
+                self.prev_cursor_col = self.cursor_col;
+                self.prev_cursor_row = self.cursor_row;
+
+                self.cursor_row = rows - 1;
+                self.cursor_col = 1;
+            }
+            // The cursor stays after the `:`, `/` or `?`
+            (event::KeyCode::Char('a'), "Command") if is_ctrl(keyev, 'a') => {
+                self.cursor_col = 1;
+            }
+            (event::KeyCode::Home, "Command") => self.cursor_col = 1,
+            (event::KeyCode::Char('e'), "Command") if is_ctrl(keyev, 'e') => {
+                self.cursor_col = self.cmd_message.len_chars() as u16;
+            }
+            (event::KeyCode::End, "Command") => {
+                self.cursor_col = self.cmd_message.len_chars() as u16;
+            }
+            (event::KeyCode::Left, "Command") => {
+                self.cursor_col = (self.cursor_col - 1).max(1);
+            }
+            (event::KeyCode::Right, "Command") => {
+                self.cursor_col = (self.cursor_col + 1).min(self.cmd_message.len_chars() as u16);
+            }
+            (event::KeyCode::Char('w'), "Command") if is_ctrl(keyev, 'w') => {
+                // The spaces before the cursor and the word before them
+                let chars: Vec<char> = self.cmd_message.chars().collect();
+                let end = self.cursor_col as usize;
+                let mut start = end;
+                while start > 1 && chars[start - 1] == ' ' {
+                    start -= 1;
+                }
+                if start > 1 {
+                    let word = regex::is_word_char(chars[start - 1]);
+                    while start > 1
+                        && chars[start - 1] != ' '
+                        && regex::is_word_char(chars[start - 1]) == word
+                    {
+                        start -= 1;
+                    }
+                }
+                self.cmd_message.remove(start..end);
+                self.cursor_col = start as u16;
+            }
+            (event::KeyCode::Tab, "Command") => self.complete_command(false),
+            (event::KeyCode::BackTab, "Command") => self.complete_command(true),
+            (event::KeyCode::Char(c), "Command") => {
+                self.cmd_message.insert_char(self.cursor_col.into(), c);
+                self.cursor_col += 1;
+            }
+            (event::KeyCode::Backspace, "Command") => {
+                // Only an empty command line is left by backspacing
+                if self.cursor_col == 1 && self.cmd_message.len_chars() > 1 {
+                    return Ok(false);
+                }
+                if self.cursor_col == 1 {
+                    self.mode = self.command_from;
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cursor_col = self.prev_cursor_col;
+                    self.cursor_row = self.prev_cursor_row;
+                    return Ok(false);
+                }
+                self.cmd_message
+                    .remove((self.cursor_col as usize - 1)..(self.cursor_col as usize));
+                self.cursor_col -= 1;
+            }
+            (event::KeyCode::Esc, "Command") => {
+                self.mode = self.command_from;
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+
+                self.cursor_col = self.prev_cursor_col;
+                self.cursor_row = self.prev_cursor_row;
+            }
+            (event::KeyCode::Enter, "Command") => {
+                let message = self.cmd_message.to_string();
+                // The command line stays showing until something replaces it,
+                // which is no message to keep
+                self.logged_message = Some(message.clone());
+                // The `=` register from Insert mode types the expression's value
+                if let Some(expression) = message.strip_prefix('=') {
+                    self.cursor_col = self.prev_cursor_col;
+                    self.cursor_row = self.prev_cursor_row;
+                    self.mode = self.command_from;
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    match expr::eval(expression) {
+                        Ok(value) => self.insert_text(&value)?,
+                        Err(err) => self.cmd_message.insert(0, &err),
+                    }
+                    return Ok(false);
+                }
+                if let Some(backward) = match message.chars().next() {
+                    Some('/') => Some(false),
+                    Some('?') => Some(true),
+                    _ => None,
+                } {
+                    self.cursor_col = self.prev_cursor_col;
+                    self.cursor_row = self.prev_cursor_row;
+                    // Searching from a visual mode extends the selection
+                    self.mode = self.command_from;
+                    // An empty pattern searches for the last one again
+                    if message.len() > 1 {
+                        self.last_search = Some(message[1..].to_string());
+                    }
+                    self.search_backward = backward;
+                    self.search_next(false)?;
+                    return Ok(false);
+                }
+                let message = message.strip_prefix(':').unwrap_or(&message);
+                if !message.trim().is_empty() {
+                    self.last_command = Some(message.to_string());
+                }
+                // Anything but quitting or settings may want all of a lazily
+                // loaded file
+                let first_word = message.split_whitespace().next();
+                let loaded = matches!(first_word, Some("q" | "quit" | "q!" | "set" | "se"))
+                    || self.finish_loading();
+
+                self.cursor_col = self.prev_cursor_col;
+                self.cursor_row = self.prev_cursor_row;
+                self.mode = "Normal";
+                if !loaded {
+                    return Ok(false);
+                }
+
+                let (range, command) = match self.parse_range(message.trim_start()) {
+                    Ok(parsed) => parsed,
+                    Err(err) => {
+                        self.command_error(&err);
+                        return Ok(false);
+                    }
+                };
+                let command = command.trim_start();
+                let words: Vec<&str> = command.split_whitespace().collect();
+                let name = words.first().copied().unwrap_or("");
+                // Commands that take a range default to the current line
+                let current = self.cursor_line();
+                let (first, last) = range.unwrap_or((current, current));
+
+                if command.is_empty() {
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    if range.is_some() {
+                        self.push_jump();
+                        self.move_cursor(last, self.first_non_blank(last))?;
+                    }
+                } else if name == "q" || name == "quit" {
+                    return self.quit(false);
+                } else if name == "q!" {
+                    return self.quit(true);
+                } else if matches!(name, "tabnew" | "tabe" | "tabedit") {
+                    if !self.autowrite() {
+                        return Ok(false);
+                    }
+                    if let Some(shell_command) =
+                        command[name.len()..].trim_start().strip_prefix('!')
+                    {
+                        self.tab_new_scratch(shell_command)?;
+                    } else if words.len() > 2 {
+                        self.command_error("Too many args for :tabnew");
+                    } else {
+                        self.tab_new(words.get(1).map(PathBuf::from))?;
+                    }
+                } else if let Some(force) = match name {
+                    "tabclose" | "tabc" => Some(false),
+                    "tabclose!" | "tabc!" => Some(true),
+                    _ => None,
+                } {
+                    if let Err(err) = self.tab_close(force) {
+                        self.command_error(&err);
+                    }
+                } else if let Some((quit, force)) = match name {
+                    "w" | "write" => Some((false, false)),
+                    "w!" | "write!" => Some((false, true)),
+                    "wq" => Some((true, false)),
+                    "wq!" => Some((true, true)),
+                    _ => None,
+                } {
+                    // `:wq` always writes the whole buffer
+                    let lines = range
+                        .filter(|&(first, last)| {
+                            !quit && (first > 0 || last + 1 < self.line_count())
+                        })
+                        .map(|_| (first, last));
+                    // `:w >> name` adds to the end of the file instead
+                    let mut args = words[1..].to_vec();
+                    let append = !quit && args.first().is_some_and(|arg| arg.starts_with(">>"));
+                    if append && args[0] == ">>" {
+                        args.remove(0);
+                    } else if append {
+                        let name = args[0];
+                        args[0] = &name[2..];
+                    }
+                    let path = args.first().map(|path| path.to_string());
+                    let clobber = !force
+                        && !append
+                        && path.as_ref().is_some_and(|path| self.is_other_file(path));
+                    if args.len() > 1 {
+                        self.command_error(if quit {
+                            "Too many args for :wq"
+                        } else {
+                            "Too many args for :write"
+                        });
+                    } else if lines.is_some() && path.is_none() {
+                        self.command_error("Give a file name to write part of the buffer");
+                    } else if append {
+                        let result = match path {
+                            Some(path) => {
+                                let (first, last) = lines.unwrap_or((0, self.line_count() - 1));
+                                self.write_lines(first, last, &path, true)
+                            }
+                            None => Err("Give a file name to append to".to_string()),
+                        };
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        match result {
+                            Ok(message) => self.cmd_message.insert(0, &message),
+                            Err(err) => self.command_error(&err),
+                        }
+                    } else if clobber && self.options.confirm {
+                        let path = path.unwrap_or_default();
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message
+                            .insert(0, &format!("Overwrite existing file {:?}? (y/n)", path));
+                        self.pending_write = Some(PendingWrite { path, lines, quit });
+                        self.mode = "Confirm";
+                        return Ok(false);
+                    } else if clobber {
+                        self.command_error(&format!(
+                            "{:?} exists (add ! to overwrite)",
+                            path.unwrap_or_default()
+                        ));
+                    } else if !self.write_to(path, lines) {
+                        self.command_failed = true;
+                    } else if quit {
+                        return self.quit(false);
+                    }
+                } else if name == "d" || name == "delete" {
+                    self.cut_lines(first, last);
+                    let line = first.min(self.line_count().saturating_sub(1));
+                    self.move_cursor(line, self.first_non_blank(line))?;
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message
+                        .insert(0, &format!("{} fewer", plural(last + 1 - first, "line")));
+                } else if name == "set" || name == "se" {
+                    let args = split_args(&command[name.len()..]);
+                    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                    match self.set_options(&args) {
+                        Ok(values) => self.show_output(&values)?,
+                        Err(err) => {
+                            self.show_output(&err)?;
+                            self.command_failed = true;
+                        }
+                    }
+                    // The view shrinks when the status line comes back
+                    self.move_cursor(self.cursor_line(), self.cursor_col as usize)?;
+                } else if name == "reverse" {
+                    let (first, last) = range.unwrap_or((0, self.line_count().saturating_sub(1)));
+                    self.reverse_lines(first, last);
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    let line = self.cursor_line().clamp(first, last);
+                    self.move_cursor(line, self.first_non_blank(line))?;
+                } else if matches!(name, "sort" | "sor" | "sort!" | "sor!") {
+                    let (first, last) = range.unwrap_or((0, self.line_count().saturating_sub(1)));
+                    let flags = words[1..].concat();
+                    let result = self.sort_lines(first, last, name.ends_with('!'), &flags);
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    match result {
+                        Ok(message) => {
+                            self.cmd_message.insert(0, &message);
+                            let line = self.cursor_line().clamp(first, last);
+                            self.move_cursor(line, self.first_non_blank(line))?;
+                        }
+                        Err(err) => self.command_error(&err),
+                    }
+                } else if name == "retab" || name == "retab!" || name == "ret" || name == "ret!" {
+                    let (first, last) = range.unwrap_or((0, self.line_count().saturating_sub(1)));
+                    let tabstop = match words.get(1).map(|arg| arg.parse::<usize>()) {
+                        None => Ok(None),
+                        Some(Ok(tabstop)) if tabstop > 0 => Ok(Some(tabstop)),
+                        Some(_) => Err(format!("Invalid tabstop for :retab: {}", words[1])),
+                    };
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    match tabstop {
+                        Ok(tabstop) => {
+                            // Keep the cursor over the same screen column
+                            let line = self.cursor_line();
+                            let display = self.display_col(line, self.cursor_col as usize);
+                            let changed = self.retab(first, last, name.ends_with('!'), tabstop);
+                            self.cmd_message
+                                .insert(0, &format!("Retabbed {}", plural(changed, "line")));
+                            self.move_cursor(line, self.col_at_display(line, display))?;
+                        }
+                        Err(err) => self.command_error(&err),
+                    }
+                } else if name == "f" || name == "file" {
+                    if words.len() > 2 {
+                        self.command_error("Too many args for :file");
+                    } else {
+                        if words.len() == 2 {
+                            self.filename = Some(PathBuf::from(words[1]));
+                        }
+                        let message = self.file_info();
+                        self.cmd_message.remove(0..self.cmd_message.len_chars());
+                        self.cmd_message.insert(0, &message);
+                    }
+                } else if name == "grep" {
+                    let pattern = command[name.len()..].trim();
+                    let (first, last) = range.unwrap_or((0, self.line_count().saturating_sub(1)));
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    let result = if pattern.is_empty() {
+                        Err("Give a pattern to :grep".to_string())
+                    } else {
+                        self.grep(pattern, first, last)
+                    };
+                    if let Err(err) = result {
+                        self.command_error(&err);
+                    }
+                } else if matches!(name, "cn" | "cnext" | "cp" | "cprevious" | "cN") {
+                    let count = self.quickfix.matches.len();
+                    let index = self.quickfix.index;
+                    if count == 0 {
+                        self.command_error("No quickfix list, run :grep first");
+                    } else if name.starts_with("cn") && index + 1 < count {
+                        self.jump_to_match(index + 1)?;
+                    } else if !name.starts_with("cn") && index > 0 {
+                        self.jump_to_match(index - 1)?;
+                    } else {
+                        self.command_error("No more matches");
+                    }
+                } else if name == "copen" || name == "cope" {
+                    if self.quickfix.matches.is_empty() {
+                        self.command_error("No quickfix list, run :grep first");
+                    } else {
+                        self.mode = "Quickfix";
+                    }
+                } else if matches!(name, "put" | "pu" | "put!" | "pu!") {
+                    // The range gives line 0, above the first line, as 1
+                    let zero = message
+                        .trim_start()
+                        .split(|c: char| !c.is_ascii_digit())
+                        .next()
+                        .is_some_and(|number| number.parse() == Ok(0));
+                    let above = name.ends_with('!') || zero;
+                    let register = match words.get(1) {
+                        None => Some('"'),
+                        Some(arg) => arg.chars().next().filter(|_| arg.chars().count() == 1),
+                    };
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    if let Err(err) = self.put(last, above, register) {
+                        self.command_error(&err);
+                    }
+                } else if let Some((copy, address)) = move_args(command) {
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    let result = self
+                        .parse_destination(address)
+                        .and_then(|dest| self.move_lines(first, last, dest, copy));
+                    match result {
+                        Ok(message) => self.cmd_message.insert(0, &message),
+                        Err(err) => self.command_error(&err),
+                    }
+                } else if let Some(vertical) = match name {
+                    "sp" | "split" => Some(false),
+                    "vs" | "vsplit" => Some(true),
+                    _ => None,
+                } {
+                    if words.len() > 1 {
+                        self.command_error(&format!("Too many args for :{}", name));
+                    } else {
+                        self.split_window(vertical)?;
+                    }
+                } else if name == "clo" || name == "close" {
+                    if self.windows.is_empty() {
+                        self.command_error("Cannot close last window");
+                    } else {
+                        self.close_window()?;
+                    }
+                } else if name == "on" || name == "only" {
+                    self.only_window()?;
+                } else if name == "e" || name == "edit" {
+                    // A directory is listed in the browser
+                    match words.get(1).map(PathBuf::from) {
+                        _ if words.len() > 2 => self.command_error("Too many args for :edit"),
+                        None => self.command_error("Give a file name to :edit"),
+                        Some(path) if path.is_dir() => {
+                            if self.autowrite() {
+                                self.explore(path, None);
+                            }
+                        }
+                        Some(path) => self.browse_to(path)?,
+                    }
+                } else if matches!(name, "Explore" | "Ex") {
+                    // The file's own directory, with the file picked
+                    let arg = command[name.len()..].trim();
+                    let current = self
+                        .filename
+                        .clone()
+                        .map(|f| std::fs::canonicalize(&f).unwrap_or(f));
+                    let (dir, selected) = match current.as_ref() {
+                        _ if !arg.is_empty() => (PathBuf::from(arg), None),
+                        Some(path) => (
+                            path.parent().map_or(PathBuf::from("."), Path::to_path_buf),
+                            path.file_name(),
+                        ),
+                        None => (PathBuf::from("."), None),
+                    };
+                    if self.autowrite() {
+                        self.explore(dir, selected);
+                    }
+                } else if name == "undojoin" || name == "undoj" {
+                    if let Err(err) = self.history.join_next() {
+                        self.command_error(err);
+                    }
+                } else if name == "version" || name == "ve" {
+                    self.show_output(&version_info())?;
+                } else if matches!(name, "messages" | "mes") {
+                    if command[name.len()..].trim() == "clear" {
+                        self.messages.clear();
+                    } else {
+                        let listing = self.message_listing();
+                        self.show_output(&listing)?;
+                        // The listing is no message of its own
+                        self.logged_message = Some(self.cmd_message.to_string());
+                    }
+                } else if name == "registers" || name == "reg" {
+                    let (cols, _) = terminal_size()?;
+                    let listing = self.register_listing(cols as usize);
+                    self.show_output(&listing)?;
+                } else if name == "words" {
+                    let message = self.count_message();
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+                    self.cmd_message.insert(0, &message);
+                } else if let Some(shell_command) = command.strip_prefix('!') {
+                    // The command may read the file, a filter only gets the lines
+                    if range.is_none() && !self.autowrite() {
+                        return Ok(false);
+                    }
+                    let result = match range {
+                        Some((first, last)) => self.filter_lines(first, last, shell_command),
+                        None => Ok(self.run_shell(shell_command)?),
+                    };
+                    match result {
+                        Ok(message) => {
+                            self.move_cursor(self.cursor_line(), self.cursor_col as usize)?;
+                            self.show_output(&message)?;
+                        }
+                        Err(err) => {
+                            self.show_output(&err)?;
+                            self.command_failed = true;
+                        }
+                    }
+                } else if let Some(args) = substitute_args(command) {
+                    let result = self.substitute(first, last, args);
+                    self.show_substitute(result)?;
+                } else if let Some((invert, pattern, command)) = parse_global(command) {
+                    self.cmd_message.remove(0..self.cmd_message.len_chars());
+
+                    let (first, last) = range.unwrap_or((0, self.line_count().saturating_sub(1)));
+                    let result = if command != "d" {
+                        Err(format!("Unsupported :g command {}", command))
+                    } else if pattern.is_empty() {
+                        Err("No pattern for :g".to_string())
+                    } else {
+                        self.global_delete(&pattern, invert, first, last)
+                    };
+                    match result {
+                        Ok(count) => {
+                            self.cmd_message
+                                .insert(0, &format!("{} fewer", plural(count, "line")));
+                            self.move_cursor(self.cursor_line(), self.cursor_col as usize)?;
+                        }
+                        Err(err) => self.command_error(&err),
+                    }
+                } else {
+                    let cmd = self.cmd_message.to_string();
+                    self.command_error(&format!("Unrecognized command {}", cmd));
+                }
+            }
+            (event::KeyCode::Esc, "Insert") => {
+                self.last_insert = Some((self.cursor_line(), self.cursor_col as usize));
+                self.snippet = None;
+                self.mode = "Normal";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char('i'), "Normal") => {
+                self.mode = "Insert";
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char('a'), "Normal") => {
+                self.mode = "Insert";
+                self.cursor_col = (self.cursor_col + 1).min(self.line_max());
+                self.move_cursors(|col, len| (col + 1).min(len));
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char('I'), "Normal") => {
+                self.mode = "Insert";
+                self.cursor_col = 0;
+                self.move_cursors(|_, _| 0);
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char('A'), "Normal") => {
+                self.mode = "Insert";
+                self.cursor_col = self.line_max();
+                self.move_cursors(|_, len| len);
+                self.cmd_message.remove(0..self.cmd_message.len_chars());
+            }
+            (event::KeyCode::Char(c @ ('o' | 'O')), "Normal") => {
+                self.open_line(c == 'O')?;
+            }
+            (event::KeyCode::Char(c @ ('n' | 'p')), "Insert") if is_ctrl(keyev, c) => {
+                self.complete_word(c == 'p');
+            }
+            (event::KeyCode::Char(c @ ('t' | 'd')), "Insert") if is_ctrl(keyev, c) => {
+                self.shift_insert_line(c == 'd')?;
+            }
+            (event::KeyCode::Char('r'), "Insert") if is_ctrl(keyev, 'r') => {
+                self.pending = "\u{12}".to_string();
+            }
+            (event::KeyCode::Char('v'), "Insert") if is_ctrl(keyev, 'v') => {
+                self.pending = "\u{16}".to_string();
+            }
+            (event::KeyCode::Char(c), "Insert") => {
+                let cursor_pos = self
+                    .text
+                    .line_to_char(self.cursor_row as usize + self.shift_row)
+                    + self.cursor_col as usize;
+                if let Some(placeholder) = self
+                    .snippet
+                    .as_ref()
+                    .and_then(|snippet| snippet.placeholder(cursor_pos))
+                {
+                    self.remove(placeholder);
+                }
+                if let Some(snippet) = self.snippet.as_mut() {
+                    snippet.fresh = false;
+                }
+                self.insert(cursor_pos, &c.to_string());
+                self.cursor_col += 1;
+                if !c.is_whitespace() {
+                    self.wrap_typed_line()?;
+                }
+            }
+            (event::KeyCode::Backspace, "Insert") => {
+                let line = self.cursor_line();
+                if self.cursor_col == 0 && line == 0 {
+                    return Ok(false);
+                }
+
+                let cursor_pos = self.text.line_to_char(line) + self.cursor_col as usize;
+                let width = self.backspace_width(line, self.cursor_col as usize);
+
+                if self.cursor_col != 0 {
+                    self.cursor_col -= width as u16;
+                } else {
+                    self.move_cursor(line - 1, self.line_len(line - 1))?;
+                }
+
+                self.remove((cursor_pos - width)..(cursor_pos));
+            }
+            (event::KeyCode::Enter, "Insert") => {
+                let line = self.cursor_line();
+                let cursor_pos = self.text.line_to_char(line) + self.cursor_col as usize;
+                let indent = if self.options.autoindent {
+                    let mut indent = self.leading_whitespace(line);
+                    indent.truncate(self.cursor_col.into());
+                    indent
+                } else {
+                    String::new()
+                };
+                self.insert(cursor_pos, &format!("\n{}", indent));
+                self.move_cursor(line + 1, indent.chars().count())?;
+            }
+            (event::KeyCode::Tab, "Insert")
+                if self.snippet_trigger().is_some()
+                    || self
+                        .snippet
+                        .as_ref()
+                        .is_some_and(|snippet| snippet.current + 1 < snippet.stops.len()) =>
+            {
+                self.snippet_tab(false)?;
+            }
+            (event::KeyCode::BackTab, "Insert") if self.snippet.is_some() => {
+                self.snippet_tab(true)?;
+            }
+            (event::KeyCode::Tab, "Insert") => {
+                let line = self.cursor_line();
+                let cursor_pos = self.text.line_to_char(line) + self.cursor_col as usize;
+                let tab = if self.options.expandtab {
+                    // Pad with spaces up to the next tab stop
+                    let width = match self.options.softtabstop {
+                        0 => self.options.tabstop,
+                        softtabstop => softtabstop,
+                    };
+                    let col = self.display_col(line, self.cursor_col as usize);
+                    " ".repeat(width - col % width)
+                } else {
+                    "\t".to_string()
+                };
+                self.insert(cursor_pos, &tab);
+                self.cursor_col += tab.chars().count() as u16;
+            }
+            _ => {
+                if let Some(logs) = self.logs.as_mut() {
+                    writeln!(logs, "Unknown key")?;
+                }
+            }
+        }
+
+        Ok(false)
+    }
+    // Char cols where each screen row of `line` starts. Without `wrap` that
+    // is only the first one, and the rest of a long line is cut off.
+    fn line_rows(&self, line: usize, text_cols: usize) -> Vec<usize> {
+        // None for the lines a closed fold hides
+        if let Some((start, _)) = self.closed_fold(line) {
+            return if line == start { vec![0] } else { Vec::new() };
+        }
+        let mut starts = vec![0];
+        if !self.options.wrap {
+            return starts;
+        }
+        let showbreak = self.options.showbreak.chars().count();
+        let continued_cols = text_cols.saturating_sub(showbreak).max(1);
+        let mut width = 0;
+        let mut row_width = 0;
+        for (col, c) in self.line_chars(line).into_iter().enumerate() {
+            let cell = if c == '\t' {
+                self.options.tabstop - width % self.options.tabstop
+            } else {
+                1
+            };
+            let row_cols = if starts.len() == 1 {
+                text_cols
+            } else {
+                continued_cols
+            };
+            if row_width > 0 && row_width + cell > row_cols {
+                starts.push(col);
+                row_width = 0;
+            }
+            row_width += cell;
+            width += cell;
+        }
+        starts
+    }
+    // Screen row of the cursor and its column in the text area
+    fn cursor_screen_pos(&self, text_cols: usize) -> (usize, usize) {
+        let line = self.cursor_line();
+        let col = self.cursor_col as usize;
+        let rows_above: usize = (self.shift_row..line)
+            .map(|line| self.line_rows(line, text_cols).len())
+            .sum();
+        if self.closed_fold(line).is_some() {
+            return (rows_above, 0);
+        }
+        let starts = self.line_rows(line, text_cols);
+        let row = starts.iter().rposition(|start| *start <= col).unwrap_or(0);
+        let offset = if row > 0 {
+            self.options.showbreak.chars().count()
+        } else {
+            0
+        };
+        let display = self.display_col(line, col) - self.display_col(line, starts[row]);
+        (rows_above + row, offset + display + self.virtual_cols)
+    }
+    // Buffer position shown at a screen cell of the text area
+    fn screen_to_pos(&self, screen_row: usize, screen_col: usize, text_cols: usize) -> Pos {
+        let mut row = 0;
+        let last_line = self.text.len_lines() - 1;
+        for line in self.shift_row..=last_line {
+            let starts = self.line_rows(line, text_cols);
+            if starts.is_empty() {
+                continue;
+            }
+            if screen_row < row + starts.len() || line == last_line {
+                let index = (screen_row - row).min(starts.len() - 1);
+                let offset = if index > 0 {
+                    self.options.showbreak.chars().count()
+                } else {
+                    0
+                };
+                let display =
+                    self.display_col(line, starts[index]) + screen_col.saturating_sub(offset);
+                return (line, self.col_at_display(line, display));
+            }
+            row += starts.len();
+        }
+        (last_line, 0)
+    }
+    // Scrolls down until the cursor's screen row is on screen, for when
+    // wrapped lines above it take more than one row each
+    fn keep_cursor_visible(&mut self) -> std::io::Result<()> {
+        if (!self.options.wrap && self.folds.is_empty()) || self.mode == "Command" {
+            return Ok(());
+        }
+        let size = terminal_size()?;
+        let text_rows = self.text_rows(size);
+        let text_cols = self.text_cols(self.window_rect(size).cols);
+        let line = self.cursor_line();
+        while self.shift_row < line && self.cursor_screen_pos(text_cols).0 >= text_rows {
+            self.shift_row += 1;
+        }
+        self.cursor_row = (line - self.shift_row) as u16;
+        Ok(())
+    }
+    fn render_pager(&self, screen: &mut Screen) {
+        let (_, rows) = screen.size();
+        let page = (rows - 1) as usize;
+        let shown = self.pager.lines.iter().skip(self.pager.top).take(page);
+        for (row, line) in shown.enumerate() {
+            screen.move_to(0, row as u16);
+            screen.print(line);
+        }
+        let last = (self.pager.top + page).min(self.pager.lines.len());
+        let status = format!(
+            "-- {}-{} of {} -- j/k, Ctrl-D/Ctrl-U to scroll, q to dismiss",
+            self.pager.top + 1,
+            last,
+            self.pager.lines.len()
+        );
+        screen.move_to(0, rows - 1);
+        screen.print_styled(status.reverse());
+        screen.cursor = (0, rows - 1);
+    }
+    fn render_quickfix(&self, screen: &mut Screen) {
+        let (_, rows) = screen.size();
+        let page = (rows - 1) as usize;
+        let number_width = self.quickfix.matches.last().unwrap().0 .0.to_string().len() + 1;
+        let shown = self.quickfix.matches.iter().enumerate();
+        for (row, (index, ((line, _), text))) in
+            shown.skip(self.quickfix.top).take(page).enumerate()
+        {
+            let entry = format!(
+                "{:>w$}: {}",
+                line + 1,
+                text.replace('\t', " "),
+                w = number_width
+            );
+            screen.move_to(0, row as u16);
+            if index == self.quickfix.index {
+                screen.print_styled(entry.reverse());
+            } else {
+                screen.print(&entry);
+            }
+        }
+        let status = format!(
+            "-- match {} of {} -- j/k to select, Enter to jump, q to dismiss",
+            self.quickfix.index + 1,
+            self.quickfix.matches.len()
+        );
+        screen.move_to(0, rows - 1);
+        screen.print_styled(status.reverse());
+        screen.cursor = (0, rows - 1);
+    }
+    fn render_browser(&self, screen: &mut Screen) {
+        let (_, rows) = screen.size();
+        let page = (rows - 1) as usize;
+        let shown = self.browser.entries.iter().enumerate();
+        for (row, (index, entry)) in shown.skip(self.browser.top).take(page).enumerate() {
+            screen.move_to(0, row as u16);
+            if index == self.browser.index {
+                screen.print_styled(entry.as_str().reverse());
+            } else if entry.ends_with('/') {
+                screen.print_styled(entry.as_str().blue());
+            } else {
+                screen.print(entry);
+            }
+        }
+        screen.move_to(0, rows - 1);
+        // Why an entry could not be opened, until the next key
+        if self.cmd_message.len_chars() > 0 {
+            screen.print(&self.cmd_message.to_string());
+        } else {
+            let status = format!(
+                "-- {} -- j/k to select, Enter to open, - to go up, q to dismiss",
+                self.browser.dir.display()
+            );
+            screen.print_styled(status.reverse());
+        }
+        screen.cursor = (0, rows - 1);
+    }
+    // With `inccommand`, the text as the `:s` being typed would leave it, as
+    // far as it shows, with the char ranges of the replacements in it
+    fn substitute_preview(
+        &mut self,
+        text_rows: usize,
+    ) -> Option<(Rope, Vec<std::ops::Range<usize>>)> {
+        if self.mode != "Command" || self.options.inccommand.is_empty() {
+            return None;
+        }
+        let message = self.cmd_message.to_string();
+        let command = message.strip_prefix(':')?.trim_start();
+        // Addresses like `.` are from the cursor in the text, not on the
+        // command line
+        let cursor_row = std::mem::replace(&mut self.cursor_row, self.prev_cursor_row);
+        let parsed = self.parse_range(command);
+        let current = self.cursor_line();
+        self.cursor_row = cursor_row;
+        let (range, command) = parsed.ok()?;
+        let args = substitute_args(command.trim_start())?;
+        let delimiter = args.chars().next()?;
+        let (pattern, rest) = split_delimited(&args[delimiter.len_utf8()..], delimiter);
+        let (replacement, flags) = split_delimited(rest, delimiter);
+        let regex = Regex::new(&pattern).ok().filter(|_| !pattern.is_empty())?;
+        let (first, last) = range.unwrap_or((current, current));
+        let mut text = self.text.clone();
+        let mut replaced = Vec::new();
+        // Lines the replacements above have added
+        let mut added = 0;
+        let last = last
+            .min(self.shift_row + text_rows)
+            .min(self.text.len_lines() - 1);
+        for line in first.max(self.shift_row)..=last {
+            let chars = self.line_chars(line);
+            let (result, ranges) =
+                substitute_line(&chars, &regex, &replacement, flags.contains('g'));
+            if ranges.is_empty() {
+                continue;
+            }
+            let line_start = text.line_to_char(line + added);
+            text.remove(line_start..line_start + chars.len());
+            text.insert(line_start, &result);
+            replaced.extend(
+                ranges
+                    .into_iter()
+                    .map(|r| line_start + r.start..line_start + r.end),
+            );
+            added += result.matches('\n').count();
+        }
+        Some((text, replaced))
+    }
+    fn render(&mut self) -> std::io::Result<()> {
+        let (cols, rows) = terminal_size()?;
+        let mut screen = Screen::new(cols, rows);
+        match self.mode {
+            "Pager" => self.render_pager(&mut screen),
+            "Quickfix" => self.render_quickfix(&mut screen),
+            "Explore" => self.render_browser(&mut screen),
+            _ => {
+                let preview = self.substitute_preview(self.text_rows((cols, rows)));
+                self.render_windows(&mut screen, preview);
+            }
+        }
+        screen.draw(self.screen.as_ref(), &mut BufWriter::new(stdout().lock()))?;
+        self.screen = Some(screen);
+        Ok(())
+    }
+    // The tab bar, every window and the command line under them
+    fn render_windows(
+        &mut self,
+        screen: &mut Screen,
+        mut preview: Option<(Rope, Vec<std::ops::Range<usize>>)>,
+    ) {
+        let (cols, rows) = screen.size();
+        let top = self.tabline_rows();
+        if top > 0 {
+            // Each tab page's file, the current one highlighted
+            screen.move_to(0, 0);
+            for i in 0..self.tabs.len() {
+                let (filename, dirty) = if i == self.tab {
+                    (&self.filename, self.dirty)
+                } else {
+                    (&self.tabs[i].filename, self.tabs[i].dirty)
+                };
+                let name = filename
+                    .as_ref()
+                    .and_then(|filename| filename.file_name())
+                    .map_or("[No Name]".into(), |name| name.to_string_lossy());
+                let label = format!(" {}{} ", if dirty { "+ " } else { "" }, name);
+                if i == self.tab {
+                    screen.print_styled(label.reverse());
+                } else {
+                    screen.print_styled(label.dark_grey());
+                }
+            }
+        }
+        // Without a status line the typed keys go on the command line
+        let status_row = rows.saturating_sub(self.status_rows() + 1);
+        for (index, rect) in self.window_rects((cols, rows)) {
+            let current = index == self.window;
+            // Right-aligned items end where the typed keys of `showcmd` begin
+            let status_cols = self.has_status_line(rect, (cols, rows)).then(|| {
+                let bottom = (rect.row + rect.rows).saturating_sub(1);
+                if self.options.showcmd && bottom == status_row && rect.col + rect.cols == cols {
+                    (rect.cols as usize).saturating_sub(SHOWCMD_COLS + 1)
+                } else {
+                    rect.cols as usize
+                }
+            });
+            let mut view = Screen::new(rect.cols, rect.rows);
+            match preview.take() {
+                // Drawn in place of the text, which stays as it is until
+                // the command is run
+                Some((text, replaced)) if current => {
+                    let text = std::mem::replace(&mut self.text, text);
+                    self.render_text(&mut view, &replaced, true, status_cols);
+                    self.text = text;
+                }
+                taken => {
+                    preview = taken;
+                    if !current {
+                        self.swap_window(index);
+                    }
+                    self.render_text(&mut view, &[], current, status_cols);
+                    if !current {
+                        self.swap_window(index);
+                    }
+                }
+            }
+            screen.put(&view, rect.col, rect.row);
+            if current {
+                screen.cursor = (rect.col + view.cursor.0, rect.row + view.cursor.1);
+            }
+            // A line between windows side by side
+            if rect.col + rect.cols < cols {
+                for row in rect.row..rect.row + rect.rows {
+                    screen.move_to(rect.col + rect.cols, row);
+                    screen.print_styled('│'.dark_grey());
+                }
+            }
+        }
+        if self.options.showcmd {
+            // The command typed so far, the end of it when it is long
+            let mut typed: String = self
+                .register_name
+                .map_or(String::new(), |name| format!("\"{}", name));
+            if let Some(count) = self.count {
+                typed.push_str(&count.to_string());
+            }
+            typed.push_str(&caret_notation(&self.pending));
+            let typed: Vec<char> = typed.chars().collect();
+            let typed: String = typed[typed.len().saturating_sub(SHOWCMD_COLS)..]
+                .iter()
+                .collect();
+            screen.move_to(cols.saturating_sub(SHOWCMD_COLS as u16 + 1), status_row);
+            screen.print(&format!("{:<1$}", typed, SHOWCMD_COLS));
+        }
+        if let Some(completion) = self.command_completion.as_ref() {
+            render_wildmenu(screen, completion, rows.saturating_sub(2));
+        }
+        screen.move_to(0, rows - 1);
+        // With nothing else to say, what is wrong with the cursor line
+        match self.diagnostic_at(self.cursor_line()) {
+            Some(diagnostic) if self.cmd_message.len_chars() == 0 && self.mode == "Normal" => {
+                let message = diagnostic.message.lines().next().unwrap_or_default();
+                screen.print_styled(message.with(severity_color(diagnostic.severity)));
+            }
+            _ => screen.print(&self.cmd_message.to_string()),
+        }
+        // The cursor is on the command line while typing a command
+        if self.mode == "Command" {
+            screen.cursor = (self.cursor_col, self.cursor_row);
+        }
+        screen.cursor_style = self.options.cursor_style(self.mode);
+    }
+    // The text of the current window, or of another one when not `current`
+    // with its view swapped in, and its status line when `status_cols` wide
+    fn render_text(
+        &self,
+        screen: &mut Screen,
+        replaced: &[std::ops::Range<usize>],
+        current: bool,
+        status_cols: Option<usize>,
+    ) {
+        let (cols, rows) = screen.size();
+
+        let gutter = self.gutter_width();
+        let text_cols = self.text_cols(cols);
+
+        let text_rows = rows.saturating_sub(status_cols.is_some() as u16).max(1) as usize;
+        let showbreak = self.options.showbreak.as_str();
+        // The cursor is on the command line while typing a command, and
+        // only the current window shows where it is
+        let bracket = if !current || self.mode == "Command" || !self.options.syntax {
+            None
+        } else {
+            self.cursor_bracket()
+        };
+        let partner = bracket.and_then(|pos| self.matching_bracket(pos));
+        // Snippet text that typing will replace shows as selected
+        let placeholder = match self.snippet.as_ref() {
+            Some(snippet) if current && self.mode == "Insert" => {
+                let cursor = self.text.line_to_char(self.cursor_line()) + self.cursor_col as usize;
+                snippet.placeholder(cursor).unwrap_or_default()
+            }
+            _ => 0..0,
+        };
+        let flash = self.flash.as_ref().map_or(0..0, |(range, _)| range.clone());
+        // The match `:s///c` is asking about, one cell when it is empty
+        let confirming = self
+            .confirm_range()
+            .map_or(0..0, |range| range.start..range.end.max(range.start + 1));
+        let mut screen_row = 0;
+        let mut line_index = self.shift_row;
+        while screen_row < text_rows && line_index < self.text.len_lines() {
+            let line_number = line_index;
+            screen.move_to(0, screen_row as u16);
+            let diagnostics: Vec<&lsp::Diagnostic> =
+                self.lsp.as_ref().map_or(Vec::new(), |client| {
+                    client
+                        .diagnostics
+                        .iter()
+                        .filter(|d| d.start.0 <= line_number && line_number <= d.end.0)
+                        .collect()
+                });
+            if self.lsp.is_some() {
+                // The most severe diagnostic on the line
+                match diagnostics.iter().map(|d| d.severity).min() {
+                    Some(severity) => {
+                        let sign = format!("{} ", severity_sign(severity));
+                        screen.print_styled(sign.with(severity_color(severity)));
+                    }
+                    None => screen.print("  "),
+                }
+            }
+            if self.options.number {
+                let signs = if self.lsp.is_some() { 2 } else { 0 };
+                let number = format!("{:>1$} ", line_number + 1, gutter - signs - 1);
+                screen.print_styled(number.dark_grey());
+            }
+            if let Some((_, end)) = self.closed_fold(line_number) {
+                let label = format!(
+                    "+--{:>3} lines: {}",
+                    end - line_number + 1,
+                    self.text.line(line_number).to_string().trim()
+                );
+                let label: String = label
+                    .chars()
+                    .chain(std::iter::repeat('-'))
+                    .take(text_cols)
+                    .collect();
+                screen.print_styled(label.dark_cyan());
+                screen_row += 1;
+                line_index = end + 1;
+                continue;
+            }
+            // Underlined columns, the end of the line for diagnostics that
+            // go on past it
+            let diagnostic_cols: Vec<(usize, usize, style::Color)> = diagnostics
+                .iter()
+                .map(|d| {
+                    let start = if d.start.0 == line_number {
+                        d.start.1
+                    } else {
+                        0
+                    };
+                    let end = if d.end.0 == line_number {
+                        d.end.1
+                    } else {
+                        usize::MAX
+                    };
+                    // An empty range still marks the char it is at
+                    (start, end.max(start + 1), severity_color(d.severity))
+                })
+                .collect();
+
+            let selected = if current {
+                self.selected_cols(line_number)
+            } else {
+                None
+            };
+            let is_selected = |col| selected.is_some_and(|(start, end)| start <= col && col < end);
+            let misspelled = if self.options.spell && self.options.syntax {
+                spell::misspellings(&self.dictionary, &self.line_chars(line_number))
+            } else {
+                Vec::new()
+            };
+            let is_misspelled = |col| {
+                misspelled
+                    .iter()
+                    .any(|&(start, end)| start <= col && col < end)
+            };
+            let row_starts = self.line_rows(line_number, text_cols);
+            let line_start = self.text.line_to_char(line_number);
+            let colorcolumns: Vec<usize> = self.options.colorcolumn.iter().map(|c| c - 1).collect();
+            let indent_len = if self.options.indentguides {
+                self.first_non_blank(line_number)
+            } else {
+                0
+            };
+            let trailing_start = if self.options.hlwhitespace {
+                let chars = self.line_chars(line_number);
+                let blanks = chars
+                    .iter()
+                    .rev()
+                    .take_while(|c| **c == ' ' || **c == '\t')
+                    .count();
+                chars.len() - blanks
+            } else {
+                usize::MAX
+            };
+
+            let mut run = String::new();
+            let mut run_style = style::ContentStyle::new();
+            let mut width = 0;
+            let mut row_width = 0;
+            let mut line_len = 0;
+            for (col, c) in self.text.line(line_number).chars().enumerate() {
+                if c == '\n' {
+                    break;
+                }
+                if row_starts[1..].contains(&col) {
+                    // Continue the wrapped line on the next row, after the
+                    // showbreak marker
+                    screen.print_styled(run_style.apply(run));
+                    run = String::new();
+                    screen_row += 1;
+                    if screen_row >= text_rows {
+                        break;
+                    }
+                    screen.move_to(gutter as u16, screen_row as u16);
+                    screen.print_styled(showbreak.dark_grey());
+                    row_width = showbreak.chars().count();
+                }
+                line_len = col + 1;
+                let cell = if c == '\t' {
+                    " ".repeat(self.options.tabstop - width % self.options.tabstop)
+                } else {
+                    c.to_string()
+                };
+                width += cell.chars().count();
+                row_width += cell.chars().count();
+                if row_width > text_cols {
+                    break;
+                }
+
+                let mut cell_style = style::ContentStyle::new();
+                if is_selected(col)
+                    || placeholder.contains(&(line_start + col))
+                    || flash.contains(&(line_start + col))
+                    || confirming.contains(&(line_start + col))
+                    || replaced
+                        .iter()
+                        .any(|range| range.contains(&(line_start + col)))
+                    || (current && self.cursors.contains(&(line_start + col)))
+                {
+                    cell_style.attributes.set(style::Attribute::Reverse);
+                }
+                let cell_start = width - cell.chars().count();
+                if colorcolumns.iter().any(|c| cell_start <= *c && *c < width) {
+                    cell_style.background_color = Some(style::Color::DarkGrey);
+                }
+                if col >= trailing_start {
+                    cell_style.background_color = Some(style::Color::Red);
+                }
+                if let Some((_, _, color)) = diagnostic_cols
+                    .iter()
+                    .find(|(start, end, _)| *start <= col && col < *end)
+                {
+                    cell_style.attributes.set(style::Attribute::Underlined);
+                    cell_style.underline_color = Some(*color);
+                }
+                if is_misspelled(col) {
+                    cell_style.attributes.set(style::Attribute::Undercurled);
+                    cell_style.underline_color = Some(style::Color::Red);
+                    cell_style.foreground_color = Some(style::Color::Red);
+                }
+                // Both brackets of a pair, or a lone one in red
+                if bracket == Some(line_start + col) || partner == Some(line_start + col) {
+                    cell_style.background_color = Some(if partner.is_some() {
+                        style::Color::DarkCyan
+                    } else {
+                        style::Color::Red
+                    });
+                }
+                // Indentation is drawn a column at a time, for the guides in it
+                let pieces = if col < indent_len {
+                    let mut guide_style = cell_style;
+                    guide_style.foreground_color = Some(style::Color::DarkGrey);
+                    (cell_start..width)
+                        .map(|column| {
+                            if column % self.options.shiftwidth == 0 {
+                                ("│".to_string(), guide_style)
+                            } else {
+                                (" ".to_string(), cell_style)
+                            }
+                        })
+                        .collect()
+                } else {
+                    vec![(cell, cell_style)]
+                };
+                for (piece, piece_style) in pieces {
+                    if piece_style != run_style {
+                        screen.print_styled(run_style.apply(run));
+                        run = String::new();
+                        run_style = piece_style;
+                    }
+                    run.push_str(&piece);
+                }
+            }
+            screen.print_styled(run_style.apply(run));
+
+            // A selected, yanked or matched line end, or an extra cursor
+            // there, shows up as one highlighted cell
+            let end_marked = is_selected(line_len)
+                || flash.contains(&(line_start + line_len))
+                || confirming.contains(&(line_start + line_len))
+                || (current && self.cursors.contains(&(line_start + line_len)));
+            if end_marked && row_width < text_cols && screen_row < text_rows {
+                screen.print_styled(style::style(' ').reverse());
+            }
+            // Colored columns past the end of the line, though not on the
+            // empty one after a final newline
+            if screen_row < text_rows && line_number < self.line_count() {
+                for column in colorcolumns.iter().filter(|c| **c >= width) {
+                    let screen_col = row_width + column - width;
+                    if screen_col < text_cols && !(is_selected(line_len) && screen_col == row_width)
+                    {
+                        screen.move_to((gutter + screen_col) as u16, screen_row as u16);
+                        screen.print_styled(' '.on_dark_grey());
+                    }
+                }
+            }
+
+            screen_row += 1;
+            line_index += 1;
+        }
+
+        if self.options.scrollbar && text_rows > 0 {
+            // The thumb is as big a part of the bar as the view is of the
+            // file, and at the bottom when the view is
+            let lines = self.line_count();
+            let thumb_len = (text_rows * text_rows / lines.max(1)).clamp(1, text_rows);
+            let scrollable = lines.saturating_sub(text_rows).max(1);
+            let thumb_start = (self.shift_row.min(scrollable) * (text_rows - thumb_len)
+                / scrollable)
+                .min(text_rows - thumb_len);
+            for row in 0..text_rows {
+                screen.move_to(cols - 1, row as u16);
+                if (thumb_start..thumb_start + thumb_len).contains(&row) {
+                    screen.print_styled('█'.grey());
+                } else {
+                    screen.print_styled('░'.dark_grey());
+                }
+            }
+        }
+
+        let diagnostics = self
+            .lsp
+            .as_ref()
+            .map_or(&[][..], |client| &client.diagnostics);
+        let errors = diagnostics
+            .iter()
+            .filter(|d| d.severity == lsp::ERROR)
+            .count();
+        let warnings = diagnostics
+            .iter()
+            .filter(|d| d.severity == lsp::WARNING)
+            .count();
+        // On the command line the cursor is still where it was in the text
+        let (line, col) = if current && self.mode == "Command" {
+            (
+                self.shift_row + self.prev_cursor_row as usize,
+                self.prev_cursor_col,
+            )
+        } else {
+            (self.cursor_line(), self.cursor_col)
+        };
+        let lines = self.line_count();
+        let item = |item| match item {
+            'M' => self.mode.to_string(),
+            'f' => match self.filename.as_ref() {
+                Some(filename) => filename.to_str().unwrap().to_string(),
+                None if self.scratch => "[Scratch]".to_string(),
+                None => String::new(),
+            },
+            'm' if self.dirty => "+".to_string(),
+            'r' if self.readonly => "RO".to_string(),
+            'y' => self.filetype.clone(),
+            'l' => (line + 1).to_string(),
+            'c' => (col + 1).to_string(),
+            'L' => lines.to_string(),
+            'p' => ((line + 1) * 100 / lines.max(1)).min(100).to_string(),
+            'i' if self.options.expandtab => format!("spaces:{}", self.options.shiftwidth),
+            'i' => "tabs".to_string(),
+            'e' if self.noeol() => "[noeol]".to_string(),
+            'w' if self.large_file && !self.options.syntax => "large file, syntax off".to_string(),
+            'd' if errors + warnings > 0 => format!("E:{} W:{}", errors, warnings),
+            'n' if !self.cursors.is_empty() => format!("{} cursors", self.cursors.len() + 1),
+            _ => String::new(),
+        };
+        if let Some(width) = status_cols {
+            let status = statusline::render(&self.options.statusline, width, item);
+            screen.move_to(0, rows.saturating_sub(1));
+            if current {
+                screen.print(&status);
+            } else {
+                screen.print_styled(status.dark_grey());
+            }
+        }
+        if current && self.mode != "Command" {
+            let (row, col) = self.cursor_screen_pos(text_cols);
+            screen.cursor = ((gutter + col) as u16, row as u16);
+        }
+    }
+}
+
+fn run(
+    logs: Option<File>,
+    filename: Option<PathBuf>,
+    stdin: Option<Rope>,
+    readonly: bool,
+) -> std::io::Result<()> {
+    let from_stdin = stdin.is_some();
+    // A directory opens the browser in it, with an empty buffer behind
+    let browse = filename.clone().filter(|path| path.is_dir());
+    let filename = filename.filter(|path| !path.is_dir());
+    let config = Config::load();
+    let mut loader = None;
+    let mut size = 0;
+    let text = if let Some(text) = stdin {
+        text
+    } else if let Some(pathbuf) = filename.as_ref() {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!readonly)
+            .create(!readonly)
+            .truncate(false)
+            .open(pathbuf)?;
+
+        size = file.metadata()?.len();
+        if size >= loader::LAZY_SIZE {
+            // Just the start for now, the rest as it gets scrolled to
+            let lazy = Loader::start(file);
+            let start = lazy.next().transpose()?.unwrap_or_default();
+            loader = Some(lazy);
+            Rope::from(start)
+        } else {
+            Rope::from_reader(BufReader::new(file))?
+        }
+    } else {
+        Rope::new()
+    };
+    // A partly loaded file can't be checked against the saved history
+    let history = filename
+        .as_deref()
+        .filter(|_| loader.is_none())
+        .and_then(|path| History::load(path, &text.to_string()))
+        .unwrap_or_default();
+    let large_file = config.is_large(size);
+    let indent = if large_file {
+        None
+    } else {
+        detect_indent(text.lines().map(|line| line.to_string()))
+    };
+
+    let mut editor = Editor {
+        // Text from stdin exists nowhere else yet
+        dirty: from_stdin,
+        logs,
+        history,
+        loader,
+        readonly,
+        large_file,
+        indent,
+        ..Editor::new(text, filename, config)
+    };
+    if let Err(err) = editor.config.apply(
+        &editor.filetype,
+        editor.indent,
+        editor.large_file,
+        &mut editor.options,
+    ) {
+        editor.cmd_message.insert(0, &err);
+    }
+    if let Some(dir) = browse {
+        editor.explore(dir, None);
+    }
+
+    let mut mouse_captured = false;
+    let mut last_render = Instant::now();
+    // For how long nothing has happened, for `autosave`
+    let mut last_event = Instant::now();
+
+    loop {
+        let (cols, rows) = terminal_size()?;
+
+        if editor.options.mouse != mouse_captured {
+            mouse_captured = editor.options.mouse;
+            if mouse_captured {
+                execute!(stdout(), event::EnableMouseCapture)?;
+            } else {
+                execute!(stdout(), event::DisableMouseCapture)?;
+            }
+        }
+
+        if let Some(logs) = editor.logs.as_mut() {
+            writeln!(logs, "Size ({} x {})", cols, rows)?;
+        }
+
+        // Keep a screen's worth of a lazily loaded file past the view
+        editor.load_until(editor.shift_row + 2 * rows as usize);
+        editor.sync_lsp();
+        editor.keep_cursor_visible()?;
+        editor.log_message();
+        // While keys or resizes are coming in faster than they are handled,
+        // only draw now and then instead of after every one
+        let behind = !editor.typeahead.is_empty() || event::poll(Duration::ZERO)?;
+        if !behind || last_render.elapsed() > Duration::from_millis(50) {
+            editor.render()?;
+            last_render = Instant::now();
+        }
+        // Events, first any that came in during a long operation
+        if editor.typeahead.is_empty() {
+            if let Some((_, yanked)) = editor.flash.as_ref() {
+                let flash = Duration::from_millis(editor.options.yankflash as u64);
+                if !event::poll(flash.saturating_sub(yanked.elapsed()))? {
+                    editor.flash = None;
+                    continue;
+                }
+            }
+            let timeout = Duration::from_millis(editor.options.timeoutlen as u64);
+            // A register name after Ctrl-R in Insert mode, or a key after Ctrl-V,
+            // is waited for as long as it takes
+            if !editor.pending.is_empty() && editor.mode != "Insert" && !event::poll(timeout)? {
+                // Nothing came to finish the key sequence, forget it
+                editor.pending.clear();
+                continue;
+            }
+            if editor.lsp.is_some() {
+                // Until a key comes, take in what the language server sends
+                let mut updated = false;
+                while !updated && !event::poll(Duration::from_millis(50))? {
+                    updated = editor.handle_lsp_messages();
+                    if editor.autosave_in(last_event.elapsed()) == Some(Duration::ZERO) {
+                        editor.save();
+                        // Waiting out another period before trying again if the
+                        // write failed
+                        last_event = Instant::now();
+                        updated = true;
+                    }
+                }
+                if updated {
+                    continue;
+                }
+            } else if let Some(wait) = editor.autosave_in(last_event.elapsed()) {
+                if !event::poll(wait)? {
+                    editor.save();
+                    last_event = Instant::now();
+                    continue;
+                }
+            }
+        }
+        let ev = match editor.typeahead.pop_front() {
+            Some(ev) => ev,
+            None => event::read()?,
+        };
+        last_event = Instant::now();
+        editor.flash = None;
+
+        if let Some(logs) = editor.logs.as_mut() {
+            writeln!(logs, "Got event {:?}", ev)?;
+            writeln!(logs, "shift_row {}", editor.shift_row)?;
+            writeln!(logs, "text len lines {}", editor.text.len_lines())?;
+        }
+
+        match ev {
+            event::Event::Key(keyev) => {
+                if editor.handle_key(keyev)? {
+                    break;
+                }
+            }
+            event::Event::Mouse(mouse) => editor.handle_mouse(mouse)?,
+            event::Event::Resize(_, _) => (),
+            _ => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Words split on whitespace, and user-perceived characters: combining marks,
+// variation selectors, skin tones, zero-width joiner sequences and CRLF all
+// count along with the char they attach to
+fn count_words_and_chars(text: &str) -> (usize, usize) {
+    let words = text.split_whitespace().count();
+    let mut chars = 0;
+    let mut prev = None;
+    for c in text.chars() {
+        let extends = matches!(c as u32,
+            0x0300..=0x036f
+            | 0x0483..=0x0489
+            | 0x0591..=0x05bd
+            | 0x0610..=0x061a
+            | 0x064b..=0x065f
+            | 0x0900..=0x0903
+            | 0x093a..=0x094f
+            | 0x1ab0..=0x1aff
+            | 0x1dc0..=0x1dff
+            | 0x200c..=0x200d
+            | 0x20d0..=0x20ff
+            | 0xfe00..=0xfe0f
+            | 0xfe20..=0xfe2f
+            | 0x1f3fb..=0x1f3ff
+            | 0xe0020..=0xe007f)
+            || (c == '\n' && prev == Some('\r'))
+            || prev == Some('\u{200d}');
+        if !extends || prev.is_none() {
+            chars += 1;
+        }
+        prev = Some(c);
+    }
+    (words, chars)
+}
+
+// Reads up to an unescaped `delimiter`, returning the text before it and the rest
+fn split_delimited(text: &str, delimiter: char) -> (String, &str) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            if let Some((_, next)) = chars.next() {
+                if next != delimiter {
+                    part.push('\\');
+                }
+                part.push(next);
+            }
+        } else if c == delimiter {
+            return (part, &text[i + c.len_utf8()..]);
+        } else {
+            part.push(c);
+        }
+    }
+    (part, "")
+}
+
+// Splits `g/pattern/cmd`, `g!/pattern/cmd` or `v/pattern/cmd` into (invert, pattern, cmd)
+fn parse_global(command: &str) -> Option<(bool, String, String)> {
+    let rest = command;
+    let (invert, rest) = if let Some(rest) = rest.strip_prefix("g!") {
+        (true, rest)
+    } else if let Some(rest) = rest.strip_prefix('g') {
+        (false, rest)
+    } else {
+        (true, rest.strip_prefix('v')?)
+    };
+    let delimiter = rest.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() {
+        return None;
+    }
+    let (pattern, command) = split_delimited(&rest[delimiter.len_utf8()..], delimiter);
+    Some((invert, pattern, command.trim().to_string()))
+}
+
+// Start and end of the word (or run of punctuation) at `col`
+// What words are made of, for `w`, `b` and `e`: blanks, word chars, or the
+// other chars
+fn char_class(c: char) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if regex::is_word_char(c) {
+        1
+    } else {
+        2
+    }
+}
+
+fn word_bounds(line: &[char], col: usize) -> Option<(usize, usize)> {
+    let c = *line.get(col)?;
+    let kind = |c: char| (c.is_whitespace(), regex::is_word_char(c));
+    let start = col
+        - line[..col]
+            .iter()
+            .rev()
+            .take_while(|other| kind(**other) == kind(c))
+            .count();
+    let end = col
+        + line[col..]
+            .iter()
+            .take_while(|other| kind(**other) == kind(c))
+            .count();
+    Some((start, end))
+}
+
+// What `ys` and `S` wrap text in for `c`. Opening brackets add spaces inside,
+// closing ones don't.
+fn surround_pair(c: char) -> Option<(&'static str, &'static str)> {
+    Some(match c {
+        '(' => ("( ", " )"),
+        ')' | 'b' => ("(", ")"),
+        '[' => ("[ ", " ]"),
+        ']' | 'r' => ("[", "]"),
+        '{' => ("{ ", " }"),
+        '}' | 'B' => ("{", "}"),
+        '<' | '>' | 'a' => ("<", ">"),
+        '"' => ("\"", "\""),
+        '\'' => ("'", "'"),
+        '`' => ("`", "`"),
+        '*' => ("*", "*"),
+        '_' => ("_", "_"),
+        '|' => ("|", "|"),
+        _ => return None,
+    })
+}
+
+fn severity_sign(severity: usize) -> char {
+    match severity {
+        lsp::ERROR => 'E',
+        lsp::WARNING => 'W',
+        3 => 'I',
+        _ => 'H',
+    }
+}
 
-                    editor.cursor_col = prev_cursor_col;
-                    editor.cursor_row = prev_cursor_row;
-                }
-                (event::KeyCode::Enter, "Command") => {
-                    let message = editor.cmd_message.to_string();
-                    let words: Vec<&str> = message.split_whitespace().collect();
+fn severity_color(severity: usize) -> style::Color {
+    match severity {
+        lsp::ERROR => style::Color::Red,
+        lsp::WARNING => style::Color::Yellow,
+        _ => style::Color::Blue,
+    }
+}
 
-                    editor.cursor_col = prev_cursor_col;
-                    editor.cursor_row = prev_cursor_row;
+// The char a key stands for after Ctrl-V in Insert mode, control keys as
+// their ASCII control character
+fn literal_key(keyev: event::KeyEvent) -> Option<char> {
+    match keyev.code {
+        event::KeyCode::Char(c) if keyev.modifiers.contains(event::KeyModifiers::CONTROL) => {
+            Some(char::from(c.to_ascii_uppercase() as u8 & 0x1f))
+        }
+        event::KeyCode::Char(c) => Some(c),
+        event::KeyCode::Enter => Some('\r'),
+        event::KeyCode::Tab => Some('\t'),
+        event::KeyCode::Esc => Some('\u{1b}'),
+        event::KeyCode::Backspace => Some('\u{8}'),
+        _ => None,
+    }
+}
 
-                    if words[0] == ":q" || words[0] == ":quit" {
-                        if editor.dirty {
-                            editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                            editor
-                                .cmd_message
-                                .insert(0, "Unsaved changes! Save file with :w or force quit :q!");
-                            editor.mode = "Normal";
-                        } else {
-                            stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                            break;
-                        }
-                    } else if words[0] == ":q!" {
-                        stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                        break;
-                    } else if words[0] == ":w" || words[0] == ":write" {
-                        if words.len() > 2 {
-                            editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                            editor.cmd_message.insert(0, "Too many args for :write");
-                        } else if words.len() == 2 {
-                            editor.filename = Some(PathBuf::from(words[1]))
-                        }
+// The first number in `line`, with its minus sign if it has one
+fn first_number(line: &str) -> Option<i64> {
+    let start = line.find(|c: char| c.is_ascii_digit())?;
+    let digits = &line[start..];
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    let number = digits[..end].parse::<i64>().unwrap_or(i64::MAX);
+    Some(if line[..start].ends_with('-') {
+        -number
+    } else {
+        number
+    })
+}
 
-                        editor.mode = "Normal";
-                        editor.save();
-                    } else if words[0] == ":wq" {
-                        if words.len() > 2 {
-                            editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                            editor.cmd_message.insert(0, "Too many args for :wq");
-                        } else if words.len() == 2 {
-                            editor.filename = Some(PathBuf::from(words[1]))
-                        }
+// The char with code `digits` in `radix`, if there is one
+fn char_from_code(digits: &str, radix: u32) -> Option<char> {
+    char::from_u32(u32::from_str_radix(digits, radix).ok()?)
+}
 
-                        if editor.save() {
-                            stdout().execute(cursor::SetCursorStyle::SteadyBlock)?;
-                            break;
-                        } else {
-                            editor.mode = "Normal";
-                        }
-                    } else {
-                        editor.mode = "Normal";
-                        let cmd = editor.cmd_message.to_string();
-
-                        editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                        editor
-                            .cmd_message
-                            .insert(0, &format!("Unrecognized command {}", cmd));
-                    }
-                }
-                (event::KeyCode::Esc, "Insert") => {
-                    editor.mode = "Normal";
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('i'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('a'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cursor_col = (editor.cursor_col + 1).min(editor.line_max());
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('I'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cursor_col = 0;
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('A'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cursor_col = editor.line_max();
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('o'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cursor_col = editor.line_max();
-                    let cursor_pos = editor
-                        .text
-                        .line_to_char(editor.cursor_row as usize + editor.shift_row)
-                        + editor.cursor_col as usize;
-                    editor.dirty = true;
-                    editor.text.insert_char(cursor_pos, '\n');
-                    editor.cursor_row += 1;
-                    editor.cursor_col = 0;
-
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char('O'), "Normal") => {
-                    editor.mode = "Insert";
-                    editor.cursor_col = 0;
-                    let cursor_pos = editor
-                        .text
-                        .line_to_char(editor.cursor_row as usize + editor.shift_row)
-                        + editor.cursor_col as usize;
-                    editor.dirty = true;
-                    editor.text.insert_char(cursor_pos, '\n');
-                    editor.cursor_col = 0;
-
-                    editor.cmd_message.remove(0..editor.cmd_message.len_chars());
-                }
-                (event::KeyCode::Char(c), "Insert") => {
-                    let cursor_pos = editor
-                        .text
-                        .line_to_char(editor.cursor_row as usize + editor.shift_row)
-                        + editor.cursor_col as usize;
-                    editor.dirty = true;
-                    editor.text.insert_char(cursor_pos, c);
-                    editor.cursor_col += 1;
-                }
-                (event::KeyCode::Backspace, "Insert") => {
-                    if editor.cursor_col == 0 && editor.cursor_row == 0 {
-                        continue;
-                    }
+// The terminal's size. Tests have no terminal, they get a standard one.
+#[cfg(not(test))]
+fn terminal_size() -> std::io::Result<(u16, u16)> {
+    terminal::size()
+}
+#[cfg(test)]
+fn terminal_size() -> std::io::Result<(u16, u16)> {
+    Ok((80, 24))
+}
 
-                    let cursor_pos = editor
-                        .text
-                        .line_to_char(editor.cursor_row as usize + editor.shift_row)
-                        + editor.cursor_col as usize;
+// Shell commands run with raw mode off, for the terminal to be as they
+// expect. Tests have no terminal to change.
+#[cfg(not(test))]
+fn set_raw_mode(raw: bool) -> std::io::Result<()> {
+    if raw {
+        terminal::enable_raw_mode()
+    } else {
+        terminal::disable_raw_mode()
+    }
+}
+#[cfg(test)]
+fn set_raw_mode(_raw: bool) -> std::io::Result<()> {
+    Ok(())
+}
 
-                    if editor.cursor_col != 0 {
-                        editor.cursor_col -= 1;
-                    } else {
-                        editor.cursor_row -= 1;
-                        editor.cursor_col = editor.line_max();
-                    }
+fn is_ctrl(keyev: event::KeyEvent, c: char) -> bool {
+    keyev.code == event::KeyCode::Char(c) && keyev.modifiers.contains(event::KeyModifiers::CONTROL)
+}
 
-                    editor.text.remove((cursor_pos - 1)..(cursor_pos));
+// The number Ctrl-A or Ctrl-X changes in `line`: the one under `col`, or
+// else the first one after it. Returns its range and what it becomes.
+// Dates and hex numbers are only taken when `formats` has them, and a date
+// is taken as a whole rather than as three numbers.
+fn increment_number(
+    line: &[char],
+    col: usize,
+    delta: i64,
+    formats: &[&str],
+) -> Option<(usize, usize, String)> {
+    let digits_at = |start: usize, hex: bool| {
+        line[start.min(line.len())..]
+            .iter()
+            .take_while(|c| {
+                if hex {
+                    c.is_ascii_hexdigit()
+                } else {
+                    c.is_ascii_digit()
                 }
-                (event::KeyCode::Enter, "Insert") => {
-                    let cursor_pos = editor
-                        .text
-                        .line_to_char(editor.cursor_row as usize + editor.shift_row)
-                        + editor.cursor_col as usize;
-                    editor.dirty = true;
-                    editor.text.insert_char(cursor_pos, '\n');
-                    editor.cursor_row += 1;
-                    editor.cursor_col = 0;
-                }
-                _ => {
-                    if let Some(logs) = logs.as_mut() {
-                        writeln!(logs, "Unknown key")?;
-                    }
+            })
+            .count()
+    };
+    let text = |start: usize, end: usize| line[start..end].iter().collect::<String>();
+
+    // (start, end, kind), dates ahead of the plain numbers inside them
+    let mut numbers: Vec<(usize, usize, &str)> = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        let is_date = formats.contains(&"date")
+            && digits_at(i, false) == 4
+            && line.get(i + 4) == Some(&'-')
+            && digits_at(i + 5, false) == 2
+            && line.get(i + 7) == Some(&'-')
+            && digits_at(i + 8, false) == 2
+            && parse_date(&text(i, i + 10)).is_some();
+        let is_hex = formats.contains(&"hex")
+            && line[i] == '0'
+            && matches!(line.get(i + 1), Some('x' | 'X'))
+            && digits_at(i + 2, true) > 0;
+        if is_date {
+            numbers.push((i, i + 10, "date"));
+            i += 10;
+        } else if is_hex {
+            let end = i + 2 + digits_at(i + 2, true);
+            numbers.push((i, end, "hex"));
+            i = end;
+        } else if line[i].is_ascii_digit() {
+            let end = i + digits_at(i, false);
+            // A minus sign, unless it joins words like `foo-1`
+            let negative =
+                i > 0 && line[i - 1] == '-' && !(i > 1 && regex::is_word_char(line[i - 2]));
+            numbers.push((if negative { i - 1 } else { i }, end, "decimal"));
+            i = end;
+        } else {
+            i += 1;
+        }
+    }
+    let &(start, end, kind) = numbers
+        .iter()
+        .find(|(start, end, _)| *start <= col && col < *end)
+        .or_else(|| numbers.iter().find(|(start, _, _)| *start > col))?;
+
+    let number = text(start, end);
+    let changed = match kind {
+        "date" => {
+            let days = parse_date(&number)? + delta;
+            let (year, month, day) = civil_from_days(days);
+            format!("{:04}-{:02}-{:02}", year, month, day)
+        }
+        "hex" => {
+            let digits = &number[2..];
+            let value = u64::from_str_radix(digits, 16)
+                .ok()?
+                .wrapping_add(delta as u64);
+            let width = digits.len();
+            // Uppercase digits stay uppercase
+            if digits.chars().any(|c| c.is_ascii_uppercase()) {
+                format!("{}{:0width$X}", &number[..2], value)
+            } else {
+                format!("{}{:0width$x}", &number[..2], value)
+            }
+        }
+        _ => {
+            let value = number.parse::<i64>().ok()?.saturating_add(delta);
+            // Leading zeros keep the number as wide as it was
+            let digits = number.trim_start_matches('-');
+            let width = if digits.len() > 1 && digits.starts_with('0') {
+                digits.len()
+            } else {
+                0
+            };
+            let sign = if value < 0 { "-" } else { "" };
+            format!("{}{:0width$}", sign, value.unsigned_abs())
+        }
+    };
+    Some((start, end, changed))
+}
+
+// Days since 1970-01-01 of a `YYYY-MM-DD` date, if it is a real one
+fn parse_date(date: &str) -> Option<i64> {
+    let mut fields = date.split('-').map(|field| field.parse::<i64>().ok());
+    let (year, month, day) = (fields.next()??, fields.next()??, fields.next()??);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if day < 1 || day > month_days {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil, with years starting in March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146097 + day_of_era - 719468)
+}
+
+// The date `days` after 1970-01-01, the inverse of parse_date
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Whether `command` is `:move` (false) or `:copy` (true), and its address,
+// which can follow the name without a space as in `:m$`
+fn move_args(command: &str) -> Option<(bool, &str)> {
+    let address = command.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    match &command[..command.len() - address.len()] {
+        "m" | "mo" | "move" => Some((false, address)),
+        "t" | "co" | "copy" => Some((true, address)),
+        _ => None,
+    }
+}
+
+// Keeps folds on the same lines of text as `changed` is removed at `pos`,
+// or inserted there, before `text` changes. Folds only on removed lines go.
+fn shift_folds(folds: &mut Vec<Fold>, text: &Rope, pos: usize, changed: &str, removed: bool) {
+    let lines = changed.matches('\n').count();
+    if lines == 0 {
+        return;
+    }
+    // Whole lines from the one at `pos` come or go, or else lines after it
+    let line = text.char_to_line(pos);
+    let first = if pos == text.line_to_char(line) && changed.ends_with('\n') {
+        line
+    } else {
+        line + 1
+    };
+    if !removed {
+        for fold in folds.iter_mut() {
+            for end in [&mut fold.start, &mut fold.end] {
+                if *end >= first {
+                    *end += lines;
                 }
-            },
-            event::Event::Resize(_, _) => (),
-            _ => {
-                break;
             }
         }
+        return;
+    }
+    let gone = first..first + lines;
+    folds.retain(|fold| !(gone.contains(&fold.start) && gone.contains(&fold.end)));
+    for fold in folds.iter_mut() {
+        if gone.contains(&fold.start) {
+            fold.start = first;
+        } else if fold.start >= gone.end {
+            fold.start -= lines;
+        }
+        if gone.contains(&fold.end) {
+            fold.end = first - 1;
+        } else if fold.end >= gone.end {
+            fold.end -= lines;
+        }
     }
+}
 
-    Ok(())
+// `:version`: what to tell in a bug report about this build. Syntax
+// highlighting, the language server client and the system clipboard, through
+// external tools, are always built in.
+fn version_info() -> String {
+    format!(
+        "owl {}\nBuilt {}\nFeatures: +syntax +lsp +clipboard\ncrossterm {}\nropey {}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("OWL_BUILD_DATE"),
+        env!("OWL_CROSSTERM_VERSION"),
+        env!("OWL_ROPEY_VERSION"),
+    )
+}
+
+// `text` with control chars written as ^J and the like
+fn caret_notation(text: &str) -> String {
+    let mut shown = String::new();
+    for c in text.chars() {
+        if (c as u32) < 0x20 {
+            shown.push('^');
+            shown.push(char::from(c as u8 + b'@'));
+        } else {
+            shown.push(c);
+        }
+    }
+    shown
+}
+
+// Registers `"x` can pick
+fn is_register_name(name: char) -> bool {
+    name.is_ascii_alphabetic() || matches!(name, '"' | '0' | '_' | '+' | '*')
+}
+
+// `seconds` as roughly how long ago, like "5 minutes ago"
+fn time_ago(seconds: u64) -> String {
+    let (amount, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    format!(
+        "{} {}{} ago",
+        amount,
+        unit,
+        if amount == 1 { "" } else { "s" }
+    )
+}
+
+// Man pages make bold and underlined text by overstriking, `c\x08c` and
+// `_\x08c`, which only a terminal printer wants
+fn strip_overstrike(text: &str) -> String {
+    let mut plain = String::new();
+    for c in text.chars() {
+        if c == '\x08' {
+            plain.pop();
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+// "1 line", "2 lines"
+fn plural(count: usize, noun: &str) -> String {
+    if count == 1 {
+        format!("{} {}", count, noun)
+    } else {
+        format!("{} {}s", count, noun)
+    }
+}
+
+// j/k, g/G and the arrow keys in a list of `len` entries shown a page at a
+// time from `top`: the entry picked after `code`, and the top that keeps it
+// on the page
+fn move_selection(
+    code: event::KeyCode,
+    index: usize,
+    top: usize,
+    len: usize,
+    rows: u16,
+) -> (usize, usize) {
+    let last = len.saturating_sub(1);
+    let index = match code {
+        event::KeyCode::Char('j') | event::KeyCode::Down => index + 1,
+        event::KeyCode::Char('k') | event::KeyCode::Up => index.saturating_sub(1),
+        event::KeyCode::Char('g') => 0,
+        event::KeyCode::Char('G') => last,
+        _ => index,
+    }
+    .min(last);
+    let page = rows.saturating_sub(1).max(1) as usize;
+    (index, top.clamp((index + 1).saturating_sub(page), index))
+}
+
+// The names Tab completes the command line with, on `row` above it, the one
+// picked highlighted. A page of them at a time when they don't all fit.
+fn render_wildmenu(screen: &mut Screen, completion: &Completion, row: u16) {
+    let candidates = &completion.candidates;
+    if candidates.len() < 2 {
+        return;
+    }
+    let (cols, _) = screen.size();
+    let cols = cols as usize;
+    let mut pages = vec![0];
+    let mut width = 0;
+    for (index, name) in candidates.iter().enumerate() {
+        let name_width = name.chars().count() + 2;
+        if width + name_width > cols && width > 0 {
+            pages.push(index);
+            width = 0;
+        }
+        width += name_width;
+    }
+    // At the typed prefix the first page shows, with nothing picked
+    let picked = completion.index.min(candidates.len() - 1);
+    let page = pages.partition_point(|&start| start <= picked) - 1;
+    let end = pages.get(page + 1).copied().unwrap_or(candidates.len());
+    screen.move_to(0, row);
+    screen.print(&" ".repeat(cols));
+    screen.move_to(0, row);
+    let shown = candidates.iter().enumerate().take(end).skip(pages[page]);
+    for (index, name) in shown {
+        if index == completion.index {
+            screen.print_styled(name.as_str().reverse());
+        } else {
+            screen.print(name);
+        }
+        screen.print("  ");
+    }
+}
+
+// The `/pattern/replacement/flags` part of `s/...` or `substitute/...`
+fn substitute_args(command: &str) -> Option<&str> {
+    let args = command
+        .strip_prefix("substitute")
+        .or_else(|| command.strip_prefix('s'))?;
+    let delimiter = args.chars().next()?;
+    if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+        return None;
+    }
+    Some(args)
+}
+
+// `line` with the matches of `regex` in it replaced, the first or all of
+// them, and where in the result each replacement went
+fn substitute_line(
+    line: &[char],
+    regex: &Regex,
+    replacement: &str,
+    global: bool,
+) -> (String, Vec<std::ops::Range<usize>>) {
+    let mut result = String::new();
+    let mut len = 0;
+    let mut replaced = Vec::new();
+    let mut pos = 0;
+    while pos <= line.len() {
+        let Some(caps) = regex.captures_at(line, pos) else {
+            break;
+        };
+        let (start, end) = caps[0].unwrap();
+        result.extend(&line[pos..start]);
+        len += start - pos;
+        let before = result.len();
+        expand_replacement(replacement, line, &caps, &mut result);
+        let added = result[before..].chars().count();
+        replaced.push(len..len + added);
+        len += added;
+        pos = if end == start {
+            // Step over a char after an empty match to make progress
+            result.extend(line.get(start));
+            len += line.get(start).map_or(0, |_| 1);
+            start + 1
+        } else {
+            end
+        };
+        if !global {
+            break;
+        }
+    }
+    result.extend(line.get(pos..).unwrap_or(&[]));
+    (result, replaced)
+}
+
+// Expands `&` and `\1`..`\9` in a :s replacement with the matched text
+fn expand_replacement(replacement: &str, line: &[char], caps: &regex::Captures, out: &mut String) {
+    let group = |index: usize, out: &mut String| {
+        if let Some(Some((start, end))) = caps.get(index) {
+            out.extend(&line[*start..*end]);
+        }
+    };
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => group(0, out),
+            '\\' => match chars.next() {
+                Some(digit @ '0'..='9') => group(digit as usize - '0' as usize, out),
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
 }
 
-fn wrap_screen(logs: Option<File>, filename: Option<PathBuf>) -> std::io::Result<()> {
+fn wrap_screen(
+    logs: Option<File>,
+    filename: Option<PathBuf>,
+    stdin: Option<Rope>,
+    readonly: bool,
+) -> std::io::Result<()> {
     terminal::enable_raw_mode()?;
     execute!(stdout(), terminal::EnterAlternateScreen)?;
 
     std::panic::set_hook(Box::new(|info| {
-        let _ = execute!(stdout(), terminal::LeaveAlternateScreen);
+        let _ = execute!(
+            stdout(),
+            event::DisableMouseCapture,
+            terminal::LeaveAlternateScreen
+        );
         let _ = terminal::disable_raw_mode();
         eprintln!("Application panicked: {}", info);
     }));
 
-    run(logs, filename)?;
+    run(logs, filename, stdin, readonly)?;
 
-    execute!(stdout(), terminal::LeaveAlternateScreen)?;
+    execute!(
+        stdout(),
+        event::DisableMouseCapture,
+        terminal::LeaveAlternateScreen
+    )?;
     terminal::disable_raw_mode()?;
 
     Ok(())
@@ -435,11 +7268,929 @@ fn main() -> std::io::Result<()> {
     let cli = Cli::parse();
 
     // If there "logs.txt" in cwd, write logs to it
-    let logs = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open("logs.txt")
-        .ok();
+    let logs = OpenOptions::new().append(true).open("logs.txt").ok();
+
+    // Piped text is read up front; keys still come from the terminal, which
+    // crossterm opens as /dev/tty when stdin isn't one
+    let from_stdin = cli.stdin || cli.filename.as_deref() == Some(std::path::Path::new("-"));
+    let (filename, stdin) = if from_stdin {
+        (
+            None,
+            Some(Rope::from_reader(BufReader::new(std::io::stdin()))?),
+        )
+    } else {
+        (cli.filename, None)
+    };
+
+    wrap_screen(logs, filename, stdin, cli.readonly)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_for(text: &str) -> Editor {
+        Editor::new(Rope::from_str(text), None, Config::default())
+    }
+
+    // Types `keys`, with Esc, Enter, Tab and Ctrl-A to Ctrl-Z as their
+    // control chars
+    fn keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            let (code, modifiers) = match c {
+                '\x1b' => (event::KeyCode::Esc, event::KeyModifiers::NONE),
+                '\r' => (event::KeyCode::Enter, event::KeyModifiers::NONE),
+                '\t' => (event::KeyCode::Tab, event::KeyModifiers::NONE),
+                '\x01'..='\x1a' => (
+                    event::KeyCode::Char((c as u8 - 1 + b'a') as char),
+                    event::KeyModifiers::CONTROL,
+                ),
+                _ => (event::KeyCode::Char(c), event::KeyModifiers::NONE),
+            };
+            editor
+                .handle_key(event::KeyEvent::new(code, modifiers))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn visual_line_delete_middle() {
+        let mut editor = editor_for("a\nb\nc\nd\ne\n");
+        keys(&mut editor, "jVjjd");
+        assert_eq!(editor.text.to_string(), "a\ne\n");
+        assert_eq!(editor.cursor_line(), 1);
+        assert_eq!(editor.mode, "Normal");
+    }
+
+    #[test]
+    fn visual_line_delete_last() {
+        let mut editor = editor_for("one\ntwo\nthree\nfour\n");
+        keys(&mut editor, "jVjjd");
+        assert_eq!(editor.text.to_string(), "one\n");
+        assert_eq!(editor.cursor_line(), 0);
+        let register = &editor.registers[&'"'];
+        assert_eq!(register.text, "two\nthree\nfour\n");
+        assert!(register.linewise);
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn global_delete() {
+        let mut editor = editor_for("fix\nTODO a\nkeep\n// TODO b\nlast\n");
+        keys(&mut editor, ":g/TODO/d\r");
+        assert_eq!(editor.text.to_string(), "fix\nkeep\nlast\n");
+        assert_eq!(editor.cmd_message.to_string(), "2 lines fewer");
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn global_delete_inverted() {
+        let mut editor = editor_for("fix\nTODO a\nkeep\n// TODO b\n");
+        keys(&mut editor, ":v/TODO/d\r");
+        assert_eq!(editor.text.to_string(), "TODO a\n// TODO b\n");
+        let mut editor = editor_for("fix\nTODO a\nkeep\n");
+        keys(&mut editor, ":g!/TODO/d\r");
+        assert_eq!(editor.text.to_string(), "TODO a\n");
+    }
+
+    #[test]
+    fn global_delete_range_and_regex() {
+        let mut editor = editor_for("x1\nx2\ny3\nx4\n");
+        keys(&mut editor, ":1,3g/^x\\d/d\r");
+        assert_eq!(editor.text.to_string(), "y3\nx4\n");
+    }
+
+    #[test]
+    fn global_delete_errors() {
+        let mut editor = editor_for("TODO\n");
+        keys(&mut editor, ":g/TODO/p\r");
+        assert_eq!(editor.cmd_message.to_string(), "Unsupported :g command p");
+        keys(&mut editor, ":g//d\r");
+        assert_eq!(editor.cmd_message.to_string(), "No pattern for :g");
+        assert_eq!(editor.text.to_string(), "TODO\n");
+        assert!(!editor.dirty);
+    }
+
+    #[test]
+    fn open_line_below() {
+        for (moves, text, line) in [
+            ("", "one\nnew\ntwo\nthree\n", 1),
+            ("j", "one\ntwo\nnew\nthree\n", 2),
+            ("jj", "one\ntwo\nthree\nnew\n", 3),
+        ] {
+            let mut editor = editor_for("one\ntwo\nthree\n");
+            keys(&mut editor, moves);
+            keys(&mut editor, "o");
+            assert_eq!(editor.mode, "Insert");
+            assert_eq!((editor.cursor_line(), editor.cursor_col), (line, 0));
+            keys(&mut editor, "new\x1b");
+            assert_eq!(editor.text.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn open_line_above() {
+        for (moves, text, line) in [
+            ("", "new\none\ntwo\nthree\n", 0),
+            ("j", "one\nnew\ntwo\nthree\n", 1),
+            ("jj", "one\ntwo\nnew\nthree\n", 2),
+        ] {
+            let mut editor = editor_for("one\ntwo\nthree\n");
+            keys(&mut editor, moves);
+            keys(&mut editor, "O");
+            assert_eq!(editor.mode, "Insert");
+            assert_eq!((editor.cursor_line(), editor.cursor_col), (line, 0));
+            keys(&mut editor, "new\x1b");
+            assert_eq!(editor.text.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn open_line_without_final_newline() {
+        let mut editor = editor_for("one\ntwo");
+        keys(&mut editor, "jonew\x1b");
+        assert_eq!(editor.text.to_string(), "one\ntwo\nnew");
+        keys(&mut editor, "ggOtop\x1b");
+        assert_eq!(editor.text.to_string(), "top\none\ntwo\nnew");
+        assert_eq!(editor.cursor_line(), 0);
+    }
+
+    #[test]
+    fn open_line_autoindent() {
+        let mut editor = editor_for("fn main() {\n    body\n}\n");
+        editor.options.autoindent = true;
+        keys(&mut editor, "jo");
+        assert_eq!((editor.cursor_line(), editor.cursor_col), (2, 4));
+        keys(&mut editor, "next\x1bO");
+        assert_eq!((editor.cursor_line(), editor.cursor_col), (2, 4));
+        keys(&mut editor, "above\x1b");
+        assert_eq!(
+            editor.text.to_string(),
+            "fn main() {\n    body\n    above\n    next\n}\n"
+        );
+    }
+
+    #[test]
+    fn reverse_buffer() {
+        let mut editor = editor_for("one\ntwo\nthree\nfour\n");
+        keys(&mut editor, ":reverse\r");
+        assert_eq!(editor.text.to_string(), "four\nthree\ntwo\none\n");
+        assert!(editor.dirty);
+    }
+
+    #[test]
+    fn reverse_range() {
+        let mut editor = editor_for("one\ntwo\nthree\nfour\n");
+        keys(&mut editor, "G:2,3reverse\r");
+        assert_eq!(editor.text.to_string(), "one\nthree\ntwo\nfour\n");
+        // The cursor is kept in the reversed lines
+        assert_eq!(editor.cursor_line(), 2);
+    }
+
+    #[test]
+    fn reverse_without_final_newline() {
+        let mut editor = editor_for("one\ntwo\nthree\nfour");
+        keys(&mut editor, ":reverse\r");
+        assert_eq!(editor.text.to_string(), "four\nthree\ntwo\none");
+    }
+
+    #[test]
+    fn nowrapscan_from_last_line() {
+        // The cursor on the empty line after the final newline
+        let mut editor = editor_for("foo\nbar\n");
+        keys(&mut editor, ":set nowrapscan\rjj");
+        assert_eq!(editor.cursor_line(), 2);
+        keys(&mut editor, "/foo\r");
+        assert_eq!(editor.cursor_line(), 2);
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "search hit BOTTOM without match for: foo"
+        );
+        keys(&mut editor, "?foo\r");
+        assert_eq!((editor.cursor_line(), editor.cursor_col), (0, 0));
+
+        // And on the last line itself
+        let mut editor = editor_for("foo\nbar\nbaz");
+        keys(&mut editor, ":set nowrapscan\rG");
+        keys(&mut editor, "/foo\r");
+        assert_eq!(editor.cursor_line(), 2);
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "search hit BOTTOM without match for: foo"
+        );
+        keys(&mut editor, "?bar\r");
+        assert_eq!(editor.cursor_line(), 1);
+    }
+
+    #[test]
+    fn nowrapscan_from_first_line() {
+        let mut editor = editor_for("foo\nbar\n");
+        keys(&mut editor, ":set nowrapscan\r?bar\r");
+        assert_eq!(editor.cursor_line(), 0);
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "search hit TOP without match for: bar"
+        );
+        keys(&mut editor, "/bar\r");
+        assert_eq!(editor.cursor_line(), 1);
+    }
+
+    #[test]
+    fn wrapscan_from_last_line() {
+        let mut editor = editor_for("foo\nbar\n");
+        keys(&mut editor, "jj/foo\r");
+        assert_eq!(editor.cursor_line(), 0);
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "search hit BOTTOM, continuing at TOP"
+        );
+    }
+
+    fn cursor(editor: &Editor) -> (usize, usize) {
+        (editor.cursor_line(), editor.cursor_col as usize)
+    }
+
+    #[test]
+    fn dw_on_last_word_stops_at_line_end() {
+        let mut editor = editor_for("foo bar\nbaz\n");
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (0, 4));
+        keys(&mut editor, "dw");
+        assert_eq!(editor.text.to_string(), "foo \nbaz\n");
+        assert_eq!(cursor(&editor), (0, 3));
+        assert_eq!(editor.registers[&'"'].text, "bar");
+        // Trailing blanks go too, but not the line break
+        let mut editor = editor_for("foo bar  \nbaz\n");
+        keys(&mut editor, "wdw");
+        assert_eq!(editor.text.to_string(), "foo \nbaz\n");
+        // And the last word of the file
+        let mut editor = editor_for("foo bar");
+        keys(&mut editor, "wdw");
+        assert_eq!(editor.text.to_string(), "foo ");
+    }
+
+    #[test]
+    fn w_motion_goes_to_next_line() {
+        let mut editor = editor_for("foo bar\n  baz qux\n");
+        keys(&mut editor, "ww");
+        assert_eq!(cursor(&editor), (1, 2));
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (1, 6));
+        // No word after the last, it stays on the last char
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (1, 8));
+    }
+
+    #[test]
+    fn word_motions() {
+        let mut editor = editor_for("foo.bar(x)\n\nlast word\n");
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (0, 3));
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (0, 4));
+        keys(&mut editor, "www");
+        assert_eq!(cursor(&editor), (0, 9));
+        // An empty line is a word of its own
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (1, 0));
+        keys(&mut editor, "w");
+        assert_eq!(cursor(&editor), (2, 0));
+        keys(&mut editor, "b");
+        assert_eq!(cursor(&editor), (1, 0));
+        keys(&mut editor, "b");
+        assert_eq!(cursor(&editor), (0, 9));
+        keys(&mut editor, "bb");
+        assert_eq!(cursor(&editor), (0, 7));
+        keys(&mut editor, "e");
+        assert_eq!(cursor(&editor), (0, 8));
+        keys(&mut editor, "e");
+        assert_eq!(cursor(&editor), (0, 9));
+        // `e` skips empty lines
+        keys(&mut editor, "e");
+        assert_eq!(cursor(&editor), (2, 3));
+    }
+
+    #[test]
+    fn dw_within_line() {
+        let mut editor = editor_for("foo bar baz\n");
+        keys(&mut editor, "dw");
+        assert_eq!(editor.text.to_string(), "bar baz\n");
+        assert_eq!(cursor(&editor), (0, 0));
+        // Repeated with `.`, undone with `u`
+        keys(&mut editor, ".");
+        assert_eq!(editor.text.to_string(), "baz\n");
+        keys(&mut editor, "u");
+        assert_eq!(editor.text.to_string(), "bar baz\n");
+    }
+
+    #[test]
+    fn cw_changes_to_word_end() {
+        let mut editor = editor_for("foo bar\nbaz\n");
+        keys(&mut editor, "cw");
+        assert_eq!(editor.mode, "Insert");
+        keys(&mut editor, "xy\x1b");
+        assert_eq!(editor.text.to_string(), "xy bar\nbaz\n");
+        // On the line's last word it stays on the line
+        keys(&mut editor, "wcwqux\x1b");
+        assert_eq!(editor.text.to_string(), "xy qux\nbaz\n");
+    }
+
+    #[test]
+    fn d_with_other_motions() {
+        let mut editor = editor_for("one two three\n");
+        keys(&mut editor, "wd$");
+        assert_eq!(editor.text.to_string(), "one \n");
+        let mut editor = editor_for("one two three\n");
+        keys(&mut editor, "wwd0");
+        assert_eq!(editor.text.to_string(), "three\n");
+        let mut editor = editor_for("one two three\n");
+        keys(&mut editor, "wdiw");
+        assert_eq!(editor.text.to_string(), "one  three\n");
+        let mut editor = editor_for("one two three\n");
+        keys(&mut editor, "wdaw");
+        assert_eq!(editor.text.to_string(), "one three\n");
+        let mut editor = editor_for("one two three\n");
+        keys(&mut editor, "de");
+        assert_eq!(editor.text.to_string(), " two three\n");
+    }
+
+    #[test]
+    fn dd_and_line_motions() {
+        let mut editor = editor_for("a\nb\nc\nd\n");
+        keys(&mut editor, "jdd");
+        assert_eq!(editor.text.to_string(), "a\nc\nd\n");
+        let register = &editor.registers[&'"'];
+        assert_eq!(register.text, "b\n");
+        assert!(register.linewise);
+        keys(&mut editor, "dj");
+        assert_eq!(editor.text.to_string(), "a\n");
+        assert_eq!(cursor(&editor), (0, 0));
+        let mut editor = editor_for("a\nb\nc\nd\n");
+        keys(&mut editor, "jj\"xdk");
+        assert_eq!(editor.text.to_string(), "a\nd\n");
+        assert_eq!(editor.registers[&'x'].text, "b\nc\n");
+    }
+
+    #[test]
+    fn cc_keeps_indent() {
+        let mut editor = editor_for("fn f() {\n    old\n}\n");
+        editor.options.autoindent = true;
+        keys(&mut editor, "jcc");
+        assert_eq!(editor.mode, "Insert");
+        assert_eq!(cursor(&editor), (1, 4));
+        keys(&mut editor, "new\x1b");
+        assert_eq!(editor.text.to_string(), "fn f() {\n    new\n}\n");
+        assert_eq!(editor.registers[&'"'].text, "    old\n");
+    }
+
+    #[test]
+    fn search_then_ctrl_o_returns() {
+        let mut editor = editor_for("start\none\ntwo\nneedle\nthree\n");
+        keys(&mut editor, "jl/needle\r");
+        assert_eq!(cursor(&editor), (3, 0));
+        keys(&mut editor, "\x0f");
+        assert_eq!(cursor(&editor), (1, 1));
+        // And Ctrl-I goes back to the match
+        keys(&mut editor, "\t");
+        assert_eq!(cursor(&editor), (3, 0));
+    }
+
+    #[test]
+    fn jumps_go_on_the_jump_list() {
+        for jump in ["/x\r", "?x\r", "*", "#", "G", ":5\r"] {
+            let mut editor = editor_for("x\na\nx y\nb\nx\n");
+            keys(&mut editor, "jj");
+            keys(&mut editor, jump);
+            assert_ne!(editor.cursor_line(), 2, "{:?}", jump);
+            keys(&mut editor, "\x0f");
+            assert_eq!(editor.cursor_line(), 2, "{:?}", jump);
+        }
+        let mut editor = editor_for("x\na\nx y\nb\nx\n");
+        keys(&mut editor, "jjgg\x0f");
+        assert_eq!(editor.cursor_line(), 2);
+        let mut editor = editor_for("x\na\nx y\nb\nx\n");
+        keys(&mut editor, "/x\rnn");
+        assert_eq!(editor.cursor_line(), 0);
+        keys(&mut editor, "\x0f");
+        assert_eq!(editor.cursor_line(), 4);
+        keys(&mut editor, "\x0f");
+        assert_eq!(editor.cursor_line(), 2);
+        // Where the cursor was is kept once, like vim, so the jump from the
+        // first line is gone
+        keys(&mut editor, "\x0f");
+        assert_eq!(editor.cursor_line(), 2);
+    }
+
+    #[test]
+    fn single_steps_stay_off_the_jump_list() {
+        let mut editor = editor_for("a\nb\nc\nd\n");
+        keys(&mut editor, "jjjk\x0f");
+        assert_eq!(editor.cursor_line(), 2);
+        assert!(editor.jumps.len() <= 1);
+        let mut editor = editor_for("a\nb\nc\nd\n");
+        keys(&mut editor, "Gkk\x0f");
+        assert_eq!(editor.cursor_line(), 0);
+    }
+
+    // A file of `text` in a directory of its own, with the undo history
+    // saved on writing it kept there too
+    fn temp_file(name: &str, text: &str) -> PathBuf {
+        static STATE: std::sync::Once = std::sync::Once::new();
+        let dir = std::env::temp_dir().join(format!("owl-test-{}", std::process::id()));
+        STATE.call_once(|| std::env::set_var("XDG_STATE_HOME", dir.join("state")));
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, text).unwrap();
+        path
+    }
 
-    wrap_screen(logs, cli.filename)
+    fn open(path: &Path) -> Editor {
+        let mut editor = editor_for("");
+        editor.open_file(path.to_path_buf()).unwrap();
+        editor
+    }
+
+    #[test]
+    fn noeol_round_trip() {
+        let path = temp_file("noeol.txt", "one\ntwo");
+        let mut editor = open(&path);
+        assert!(editor.noeol());
+        assert!(editor.file_info().contains("[noeol]"));
+        keys(&mut editor, "ix\x1b:w\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xone\ntwo");
+        // Loaded again it is still without one
+        let mut editor = open(&path);
+        assert!(editor.noeol());
+        keys(&mut editor, ":w\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xone\ntwo");
+    }
+
+    #[test]
+    fn noeol_with_fixeol() {
+        let path = temp_file("fixeol.txt", "one\ntwo");
+        let mut editor = open(&path);
+        keys(&mut editor, ":set fixeol\r:w\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+        assert!(!editor.noeol());
+        let path = temp_file("fixeol-dos.txt", "one\r\ntwo");
+        let mut editor = open(&path);
+        keys(&mut editor, ":set fixeol\r:w\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn eol_round_trip() {
+        let path = temp_file("eol.txt", "one\ntwo\n");
+        let mut editor = open(&path);
+        assert!(!editor.noeol());
+        assert!(!editor.file_info().contains("[noeol]"));
+        keys(&mut editor, ":w\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    }
+
+    fn sorted(text: &str, command: &str) -> (String, String) {
+        let mut editor = editor_for(text);
+        keys(&mut editor, command);
+        (editor.text.to_string(), editor.cmd_message.to_string())
+    }
+
+    #[test]
+    fn sort_unique() {
+        assert_eq!(
+            sorted("b\na\nb\nc\na\n", ":sort u\r"),
+            (
+                "a\nb\nc\n".to_string(),
+                "2 duplicate lines removed".to_string()
+            )
+        );
+        assert_eq!(
+            sorted("b\na\nc\n", ":sort u\r"),
+            ("a\nb\nc\n".to_string(), "No duplicate lines".to_string())
+        );
+        assert_eq!(sorted("b\na\nb\n", ":sort\r").0, "a\nb\nb\n");
+    }
+
+    #[test]
+    fn sort_reverse_unique() {
+        assert_eq!(
+            sorted("b\na\nb\nc\n", ":sort! u\r"),
+            (
+                "c\nb\na\n".to_string(),
+                "1 duplicate line removed".to_string()
+            )
+        );
+        assert_eq!(sorted("b\na\nc\n", ":sort!\r").0, "c\nb\na\n");
+    }
+
+    #[test]
+    fn sort_numeric_unique() {
+        assert_eq!(
+            sorted("x10\nx9\nx10\nx100\n", ":sort nu\r"),
+            (
+                "x9\nx10\nx100\n".to_string(),
+                "1 duplicate line removed".to_string()
+            )
+        );
+        // The same number on different lines is no duplicate
+        assert_eq!(sorted("2 b\n1\n2 a\n", ":sort nu\r").0, "1\n2 b\n2 a\n");
+        // Lines without a number come first, in their order, and are only
+        // duplicates when the same
+        assert_eq!(
+            sorted("b\n3\na\nb\n1\nb\n", ":sort nu\r"),
+            (
+                "b\na\nb\n1\n3\n".to_string(),
+                "1 duplicate line removed".to_string()
+            )
+        );
+        assert_eq!(sorted("x10\nx9\nx100\n", ":sort n\r").0, "x9\nx10\nx100\n");
+        assert_eq!(sorted("x10\nx9\nx100\n", ":sort! n\r").0, "x100\nx10\nx9\n");
+    }
+
+    #[test]
+    fn sort_ignore_case_unique() {
+        assert_eq!(
+            sorted("b\nA\na\nB\n", ":sort iu\r"),
+            (
+                "A\nb\n".to_string(),
+                "2 duplicate lines removed".to_string()
+            )
+        );
+        assert_eq!(sorted("b\nA\na\nB\n", ":sort i\r").0, "A\na\nb\nB\n");
+    }
+
+    #[test]
+    fn sort_bad_flag() {
+        let (text, message) = sorted("b\na\n", ":sort x\r");
+        assert_eq!(text, "b\na\n");
+        assert_eq!(message, "Invalid flag for :sort: x");
+    }
+
+    #[test]
+    fn toggle_case_inner_word() {
+        let mut editor = editor_for("say HeLLo there\n");
+        keys(&mut editor, "wlg~iw");
+        assert_eq!(editor.text.to_string(), "say hEllO there\n");
+        assert_eq!(cursor(&editor), (0, 4));
+        keys(&mut editor, "w.");
+        assert_eq!(editor.text.to_string(), "say hEllO THERE\n");
+        keys(&mut editor, "g~~");
+        assert_eq!(editor.text.to_string(), "SAY HeLLo there\n");
+    }
+
+    #[test]
+    fn delete_inside_multiline_call() {
+        let mut editor = editor_for("f(\n    a,\n    b,\n    c\n)\n");
+        keys(&mut editor, "jjdi(");
+        assert_eq!(editor.text.to_string(), "f(\n)\n");
+        assert_eq!(cursor(&editor), (1, 0));
+        assert_eq!(
+            editor.registers[&'"'].text.to_string(),
+            "    a,\n    b,\n    c\n"
+        );
+        assert!(editor.registers[&'"'].linewise);
+    }
+
+    #[test]
+    fn change_inside_multiline_block() {
+        let mut editor = editor_for("if x {\n    a();\n    b();\n}\n");
+        editor.options.autoindent = true;
+        keys(&mut editor, "jci{c();\x1b");
+        assert_eq!(editor.text.to_string(), "if x {\n    c();\n}\n");
+    }
+
+    #[test]
+    fn bracket_objects_on_one_line() {
+        let mut editor = editor_for("f(a, g(b), c)\n");
+        keys(&mut editor, "/b\rdib");
+        assert_eq!(editor.text.to_string(), "f(a, g(), c)\n");
+        keys(&mut editor, "0lda(");
+        assert_eq!(editor.text.to_string(), "f\n");
+
+        let mut editor = editor_for("x = {a: 1};\n");
+        keys(&mut editor, "/a\rda{");
+        assert_eq!(editor.text.to_string(), "x = ;\n");
+
+        let mut editor = editor_for("g(old)\n");
+        keys(&mut editor, "/d\rci(new\x1b");
+        assert_eq!(editor.text.to_string(), "g(new)\n");
+        keys(&mut editor, "dab");
+        assert_eq!(editor.text.to_string(), "g\n");
+    }
+
+    #[test]
+    fn ranged_write_replaces_the_file() {
+        use std::os::unix::fs::MetadataExt;
+        let path = temp_file("part.txt", "old\n");
+        let inode = std::fs::metadata(&path).unwrap().ino();
+        let mut editor = editor_for("a\nb\nc\n");
+        keys(&mut editor, &format!(":2,3w! {}\r", path.display()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "b\nc\n");
+        // Through a new file renamed over it, with none left behind
+        let renamed = std::fs::metadata(&path).unwrap().ino();
+        assert_ne!(renamed, inode);
+        assert!(!std::fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .any(|entry| entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with(".part.txt")));
+        // Appending still writes into the file
+        keys(&mut editor, &format!(":1w >> {}\r", path.display()));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "b\nc\na\n");
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), renamed);
+    }
+
+    #[test]
+    fn repeat_command_with_count() {
+        let mut editor = editor_for("xxxxxx\n");
+        keys(&mut editor, ":s/x/y/\r3@:");
+        assert_eq!(editor.text.to_string(), "yyyyxx\n");
+        // The count is only for the command it was typed before
+        keys(&mut editor, "@:");
+        assert_eq!(editor.text.to_string(), "yyyyyx\n");
+        keys(&mut editor, "2@@");
+        assert_eq!(editor.text.to_string(), "yyyyyy\n");
+        assert!(editor.command_failed);
+        assert!(editor
+            .cmd_message
+            .to_string()
+            .starts_with("Pattern not found"));
+
+        let mut editor = editor_for(&"x\n".repeat(12));
+        keys(&mut editor, ":d\r10@:");
+        assert_eq!(editor.text.to_string(), "x\n");
+    }
+
+    #[test]
+    fn edit_directory_lists_it() {
+        let file = temp_file("edit-dir/b.txt", "bee\n");
+        temp_file("edit-dir/a.txt", "ay\n");
+        std::fs::create_dir_all(file.with_file_name("sub")).unwrap();
+        let dir = file.parent().unwrap();
+        let mut editor = editor_for("");
+        keys(&mut editor, &format!(":e {}/\r", dir.display()));
+        assert_eq!(editor.mode, "Explore");
+        assert_eq!(editor.browser.dir, std::fs::canonicalize(dir).unwrap());
+        assert_eq!(editor.browser.entries, ["../", "sub/", "a.txt", "b.txt"]);
+        // Enter opens the file picked
+        keys(&mut editor, "jjj\r");
+        assert_eq!(editor.mode, "Normal");
+        assert_eq!(editor.text.to_string(), "bee\n");
+    }
+
+    #[test]
+    fn edit_file() {
+        let path = temp_file("edit.txt", "one\n");
+        let mut editor = editor_for("");
+        keys(&mut editor, &format!(":e {}\r", path.display()));
+        assert_eq!(editor.text.to_string(), "one\n");
+        assert_eq!(editor.filename.as_deref(), Some(path.as_path()));
+        // Not over unsaved changes
+        let other = temp_file("edit-other.txt", "two\n");
+        keys(&mut editor, &format!("ix\x1b:e {}\r", other.display()));
+        assert_eq!(editor.text.to_string(), "xone\n");
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "No write since last change, write it first"
+        );
+        keys(&mut editor, ":e\r");
+        assert_eq!(editor.cmd_message.to_string(), "Give a file name to :edit");
+    }
+
+    #[test]
+    fn autowrite_before_shell_command() {
+        let path = temp_file("autowrite-shell.txt", "one\n");
+        let mut editor = open(&path);
+        keys(&mut editor, ":set aw\rix\x1b");
+        keys(&mut editor, &format!(":!cat {}\r", path.display()));
+        // Written before the command that reads it
+        assert_eq!(editor.cmd_message.to_string(), "xone");
+        assert!(!editor.dirty);
+        // Not without the option
+        keys(&mut editor, ":set noaw\riy\x1b");
+        keys(&mut editor, &format!(":!cat {}\r", path.display()));
+        assert_eq!(editor.cmd_message.to_string(), "xone");
+        assert!(editor.dirty);
+        // Nor a filter, which gets the lines themselves
+        keys(&mut editor, ":set aw\r:.!tr a-z A-Z\r");
+        assert_eq!(editor.text.to_string(), "XYONE\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "xone\n");
+    }
+
+    #[test]
+    fn autowrite_before_leaving_for_tab_or_browser() {
+        for command in [":tabnew\r", ":Ex\r"] {
+            let path = temp_file("autowrite-leave.txt", "one\n");
+            let mut editor = open(&path);
+            keys(&mut editor, ":set aw\rix\x1b");
+            keys(&mut editor, command);
+            assert_eq!(
+                std::fs::read_to_string(&path).unwrap(),
+                "xone\n",
+                "{:?}",
+                command
+            );
+        }
+        let path = temp_file("autowrite-off.txt", "one\n");
+        let mut editor = open(&path);
+        keys(&mut editor, "ix\x1b:tabnew\r");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn autowrite_needs_a_file_name() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, ":set aw\rix\x1b:!true\r");
+        assert!(editor.dirty);
+        assert_eq!(editor.filename, None);
+    }
+
+    #[test]
+    fn split_windows_have_their_own_cursors() {
+        let mut editor = editor_for("one\ntwo\nthree\n");
+        keys(&mut editor, "j:split\r");
+        // The new window is above, and gone to
+        let rects = editor.window_rects((80, 24));
+        assert_eq!(rects.len(), 2);
+        assert_eq!(rects[0].0, editor.window);
+        assert!(rects[0].1.row < rects[1].1.row);
+        assert_eq!(editor.cursor_line(), 1);
+        keys(&mut editor, "j");
+        keys(&mut editor, "\x17w");
+        assert_eq!(editor.cursor_line(), 1);
+        // Round to the top one again
+        keys(&mut editor, "\x17w");
+        assert_eq!(editor.cursor_line(), 2);
+        keys(&mut editor, "\x17W");
+        assert_eq!(editor.cursor_line(), 1);
+        // Editing shows in both, and moves the cursor of the other one when
+        // its line is gone
+        keys(&mut editor, "jdd\x17w");
+        assert_eq!(editor.text.to_string(), "one\ntwo\n");
+        assert_eq!(editor.cursor_line(), 1);
+        assert_eq!(editor.line_len(editor.cursor_line()), 3);
+    }
+
+    #[test]
+    fn ctrl_w_splits_and_moves_by_direction() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, "\x17v");
+        let left = editor.window;
+        keys(&mut editor, "\x17l");
+        let right = editor.window;
+        assert_ne!(left, right);
+        // Nothing further right
+        keys(&mut editor, "\x17l");
+        assert_eq!(editor.window, right);
+        keys(&mut editor, "\x17h\x17s");
+        let top = editor.window;
+        assert_eq!(editor.window_rects((80, 24)).len(), 3);
+        keys(&mut editor, "\x17j");
+        assert_eq!(editor.window, left);
+        keys(&mut editor, "\x17\x0b");
+        assert_eq!(editor.window, top);
+        keys(&mut editor, "\x17l");
+        assert_eq!(editor.window, right);
+        // In order from the top left, going round, or straight to one with
+        // a count
+        keys(&mut editor, "\x17w");
+        assert_eq!(editor.window, top);
+        keys(&mut editor, "2\x17w");
+        assert_eq!(editor.window, left);
+        keys(&mut editor, "\x17p");
+        assert_eq!(editor.window, top);
+    }
+
+    #[test]
+    fn split_takes_no_args() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, ":vsplit other.txt\r");
+        assert_eq!(editor.cmd_message.to_string(), "Too many args for :vsplit");
+        assert!(editor.windows.is_empty());
+    }
+
+    #[test]
+    fn ctrl_w_resizes_windows() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, "\x17s");
+        let rows = |editor: &Editor| editor.window_rect((80, 24)).rows;
+        let before = rows(&editor);
+        keys(&mut editor, "3\x17+");
+        assert_eq!(rows(&editor), before + 3);
+        keys(&mut editor, "\x17-");
+        assert_eq!(rows(&editor), before + 2);
+        // Only side by side windows get wider
+        keys(&mut editor, "\x17>");
+        assert_eq!(editor.window_rect((80, 24)).cols, 80);
+        keys(&mut editor, "\x17v");
+        let cols = editor.window_rect((80, 24)).cols;
+        keys(&mut editor, "5\x17<");
+        assert_eq!(editor.window_rect((80, 24)).cols, cols - 5);
+        // The other windows are what is left
+        let rects = editor.window_rects((80, 24));
+        assert_eq!(rects[1].1.cols, 80 - (cols - 5) - 1);
+    }
+
+    #[test]
+    fn ctrl_w_closes_windows() {
+        let mut editor = editor_for("one\ntwo\n");
+        keys(&mut editor, "\x17sj\x17s\x17v");
+        assert_eq!(editor.window_rects((80, 24)).len(), 4);
+        // The space goes to the one beside it, which is gone to
+        keys(&mut editor, "\x17c");
+        assert_eq!(editor.window_rects((80, 24)).len(), 3);
+        assert_eq!(editor.window_rect((80, 24)).cols, 80);
+        keys(&mut editor, "\x17q");
+        assert_eq!(editor.window_rects((80, 24)).len(), 2);
+        assert_eq!(editor.cursor_line(), 1);
+        keys(&mut editor, "\x17s\x17k\x17o");
+        assert!(editor.windows.is_empty());
+        assert_eq!(
+            editor.window_rects((80, 24)),
+            [(0, editor.text_area((80, 24)))]
+        );
+        assert_eq!(editor.cursor_line(), 1);
+    }
+
+    #[test]
+    fn ctrl_w_closing_the_last_window_quits() {
+        let ctrl_w = event::KeyEvent::new(event::KeyCode::Char('w'), event::KeyModifiers::CONTROL);
+        for key in ['q', 'c'] {
+            let key = event::KeyEvent::new(event::KeyCode::Char(key), event::KeyModifiers::NONE);
+            let mut editor = editor_for("one\n");
+            keys(&mut editor, "\x17s");
+            assert!(!editor.handle_key(ctrl_w).unwrap());
+            assert!(!editor.handle_key(key).unwrap());
+            assert!(editor.windows.is_empty());
+            assert!(!editor.handle_key(ctrl_w).unwrap());
+            assert!(editor.handle_key(key).unwrap());
+            // Not with changes, as with :q
+            let mut editor = editor_for("one\n");
+            keys(&mut editor, "ix\x1b");
+            assert!(!editor.handle_key(ctrl_w).unwrap());
+            assert!(!editor.handle_key(key).unwrap());
+            assert!(editor
+                .cmd_message
+                .to_string()
+                .starts_with("Unsaved changes!"));
+        }
+    }
+
+    #[test]
+    fn laststatus_one_shows_with_windows() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, ":set ls=1\r");
+        assert_eq!(editor.status_rows(), 0);
+        assert_eq!(editor.text_rows((80, 24)), 23);
+        keys(&mut editor, ":split\r");
+        assert_eq!(editor.status_rows(), 1);
+        // Both windows have one, the bottom one too
+        let rects = editor.window_rects((80, 24));
+        assert!(rects
+            .iter()
+            .all(|(_, rect)| editor.has_status_line(*rect, (80, 24))));
+        keys(&mut editor, ":only\r");
+        assert_eq!(editor.status_rows(), 0);
+        keys(&mut editor, ":set ls=0\r:vsplit\r");
+        assert_eq!(editor.status_rows(), 0);
+    }
+
+    #[test]
+    fn undojoin_undoes_two_changes_at_once() {
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, "ia\x1b:undojoin\rib\x1b");
+        assert_eq!(editor.text.to_string(), "abone\n");
+        keys(&mut editor, "u");
+        assert_eq!(editor.text.to_string(), "one\n");
+        // Not right after an undo, nor does it do anything before a change
+        keys(&mut editor, "ia\x1bu:undojoin\r");
+        assert_eq!(
+            editor.cmd_message.to_string(),
+            "undojoin is not allowed after undo"
+        );
+        let mut editor = editor_for("one\n");
+        keys(&mut editor, ":undojoin\ria\x1bib\x1bu");
+        assert_eq!(editor.text.to_string(), "aone\n");
+    }
+
+    #[test]
+    fn messages_are_kept_once() {
+        let mut editor = editor_for("one\n");
+        assert_eq!(editor.message_listing(), "No messages yet");
+        keys(&mut editor, ":nosuch\r");
+        let error = editor.cmd_message.to_string();
+        // Logged once however long it shows, and not the command typed
+        editor.log_message();
+        editor.log_message();
+        keys(&mut editor, ":mes");
+        editor.log_message();
+        keys(&mut editor, "sages\r");
+        editor.log_message();
+        let listing = editor.message_listing();
+        assert_eq!(listing.lines().count(), 1);
+        assert!(listing.ends_with(&format!("  {}", error)), "{:?}", listing);
+        keys(&mut editor, ":messages clear\r");
+        assert_eq!(editor.message_listing(), "No messages yet");
+    }
 }
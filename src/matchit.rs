@@ -0,0 +1,168 @@
+// Keyword pairs for `%`: `if` and `fi`, `do` and `done`, HTML tags and the
+// like, found per line so a search can be made for the partner of any one.
+//
+// Strings and comments are not told apart from code, a keyword in one
+// counts like any other.
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Role {
+    Open,
+    // `else` and the like, which `%` stops at on its way to the end
+    Middle,
+    Close,
+}
+
+pub struct Word {
+    pub col: usize,
+    pub len: usize,
+    // Words only pair with words of the same group
+    pub group: String,
+    pub role: Role,
+}
+
+// A group of keywords: those opening it, those in the middle and those
+// closing it
+type Group = (
+    &'static [&'static str],
+    &'static [&'static str],
+    &'static [&'static str],
+);
+
+fn groups(filetype: &str) -> &'static [Group] {
+    match filetype {
+        "sh" => &[
+            (&["if"], &["elif", "else"], &["fi"]),
+            (&["do"], &[], &["done"]),
+            (&["case"], &[], &["esac"]),
+        ],
+        "lua" => &[
+            (&["function", "if", "do"], &["elseif", "else"], &["end"]),
+            (&["repeat"], &[], &["until"]),
+        ],
+        "ruby" => &[(
+            &[
+                "def", "class", "module", "if", "unless", "while", "until", "case", "begin", "do",
+            ],
+            &["elsif", "else", "when", "rescue", "ensure"],
+            &["end"],
+        )],
+        "make" => &[
+            (&["ifeq", "ifneq", "ifdef", "ifndef"], &["else"], &["endif"]),
+            (&["define"], &[], &["endef"]),
+        ],
+        "c" | "cpp" => &[(
+            &["#if", "#ifdef", "#ifndef"],
+            &["#elif", "#else"],
+            &["#endif"],
+        )],
+        _ => &[],
+    }
+}
+
+// Ruby keywords that only open a block at the start of a line, elsewhere
+// they are modifiers like `return if done`
+const RUBY_MODIFIERS: [&str; 4] = ["if", "unless", "while", "until"];
+
+// Elements that have no closing tag
+const VOID_ELEMENTS: [&str; 13] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track",
+    "wbr",
+];
+
+// The keywords and tags in `line` that `%` pairs up, in order
+pub fn words(filetype: &str, line: &[char]) -> Vec<Word> {
+    if filetype == "html" {
+        return tags(line);
+    }
+    let groups = groups(filetype);
+    if groups.is_empty() {
+        return Vec::new();
+    }
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let first_non_blank = line.iter().take_while(|c| c.is_whitespace()).count();
+    let mut words = Vec::new();
+    let mut col = 0;
+    while col < line.len() {
+        // `#` only starts a word as the start of a C preprocessor directive
+        let hash = line[col] == '#' && line.get(col + 1).is_some_and(|c| is_word_char(*c));
+        if !hash && !is_word_char(line[col]) {
+            col += 1;
+            continue;
+        }
+        let start = col;
+        col += 1;
+        while col < line.len() && is_word_char(line[col]) {
+            col += 1;
+        }
+        // Part of a longer name like `$if_x` or `a.end` is no keyword
+        if start > 0 && matches!(line[start - 1], '$' | '.' | ':' | '-') {
+            continue;
+        }
+        let word: String = line[start..col].iter().collect();
+        if filetype == "ruby" && start != first_non_blank && RUBY_MODIFIERS.contains(&&*word) {
+            continue;
+        }
+        for (open, middle, close) in groups {
+            let role = if open.contains(&&*word) {
+                Role::Open
+            } else if middle.contains(&&*word) {
+                Role::Middle
+            } else if close.contains(&&*word) {
+                Role::Close
+            } else {
+                continue;
+            };
+            words.push(Word {
+                col: start,
+                len: col - start,
+                group: open[0].to_string(),
+                role,
+            });
+            break;
+        }
+    }
+    words
+}
+
+// Opening and closing HTML tags, each of them from its `<` to its `>` or
+// the end of the line
+fn tags(line: &[char]) -> Vec<Word> {
+    let mut words = Vec::new();
+    let mut col = 0;
+    while col < line.len() {
+        if line[col] != '<' {
+            col += 1;
+            continue;
+        }
+        let start = col;
+        let closing = line.get(col + 1) == Some(&'/');
+        let name_start = col + 1 + closing as usize;
+        let name_len = line[name_start.min(line.len())..]
+            .iter()
+            .take_while(|c| c.is_alphanumeric() || **c == '-')
+            .count();
+        let end = line[start..]
+            .iter()
+            .position(|c| *c == '>')
+            .map_or(line.len(), |offset| start + offset + 1);
+        col = end;
+        if name_len == 0 {
+            continue;
+        }
+        let name: String = line[name_start..name_start + name_len]
+            .iter()
+            .collect::<String>()
+            .to_lowercase();
+        let self_closing = line[..end].ends_with(&['/', '>']);
+        if !closing && (self_closing || VOID_ELEMENTS.contains(&name.as_str())) {
+            continue;
+        }
+        words.push(Word {
+            col: start,
+            len: end - start,
+            group: name,
+            role: if closing { Role::Close } else { Role::Open },
+        });
+    }
+    words
+}
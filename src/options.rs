@@ -0,0 +1,661 @@
+// Editor settings changed with `:set` and the owlrc config file.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::screen::CursorStyle;
+use crate::statusline;
+
+pub struct Options {
+    pub expandtab: bool,
+    pub tabstop: usize,
+    pub shiftwidth: usize,
+    pub softtabstop: usize,
+    pub autoindent: bool,
+    pub number: bool,
+    pub mouse: bool,
+    pub spell: bool,
+    pub smarthome: bool,
+    pub wrap: bool,
+    // Shown at the start of every continued row of a wrapped line
+    pub showbreak: String,
+    pub backup: bool,
+    pub backupext: String,
+    // Directory for backups, next to the file when empty
+    pub backupdir: String,
+    // Whether `:w` to another file that exists asks before overwriting it,
+    // rather than refusing without `!`
+    pub confirm: bool,
+    // Whether saving ends a last line that has no line ending with one.
+    // Off, the text is written as it is.
+    pub fixendofline: bool,
+    // Screen columns to shade, counted from 1
+    pub colorcolumn: Vec<usize>,
+    // Milliseconds to wait for the next key of a sequence like `gg`
+    pub timeoutlen: usize,
+    // Highlighting worked out from the text: matching brackets and, with
+    // `spell`, misspelled words
+    pub syntax: bool,
+    // Files of at least this many MiB open with `syntax` off and without
+    // detecting their indentation, 0 for no limit
+    pub largefile: usize,
+    // Searches go on from the other end of the file
+    pub wrapscan: bool,
+    // Marks every shiftwidth columns of indentation
+    pub indentguides: bool,
+    // Lines typed past this width are broken at a blank, 0 for never
+    pub textwidth: usize,
+    // Command that starts a language server for the file, none when empty
+    pub lsp: String,
+    // What Ctrl-A and Ctrl-X take for numbers besides decimal ones, a comma
+    // list of `hex` and `date`
+    pub nrformats: String,
+    // A column on the right showing where the view is in the file
+    pub scrollbar: bool,
+    // Blanks at the ends of lines shown in red
+    pub hlwhitespace: bool,
+    // Milliseconds yanked text stays highlighted for, 0 for not at all
+    pub yankflash: usize,
+    // Milliseconds of doing nothing after which changes to a named file are
+    // written, 0 for never. Not while typing in Insert or Command mode.
+    pub autosave: usize,
+    // Whether changes to a named file are written before going to another
+    // file, rather than refusing to
+    pub autowrite: bool,
+    // Lines the view scrolls by when the cursor goes past its top or bottom
+    pub scrolljump: usize,
+    // The cursor in each mode, a comma list of `modes:shape` where modes are
+    // some of `n`ormal, `v`isual, `i`nsert and `c`ommand joined with `-`, and
+    // the shape is `block`, `bar` or `underline`, blinking with `-blink`
+    pub guicursor: String,
+    // The keys typed so far of a command shown in the status line
+    pub showcmd: bool,
+    // `all` to let the cursor go past the end of lines in Normal and the
+    // visual modes. It can always be on the place just after the last char,
+    // so `onemore` is the same as the default.
+    pub virtualedit: String,
+    // The status line, with `%` items for what it shows
+    pub statusline: String,
+    // `unnamed` to yank to and paste from X's primary selection, the `*`
+    // register, when no register is picked, `unnamedplus` for the clipboard,
+    // the `+` register
+    pub clipboard: String,
+    // `nosplit` to show what a `:s` being typed would do to the text as it
+    // is typed, nothing changing until it is run
+    pub inccommand: String,
+    // When the status line shows: 0 for never, 1 with more than one window
+    // and 2 for always
+    pub laststatus: usize,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            expandtab: true,
+            tabstop: 4,
+            shiftwidth: 4,
+            softtabstop: 0,
+            autoindent: false,
+            number: false,
+            mouse: true,
+            spell: false,
+            smarthome: false,
+            wrap: false,
+            showbreak: String::new(),
+            backup: false,
+            backupext: "~".to_string(),
+            backupdir: String::new(),
+            confirm: false,
+            fixendofline: false,
+            colorcolumn: Vec::new(),
+            timeoutlen: 1000,
+            syntax: true,
+            largefile: 16,
+            wrapscan: true,
+            indentguides: false,
+            textwidth: 0,
+            lsp: String::new(),
+            nrformats: "hex,date".to_string(),
+            scrollbar: false,
+            hlwhitespace: false,
+            yankflash: 150,
+            autosave: 0,
+            autowrite: false,
+            scrolljump: 1,
+            guicursor: "n-v:block,i-c:bar".to_string(),
+            showcmd: true,
+            virtualedit: String::new(),
+            statusline: statusline::DEFAULT.to_string(),
+            laststatus: 2,
+            inccommand: String::new(),
+            clipboard: String::new(),
+        }
+    }
+}
+
+impl Options {
+    // Applies a single `:set` argument: `name`, `noname` or `name=value`
+    pub fn set(&mut self, arg: &str) -> Result<(), String> {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg, None),
+        };
+        match (name, value) {
+            ("expandtab" | "et", None) => self.expandtab = true,
+            ("noexpandtab" | "noet", None) => self.expandtab = false,
+            ("autoindent" | "ai", None) => self.autoindent = true,
+            ("noautoindent" | "noai", None) => self.autoindent = false,
+            ("number" | "nu", None) => self.number = true,
+            ("nonumber" | "nonu", None) => self.number = false,
+            ("mouse", None) => self.mouse = true,
+            ("nomouse", None) => self.mouse = false,
+            ("spell", None) => self.spell = true,
+            ("nospell", None) => self.spell = false,
+            ("smarthome", None) => self.smarthome = true,
+            ("nosmarthome", None) => self.smarthome = false,
+            ("wrapscan" | "ws", None) => self.wrapscan = true,
+            ("nowrapscan" | "nows", None) => self.wrapscan = false,
+            ("indentguides" | "ig", None) => self.indentguides = true,
+            ("noindentguides" | "noig", None) => self.indentguides = false,
+            ("scrollbar", None) => self.scrollbar = true,
+            ("noscrollbar", None) => self.scrollbar = false,
+            ("hlwhitespace" | "hlws", None) => self.hlwhitespace = true,
+            ("nohlwhitespace" | "nohlws", None) => self.hlwhitespace = false,
+            ("showcmd" | "sc", None) => self.showcmd = true,
+            ("noshowcmd" | "nosc", None) => self.showcmd = false,
+            ("wrap", None) => self.wrap = true,
+            ("nowrap", None) => self.wrap = false,
+            ("showbreak" | "sbr", Some(value)) => self.showbreak = value.to_string(),
+            ("backup" | "bk", None) => self.backup = true,
+            ("nobackup" | "nobk", None) => self.backup = false,
+            ("backupext" | "bex", Some(value)) => self.backupext = value.to_string(),
+            ("backupdir" | "bdir", Some(value)) => self.backupdir = value.to_string(),
+            ("confirm" | "cf", None) => self.confirm = true,
+            ("noconfirm" | "nocf", None) => self.confirm = false,
+            ("fixendofline" | "fixeol", None) => self.fixendofline = true,
+            ("nofixendofline" | "nofixeol", None) => self.fixendofline = false,
+            ("lsp", Some(value)) => self.lsp = value.to_string(),
+            ("virtualedit" | "ve", Some(value)) => {
+                if !["", "onemore", "all"].contains(&value) {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+                self.virtualedit = value.to_string();
+            }
+            ("clipboard" | "cb", Some(value)) => {
+                if let Some(part) = value
+                    .split(',')
+                    .find(|part| !["", "unnamed", "unnamedplus"].contains(part))
+                {
+                    return Err(format!("Invalid value for {}: {}", name, part));
+                }
+                self.clipboard = value.to_string();
+            }
+            ("inccommand" | "icm", Some(value)) => {
+                if !["", "nosplit"].contains(&value) {
+                    return Err(format!("Invalid value for {}: {}", name, value));
+                }
+                self.inccommand = value.to_string();
+            }
+            ("statusline" | "stl", Some(value)) => {
+                statusline::check(value)?;
+                self.statusline = value.to_string();
+            }
+            ("laststatus" | "ls", Some(value)) => {
+                self.laststatus = match value.parse() {
+                    Ok(laststatus) if laststatus <= 2 => laststatus,
+                    _ => return Err(format!("Invalid value for {}: {}", name, value)),
+                }
+            }
+            ("nrformats" | "nf", Some(value)) => {
+                if let Some(format) = value
+                    .split(',')
+                    .find(|format| !["", "hex", "date"].contains(format))
+                {
+                    return Err(format!("Invalid value for {}: {}", name, format));
+                }
+                self.nrformats = value.to_string();
+            }
+            ("colorcolumn" | "cc", Some(value)) => {
+                self.colorcolumn = value
+                    .split(',')
+                    .filter(|column| !column.is_empty())
+                    .map(|column| parse_width(name, column))
+                    .collect::<Result<_, _>>()?
+            }
+            ("tabstop" | "ts", Some(value)) => self.tabstop = parse_width(name, value)?,
+            ("shiftwidth" | "sw", Some(value)) => self.shiftwidth = parse_width(name, value)?,
+            ("softtabstop" | "sts", Some(value)) => {
+                self.softtabstop = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("syntax" | "syn", Some("on")) => self.syntax = true,
+            ("syntax" | "syn", Some("off")) => self.syntax = false,
+            ("largefile", Some(value)) => {
+                self.largefile = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("textwidth" | "tw", Some(value)) => {
+                self.textwidth = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("timeoutlen" | "tm", Some(value)) => {
+                self.timeoutlen = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("yankflash", Some(value)) => {
+                self.yankflash = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("autosave", Some(value)) => {
+                self.autosave = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("autowrite" | "aw", None) => self.autowrite = true,
+            ("noautowrite" | "noaw", None) => self.autowrite = false,
+            ("scrolljump" | "sj", Some(value)) => {
+                self.scrolljump = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value for {}: {}", name, value))?
+            }
+            ("guicursor" | "gcr", Some(value)) => {
+                if let Some(part) = value.split(',').find(|part| parse_cursor(part).is_none()) {
+                    return Err(format!("Invalid value for {}: {}", name, part));
+                }
+                self.guicursor = value.to_string();
+            }
+            _ => return Err(format!("Unknown option: {}", arg)),
+        }
+        Ok(())
+    }
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "expandtab" | "et" => flag("expandtab", self.expandtab),
+            "autoindent" | "ai" => flag("autoindent", self.autoindent),
+            "number" | "nu" => flag("number", self.number),
+            "mouse" => flag("mouse", self.mouse),
+            "spell" => flag("spell", self.spell),
+            "smarthome" => flag("smarthome", self.smarthome),
+            "wrap" => flag("wrap", self.wrap),
+            "scrollbar" => flag("scrollbar", self.scrollbar),
+            "hlwhitespace" | "hlws" => flag("hlwhitespace", self.hlwhitespace),
+            "showcmd" | "sc" => flag("showcmd", self.showcmd),
+            "indentguides" | "ig" => flag("indentguides", self.indentguides),
+            "wrapscan" | "ws" => flag("wrapscan", self.wrapscan),
+            "showbreak" | "sbr" => format!("showbreak={}", self.showbreak),
+            "backup" | "bk" => flag("backup", self.backup),
+            "backupext" | "bex" => format!("backupext={}", self.backupext),
+            "backupdir" | "bdir" => format!("backupdir={}", self.backupdir),
+            "confirm" | "cf" => flag("confirm", self.confirm),
+            "fixendofline" | "fixeol" => flag("fixendofline", self.fixendofline),
+            "lsp" => format!("lsp={}", self.lsp),
+            "nrformats" | "nf" => format!("nrformats={}", self.nrformats),
+            "virtualedit" | "ve" => format!("virtualedit={}", self.virtualedit),
+            "statusline" | "stl" => format!("statusline={}", self.statusline),
+            "laststatus" | "ls" => format!("laststatus={}", self.laststatus),
+            "inccommand" | "icm" => format!("inccommand={}", self.inccommand),
+            "clipboard" | "cb" => format!("clipboard={}", self.clipboard),
+            "colorcolumn" | "cc" => {
+                let columns: Vec<String> = self.colorcolumn.iter().map(usize::to_string).collect();
+                format!("colorcolumn={}", columns.join(","))
+            }
+            "tabstop" | "ts" => format!("tabstop={}", self.tabstop),
+            "shiftwidth" | "sw" => format!("shiftwidth={}", self.shiftwidth),
+            "softtabstop" | "sts" => format!("softtabstop={}", self.softtabstop),
+            "timeoutlen" | "tm" => format!("timeoutlen={}", self.timeoutlen),
+            "textwidth" | "tw" => format!("textwidth={}", self.textwidth),
+            "syntax" | "syn" => format!("syntax={}", if self.syntax { "on" } else { "off" }),
+            "largefile" => format!("largefile={}", self.largefile),
+            "yankflash" => format!("yankflash={}", self.yankflash),
+            "autosave" => format!("autosave={}", self.autosave),
+            "autowrite" | "aw" => flag("autowrite", self.autowrite),
+            "scrolljump" | "sj" => format!("scrolljump={}", self.scrolljump),
+            "guicursor" | "gcr" => format!("guicursor={}", self.guicursor),
+            _ => return None,
+        };
+        Some(value)
+    }
+    // The cursor for `mode`, from the last part of `guicursor` naming it. A
+    // block when none does.
+    pub fn cursor_style(&self, mode: &str) -> CursorStyle {
+        let letter = match mode {
+            "Insert" => "i",
+            "Command" => "c",
+            "Visual" | "Visual Line" => "v",
+            _ => "n",
+        };
+        self.guicursor
+            .split(',')
+            .filter_map(parse_cursor)
+            .rfind(|(modes, _)| modes.split('-').any(|m| m == letter))
+            .map_or(CursorStyle::BLOCK, |(_, style)| style)
+    }
+    // Indentation defaults for filetypes with a strong convention
+    pub fn set_filetype_defaults(&mut self, filetype: &str) {
+        let (expandtab, tabstop, shiftwidth) = match filetype {
+            "make" => (false, 8, 8),
+            "go" => (false, 4, 4),
+            "python" | "rust" | "c" | "cpp" | "java" => (true, 4, 4),
+            "javascript" | "typescript" | "json" | "yaml" | "html" | "css" | "ruby" | "lua" => {
+                (true, 2, 2)
+            }
+            _ => return,
+        };
+        self.expandtab = expandtab;
+        self.tabstop = tabstop;
+        self.shiftwidth = shiftwidth;
+    }
+}
+
+// Splits `:set` arguments on whitespace, except where it is escaped with `\`
+pub fn split_args(text: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next.is_whitespace() => arg.push(next),
+                Some(next) => {
+                    arg.push('\\');
+                    arg.push(next);
+                }
+                None => arg.push('\\'),
+            },
+            c if c.is_whitespace() => {
+                if !arg.is_empty() {
+                    args.push(std::mem::take(&mut arg));
+                }
+            }
+            c => arg.push(c),
+        }
+    }
+    if !arg.is_empty() {
+        args.push(arg);
+    }
+    args
+}
+
+// A part of `guicursor`, `modes:shape`
+fn parse_cursor(part: &str) -> Option<(&str, CursorStyle)> {
+    let (modes, shape) = part.split_once(':')?;
+    let known = modes
+        .split('-')
+        .all(|mode| ["n", "v", "i", "c"].contains(&mode));
+    Some((modes, CursorStyle::parse(shape)?)).filter(|_| known)
+}
+
+fn flag(name: &str, value: bool) -> String {
+    if value {
+        name.to_string()
+    } else {
+        format!("no{}", name)
+    }
+}
+
+fn parse_width(name: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(width) if width > 0 => Ok(width),
+        _ => Err(format!("Invalid value for {}: {}", name, value)),
+    }
+}
+
+pub fn detect_filetype(filename: Option<&Path>, first_line: &str) -> String {
+    let name = filename
+        .and_then(|path| path.file_name())
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let extension = filename
+        .and_then(|path| path.extension())
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    let filetype = match (name, extension) {
+        ("Makefile" | "makefile" | "GNUmakefile", _) | (_, "mk") => "make",
+        (_, "rs") => "rust",
+        (_, "py") => "python",
+        (_, "c" | "h") => "c",
+        (_, "cpp" | "cc" | "cxx" | "hpp") => "cpp",
+        (_, "go") => "go",
+        (_, "java") => "java",
+        (_, "js" | "mjs") => "javascript",
+        (_, "ts") => "typescript",
+        (_, "json") => "json",
+        (_, "yaml" | "yml") => "yaml",
+        (_, "toml") => "toml",
+        (_, "html" | "htm") => "html",
+        (_, "css") => "css",
+        (_, "rb") => "ruby",
+        (_, "lua") => "lua",
+        (_, "sh" | "bash" | "zsh") => "sh",
+        (_, "md" | "markdown") => "markdown",
+        (_, "txt") => "text",
+        _ => "",
+    };
+    if !filetype.is_empty() {
+        return filetype.to_string();
+    }
+
+    // Fall back to the interpreter named by a shebang, e.g. `#!/usr/bin/env python3`
+    let Some(shebang) = first_line.strip_prefix("#!") else {
+        return String::new();
+    };
+    let interpreter = shebang
+        .split_whitespace()
+        .find(|word| !word.ends_with("/env"))
+        .and_then(|word| word.rsplit('/').next())
+        .unwrap_or("");
+    let filetype = if interpreter.starts_with("python") {
+        "python"
+    } else if matches!(interpreter, "sh" | "bash" | "zsh" | "dash") {
+        "sh"
+    } else if interpreter == "node" {
+        "javascript"
+    } else if interpreter == "ruby" {
+        "ruby"
+    } else if interpreter.starts_with("lua") {
+        "lua"
+    } else {
+        ""
+    };
+    filetype.to_string()
+}
+
+#[derive(Clone, Copy)]
+pub enum Indent {
+    Spaces(usize),
+    Tabs,
+}
+
+// Guesses how a file is indented from the first lines of it. Space widths are
+// taken from how much the indentation grows between lines.
+pub fn detect_indent(lines: impl Iterator<Item = String>) -> Option<Indent> {
+    let mut tab_lines = 0;
+    let mut space_lines = 0;
+    let mut steps = [0usize; 9];
+    let mut prev_width = 0;
+    for line in lines.take(1000) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let spaces = line.len() - line.trim_start_matches(' ').len();
+        if line.starts_with('\t') {
+            tab_lines += 1;
+            prev_width = 0;
+            continue;
+        }
+        // A single space is usually a comment continuation like ` *`
+        if spaces > 1 {
+            space_lines += 1;
+        }
+        if spaces > prev_width && spaces - prev_width < steps.len() && spaces != 1 {
+            steps[spaces - prev_width] += 1;
+        }
+        prev_width = spaces;
+    }
+    if tab_lines == 0 && space_lines == 0 {
+        return None;
+    }
+    if tab_lines > space_lines {
+        return Some(Indent::Tabs);
+    }
+    // Most common step, preferring the wider one on ties
+    let width = (2..steps.len()).rev().max_by_key(|width| steps[*width])?;
+    (steps[width] > 0).then_some(Indent::Spaces(width))
+}
+
+// What starts a line comment in `filetype`, for `gc`
+pub fn comment_prefix(filetype: &str) -> Option<&'static str> {
+    match filetype {
+        "rust" | "c" | "cpp" | "go" | "java" | "javascript" | "typescript" => Some("//"),
+        "python" | "sh" | "ruby" | "yaml" | "toml" | "make" => Some("#"),
+        "lua" => Some("--"),
+        _ => None,
+    }
+}
+
+// Commands from owlrc: the ones before any `[filetype]` header run for every
+// file, the ones under a header only for that filetype.
+#[derive(Default)]
+pub struct Config {
+    pub global: Vec<String>,
+    pub filetypes: HashMap<String, Vec<String>>,
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_dir.join("owl").join("owlrc"))
+    }
+
+    pub fn load() -> Config {
+        Config::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .map(|text| Config::parse(&text))
+            .unwrap_or_default()
+    }
+
+    fn parse(text: &str) -> Config {
+        let mut config = Config::default();
+        let mut section: Option<String> = None;
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with('"') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                section = Some(name.trim().to_string());
+                continue;
+            }
+            let command = line.strip_prefix(':').unwrap_or(line).to_string();
+            match section.as_ref() {
+                Some(filetype) => config
+                    .filetypes
+                    .entry(filetype.clone())
+                    .or_default()
+                    .push(command),
+                None => config.global.push(command),
+            }
+        }
+        config
+    }
+
+    // Whether a file of `size` bytes is past the `largefile` limit set by
+    // owlrc's global commands
+    pub fn is_large(&self, size: u64) -> bool {
+        let mut options = Options::default();
+        // Errors are reported when the commands are applied for real
+        let _ = run_commands(&mut options, &self.global);
+        options.largefile > 0 && size >= options.largefile as u64 * 1024 * 1024
+    }
+
+    // Snippets from `snippet TRIGGER TEMPLATE` commands, the ones for
+    // `filetype` ahead of the global ones. `\n` and `\t` in the template
+    // stand for a line break and a level of indentation.
+    pub fn snippets(&self, filetype: &str) -> Vec<(String, String)> {
+        let section = self.filetypes.get(filetype).map_or(&[][..], |c| c);
+        section
+            .iter()
+            .chain(self.global.iter())
+            .filter_map(|command| {
+                let (trigger, template) =
+                    command.strip_prefix("snippet ")?.trim().split_once(' ')?;
+                let mut unescaped = String::new();
+                let mut chars = template.trim_start().chars();
+                while let Some(c) = chars.next() {
+                    match (c, chars.clone().next()) {
+                        ('\\', Some('n')) => unescaped.push('\n'),
+                        ('\\', Some('t')) => unescaped.push('\t'),
+                        ('\\', Some(next)) => {
+                            unescaped.push('\\');
+                            unescaped.push(next);
+                        }
+                        (c, _) => {
+                            unescaped.push(c);
+                            continue;
+                        }
+                    }
+                    chars.next();
+                }
+                Some((trigger.to_string(), unescaped))
+            })
+            .collect()
+    }
+
+    // Resets `options` for `filetype`: owlrc's global commands first, then the
+    // large file settings, the built-in filetype defaults, the indentation
+    // found in the file, and last owlrc's section for that filetype
+    pub fn apply(
+        &self,
+        filetype: &str,
+        indent: Option<Indent>,
+        large: bool,
+        options: &mut Options,
+    ) -> Result<(), String> {
+        *options = Options::default();
+        run_commands(options, &self.global)?;
+        if large {
+            options.syntax = false;
+        }
+        options.set_filetype_defaults(filetype);
+        match indent {
+            Some(Indent::Spaces(width)) => {
+                options.expandtab = true;
+                options.tabstop = width;
+                options.shiftwidth = width;
+            }
+            Some(Indent::Tabs) => {
+                options.expandtab = false;
+                options.shiftwidth = options.tabstop;
+            }
+            None => {}
+        }
+        if let Some(commands) = self.filetypes.get(filetype) {
+            run_commands(options, commands)?;
+        }
+        Ok(())
+    }
+}
+
+fn run_commands(options: &mut Options, commands: &[String]) -> Result<(), String> {
+    for command in commands {
+        // Read by Config::snippets instead
+        if command.starts_with("snippet ") {
+            continue;
+        }
+        let Some(args) = command.strip_prefix("set ") else {
+            return Err(format!("Unsupported owlrc command: {}", command));
+        };
+        for arg in split_args(args) {
+            options.set(&arg)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,474 @@
+// Small backtracking regex engine used by search, :s and :g.
+//
+// Supports literals, `.`, `[...]` classes, `\d \w \s` (and negations), `^ $`,
+// word boundaries `\b \< \>`, groups `( )` / `(?: )`, alternation `|` and the
+// quantifiers `* + ? {n,m}` with lazy `?` variants. Matching works on a slice
+// of chars, which is how lines come out of the rope.
+
+enum Node {
+    Char(char),
+    Any,
+    Class(Vec<ClassItem>, bool),
+    Start,
+    End,
+    WordBoundary,
+    WordStart,
+    WordEnd,
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(from, to) => from <= c && c <= to,
+            ClassItem::Digit(negated) => c.is_ascii_digit() != negated,
+            ClassItem::Word(negated) => is_word_char(c) != negated,
+            ClassItem::Space(negated) => c.is_whitespace() != negated,
+        }
+    }
+}
+
+pub fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+pub struct Regex {
+    node: Node,
+    groups: usize,
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    pos: usize,
+    groups: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+    fn parse_alt(&mut self) -> Result<Node, String> {
+        let mut alts = vec![self.parse_concat()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_concat()?);
+        }
+        if alts.len() == 1 {
+            Ok(alts.pop().unwrap())
+        } else {
+            Ok(Node::Alt(alts))
+        }
+    }
+    fn parse_concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_quantifier(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        match self.next().unwrap() {
+            '(' => {
+                let index = if self.chars[self.pos..].starts_with(&['?', ':']) {
+                    self.pos += 2;
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let inner = self.parse_alt()?;
+                if self.next() != Some(')') {
+                    return Err("Unmatched (".to_string());
+                }
+                Ok(Node::Group(Box::new(inner), index))
+            }
+            '.' => Ok(Node::Any),
+            '^' => Ok(Node::Start),
+            '$' => Ok(Node::End),
+            '[' => self.parse_class(),
+            '\\' => match self.next() {
+                Some('b') => Ok(Node::WordBoundary),
+                Some('<') => Ok(Node::WordStart),
+                Some('>') => Ok(Node::WordEnd),
+                Some(c) => Ok(match escape_class(c) {
+                    Some(item) => Node::Class(vec![item], false),
+                    None => Node::Char(escape_char(c)),
+                }),
+                None => Err("Trailing \\".to_string()),
+            },
+            c @ ('*' | '+' | '?') => Err(format!("Nothing to repeat before {}", c)),
+            c => Ok(Node::Char(c)),
+        }
+    }
+    fn parse_class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = match self.next() {
+                Some(']') if !first => break,
+                Some(c) => c,
+                None => return Err("Unmatched [".to_string()),
+            };
+            first = false;
+            let from = if c == '\\' {
+                let escaped = self.next().ok_or("Unmatched [")?;
+                if let Some(item) = escape_class(escaped) {
+                    items.push(item);
+                    continue;
+                }
+                escape_char(escaped)
+            } else {
+                c
+            };
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let mut to = self.next().unwrap();
+                if to == '\\' {
+                    to = escape_char(self.next().ok_or("Unmatched [")?);
+                }
+                if to < from {
+                    return Err(format!("Invalid range {}-{}", from, to));
+                }
+                items.push(ClassItem::Range(from, to));
+            } else {
+                items.push(ClassItem::Range(from, from));
+            }
+        }
+        Ok(Node::Class(items, negated))
+    }
+    fn parse_quantifier(&mut self, atom: Node) -> Result<Node, String> {
+        let mut node = atom;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => match self.parse_braces() {
+                    Some(bounds) => bounds,
+                    None => return Ok(node),
+                },
+                _ => return Ok(node),
+            };
+            if matches!(node, Node::Start | Node::End) {
+                return Err("Nothing to repeat".to_string());
+            }
+            self.pos += 1;
+            let greedy = if self.peek() == Some('?') {
+                self.pos += 1;
+                false
+            } else {
+                true
+            };
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+                greedy,
+            };
+        }
+    }
+    // Parses `{n}`, `{n,}` or `{n,m}`, leaving `pos` on the closing brace
+    fn parse_braces(&mut self) -> Option<(usize, Option<usize>)> {
+        let rest: String = self.chars[self.pos + 1..].iter().collect();
+        let close = rest.find('}')?;
+        let body = &rest[..close];
+        let (min, max) = match body.split_once(',') {
+            Some((min, "")) => (min.parse().ok()?, None),
+            Some((min, max)) => (min.parse().ok()?, Some(max.parse().ok()?)),
+            None => {
+                let n = body.parse().ok()?;
+                (n, Some(n))
+            }
+        };
+        self.pos += body.chars().count() + 1;
+        Some((min, max))
+    }
+}
+
+fn escape_class(c: char) -> Option<ClassItem> {
+    match c {
+        'd' => Some(ClassItem::Digit(false)),
+        'D' => Some(ClassItem::Digit(true)),
+        'w' => Some(ClassItem::Word(false)),
+        'W' => Some(ClassItem::Word(true)),
+        's' => Some(ClassItem::Space(false)),
+        'S' => Some(ClassItem::Space(true)),
+        _ => None,
+    }
+}
+
+fn escape_char(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        c => c,
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut parser = Parser {
+            chars: &chars,
+            pos: 0,
+            groups: 0,
+        };
+        let node = parser.parse_alt()?;
+        if parser.pos < chars.len() {
+            return Err("Unmatched )".to_string());
+        }
+        Ok(Regex {
+            node,
+            groups: parser.groups,
+        })
+    }
+
+    // First match at or after `start`, as a char range
+    pub fn find_at(&self, text: &[char], start: usize) -> Option<(usize, usize)> {
+        self.captures_at(text, start).map(|caps| caps[0].unwrap())
+    }
+
+    // Like `find_at`, with group 0 being the whole match
+    pub fn captures_at(&self, text: &[char], start: usize) -> Option<Captures> {
+        for from in start..=text.len() {
+            let mut caps = vec![None; self.groups + 1];
+            if self.match_node(&self.node, text, from, &mut caps, &mut |end, caps| {
+                caps[0] = Some((from, end));
+                true
+            }) {
+                return Some(caps);
+            }
+        }
+        None
+    }
+
+    fn match_node(
+        &self,
+        node: &Node,
+        text: &[char],
+        pos: usize,
+        caps: &mut Captures,
+        k: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        let prev_word = pos > 0 && is_word_char(text[pos - 1]);
+        let next_word = pos < text.len() && is_word_char(text[pos]);
+        match node {
+            Node::Char(_) | Node::Any | Node::Class(..) => {
+                pos < text.len() && single_matches(node, text[pos]) && k(pos + 1, caps)
+            }
+            Node::Start => pos == 0 && k(pos, caps),
+            Node::End => (pos == text.len() || text[pos] == '\n') && k(pos, caps),
+            Node::WordBoundary => prev_word != next_word && k(pos, caps),
+            Node::WordStart => !prev_word && next_word && k(pos, caps),
+            Node::WordEnd => prev_word && !next_word && k(pos, caps),
+            Node::Group(inner, None) => self.match_node(inner, text, pos, caps, k),
+            Node::Group(inner, Some(index)) => {
+                let index = *index;
+                self.match_node(inner, text, pos, caps, &mut |end, caps| {
+                    let saved = caps[index];
+                    caps[index] = Some((pos, end));
+                    if k(end, caps) {
+                        true
+                    } else {
+                        caps[index] = saved;
+                        false
+                    }
+                })
+            }
+            Node::Concat(nodes) => self.match_seq(nodes, text, pos, caps, k),
+            Node::Alt(alts) => alts
+                .iter()
+                .any(|alt| self.match_node(alt, text, pos, caps, k)),
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                if matches!(**node, Node::Char(_) | Node::Any | Node::Class(..)) {
+                    // Single chars can be counted up front instead of recursing
+                    let limit = max.unwrap_or(usize::MAX);
+                    let count = text[pos..]
+                        .iter()
+                        .take(limit)
+                        .take_while(|c| single_matches(node, **c))
+                        .count();
+                    if count < *min {
+                        return false;
+                    }
+                    if *greedy {
+                        (*min..=count).rev().any(|n| k(pos + n, caps))
+                    } else {
+                        (*min..=count).any(|n| k(pos + n, caps))
+                    }
+                } else {
+                    self.match_repeat(node, *min, *max, *greedy, 0, text, pos, caps, k)
+                }
+            }
+        }
+    }
+
+    fn match_seq(
+        &self,
+        nodes: &[Node],
+        text: &[char],
+        pos: usize,
+        caps: &mut Captures,
+        k: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        match nodes.split_first() {
+            None => k(pos, caps),
+            Some((first, rest)) => self.match_node(first, text, pos, caps, &mut |next, caps| {
+                self.match_seq(rest, text, next, caps, k)
+            }),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn match_repeat(
+        &self,
+        node: &Node,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+        count: usize,
+        text: &[char],
+        pos: usize,
+        caps: &mut Captures,
+        k: &mut dyn FnMut(usize, &mut Captures) -> bool,
+    ) -> bool {
+        let can_repeat = max.is_none_or(|max| count < max);
+        let repeat = |caps: &mut Captures, k: &mut dyn FnMut(usize, &mut Captures) -> bool| {
+            can_repeat
+                && self.match_node(node, text, pos, caps, &mut |next, caps| {
+                    // An empty iteration can't make progress, stop repeating
+                    (next != pos || count < min)
+                        && self.match_repeat(node, min, max, greedy, count + 1, text, next, caps, k)
+                })
+        };
+        if count < min {
+            return repeat(caps, k);
+        }
+        if greedy && repeat(caps, k) {
+            return true;
+        }
+        k(pos, caps) || (!greedy && repeat(caps, k))
+    }
+}
+
+fn single_matches(node: &Node, c: char) -> bool {
+    match node {
+        Node::Char(expected) => c == *expected,
+        Node::Any => c != '\n',
+        Node::Class(items, negated) => items.iter().any(|item| item.matches(c)) != *negated,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        let text: Vec<char> = text.chars().collect();
+        Regex::new(pattern).unwrap().find_at(&text, 0)
+    }
+
+    #[test]
+    fn literals_and_any() {
+        assert_eq!(find("TODO", "a TODO b"), Some((2, 6)));
+        assert_eq!(find("T.DO", "TADO"), Some((0, 4)));
+        assert_eq!(find("TODO", "todo"), None);
+        assert_eq!(find("a\\.b", "axb a.b"), Some((4, 7)));
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(find("[0-9]+", "ab 123 c"), Some((3, 6)));
+        assert_eq!(find("[^a-z ]", "ab c1"), Some((4, 5)));
+        assert_eq!(find("\\d\\s\\w", "x1 y"), Some((1, 4)));
+        assert_eq!(find("\\D+", "12ab3"), Some((2, 4)));
+        assert_eq!(find("[\\d_]+", "ab_12"), Some((2, 5)));
+    }
+
+    #[test]
+    fn anchors_and_word_boundaries() {
+        assert_eq!(find("^a", "ba"), None);
+        assert_eq!(find("^b", "ba"), Some((0, 1)));
+        assert_eq!(find("a$", "aba"), Some((2, 3)));
+        assert_eq!(find("a$", "ab\n"), None);
+        assert_eq!(find("b$", "ab\n"), Some((1, 2)));
+        assert_eq!(find("\\bcat\\b", "concat cat"), Some((7, 10)));
+        assert_eq!(find("\\<in", "main in"), Some((5, 7)));
+        assert_eq!(find("in\\>", "into main"), Some((7, 9)));
+    }
+
+    #[test]
+    fn groups_and_alternation() {
+        assert_eq!(find("cat|dog", "hotdog"), Some((3, 6)));
+        assert_eq!(find("(ab)+", "xababy"), Some((1, 5)));
+        assert_eq!(find("(?:a|b)c", "xbc"), Some((1, 3)));
+        let text: Vec<char> = "key=value".chars().collect();
+        let caps = Regex::new("(\\w+)=(\\w+)")
+            .unwrap()
+            .captures_at(&text, 0)
+            .unwrap();
+        assert_eq!(caps, vec![Some((0, 9)), Some((0, 3)), Some((4, 9))]);
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert_eq!(find("ab*c", "ac abbbc"), Some((0, 2)));
+        assert_eq!(find("ab+c", "ac abbbc"), Some((3, 8)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("a{2,3}", "a aaaa"), Some((2, 5)));
+        assert_eq!(find("a{2}", "a aaaa"), Some((2, 4)));
+        assert_eq!(find("<.*>", "<a><b>"), Some((0, 6)));
+        assert_eq!(find("<.*?>", "<a><b>"), Some((0, 3)));
+    }
+
+    #[test]
+    fn find_at_starts_at_start() {
+        let text: Vec<char> = "TODO TODO".chars().collect();
+        let regex = Regex::new("TODO").unwrap();
+        assert_eq!(regex.find_at(&text, 1), Some((5, 9)));
+        assert_eq!(regex.find_at(&text, 6), None);
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Regex::new("(ab").is_err());
+        assert!(Regex::new("ab)").is_err());
+        assert!(Regex::new("[ab").is_err());
+    }
+}
@@ -0,0 +1,208 @@
+// What is on the terminal, cell by cell.
+//
+// Every frame is drawn into a fresh Screen first. Sending it then only writes
+// the cells that changed since the frame before, so nothing is cleared and
+// redrawn unless the terminal was resized or a full redraw was asked for.
+use std::io::Write;
+
+use crossterm::style::{ContentStyle, StyledContent};
+use crossterm::{cursor, queue, style, terminal};
+
+// How the terminal draws the cursor
+#[derive(Clone, Copy, PartialEq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blink: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CursorShape {
+    Block,
+    Bar,
+    Underline,
+}
+
+impl CursorStyle {
+    pub const BLOCK: CursorStyle = CursorStyle {
+        shape: CursorShape::Block,
+        blink: false,
+    };
+
+    // A shape named as in the `guicursor` option: `block`, `bar` or
+    // `underline`, with `-blink` after it to blink
+    pub fn parse(text: &str) -> Option<CursorStyle> {
+        let (shape, blink) = match text.strip_suffix("-blink") {
+            Some(shape) => (shape, true),
+            None => (text, false),
+        };
+        let shape = match shape {
+            "block" => CursorShape::Block,
+            "bar" => CursorShape::Bar,
+            "underline" => CursorShape::Underline,
+            _ => return None,
+        };
+        Some(CursorStyle { shape, blink })
+    }
+
+    fn command(self) -> cursor::SetCursorStyle {
+        match (self.shape, self.blink) {
+            (CursorShape::Block, false) => cursor::SetCursorStyle::SteadyBlock,
+            (CursorShape::Block, true) => cursor::SetCursorStyle::BlinkingBlock,
+            (CursorShape::Bar, false) => cursor::SetCursorStyle::SteadyBar,
+            (CursorShape::Bar, true) => cursor::SetCursorStyle::BlinkingBar,
+            (CursorShape::Underline, false) => cursor::SetCursorStyle::SteadyUnderScore,
+            (CursorShape::Underline, true) => cursor::SetCursorStyle::BlinkingUnderScore,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Cell {
+    c: char,
+    style: ContentStyle,
+}
+
+const BLANK: Cell = Cell {
+    c: ' ',
+    style: ContentStyle {
+        foreground_color: None,
+        background_color: None,
+        underline_color: None,
+        attributes: style::Attributes::none(),
+    },
+};
+
+pub struct Screen {
+    cols: u16,
+    rows: u16,
+    cells: Vec<Cell>,
+    // Where the next `print` goes
+    col: u16,
+    row: u16,
+    pub cursor: (u16, u16),
+    pub cursor_style: CursorStyle,
+}
+
+impl Screen {
+    pub fn new(cols: u16, rows: u16) -> Screen {
+        Screen {
+            cols,
+            rows,
+            cells: vec![BLANK; cols as usize * rows as usize],
+            col: 0,
+            row: 0,
+            cursor: (0, 0),
+            cursor_style: CursorStyle::BLOCK,
+        }
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        (self.cols, self.rows)
+    }
+
+    pub fn move_to(&mut self, col: u16, row: u16) {
+        self.col = col;
+        self.row = row;
+    }
+
+    // Writes `text` from the current position, cut off at the end of the row
+    pub fn print(&mut self, text: &str) {
+        self.print_styled(ContentStyle::new().apply(text));
+    }
+
+    pub fn print_styled<D: std::fmt::Display>(&mut self, content: StyledContent<D>) {
+        if self.row >= self.rows {
+            return;
+        }
+        for c in content.content().to_string().chars() {
+            if self.col >= self.cols {
+                break;
+            }
+            let index = self.row as usize * self.cols as usize + self.col as usize;
+            self.cells[index] = Cell {
+                c,
+                style: *content.style(),
+            };
+            self.col += 1;
+        }
+    }
+
+    // Copies the cells of `other` in with its top left at `col` and `row`,
+    // cut off at the edges
+    pub fn put(&mut self, other: &Screen, col: u16, row: u16) {
+        for other_row in 0..other.rows.min(self.rows.saturating_sub(row)) {
+            let cols = other.cols.min(self.cols.saturating_sub(col)) as usize;
+            let from = other_row as usize * other.cols as usize;
+            let to = (row + other_row) as usize * self.cols as usize + col as usize;
+            self.cells[to..to + cols].copy_from_slice(&other.cells[from..from + cols]);
+        }
+    }
+
+    // Sends the cells that differ from `previous`, or all of them when there
+    // is no previous frame or it had another size
+    pub fn draw(&self, previous: Option<&Screen>, out: &mut impl Write) -> std::io::Result<()> {
+        let previous = previous.filter(|p| p.cols == self.cols && p.rows == self.rows);
+        queue!(out, cursor::Hide)?;
+        if previous.is_none() {
+            queue!(out, terminal::Clear(terminal::ClearType::All))?;
+        }
+        // Cells that need printing, after a clear that is all but the blank ones
+        let changed =
+            |index: usize| self.cells[index] != previous.map_or(BLANK, |p| p.cells[index]);
+        // Where the terminal cursor is after the last run printed
+        let mut at = None;
+        for row in 0..self.rows {
+            let row_start = row as usize * self.cols as usize;
+            let style_at = |col: u16| self.cells[row_start + col as usize].style;
+            let mut col = 0;
+            while col < self.cols {
+                if !changed(row_start + col as usize) {
+                    col += 1;
+                    continue;
+                }
+                // Take in the changed cells that follow with the same style, and
+                // short gaps of unchanged ones between them, which is cheaper
+                // than moving the cursor over the gap
+                let run_style = style_at(col);
+                let run_start = col;
+                let mut run_end = col + 1;
+                loop {
+                    let mut next = run_end;
+                    while next < self.cols
+                        && next - run_end < 4
+                        && !changed(row_start + next as usize)
+                        && style_at(next) == run_style
+                    {
+                        next += 1;
+                    }
+                    if next < self.cols
+                        && changed(row_start + next as usize)
+                        && style_at(next) == run_style
+                    {
+                        run_end = next + 1;
+                    } else {
+                        break;
+                    }
+                }
+                if at != Some((run_start, row)) {
+                    queue!(out, cursor::MoveTo(run_start, row))?;
+                }
+                let run: String = (run_start..run_end)
+                    .map(|col| self.cells[row_start + col as usize].c)
+                    .collect();
+                queue!(out, style::PrintStyledContent(run_style.apply(run)))?;
+                at = Some((run_end, row));
+                col = run_end;
+            }
+        }
+        if previous.is_none_or(|p| p.cursor_style != self.cursor_style) {
+            queue!(out, self.cursor_style.command())?;
+        }
+        queue!(
+            out,
+            cursor::MoveTo(self.cursor.0, self.cursor.1),
+            cursor::Show
+        )?;
+        out.flush()
+    }
+}
@@ -0,0 +1,178 @@
+// Snippets: templates that replace the word before the cursor when Tab is
+// pressed after it in Insert mode.
+//
+// In a template `$1`, `$2`, ... are the places Tab goes to in turn and `$0`
+// the one it goes to last, the end of the snippet when there is none.
+// `${1:text}` fills the place with text that typing replaces. A tab indents
+// by one level and a newline keeps the indentation of the trigger's line.
+
+// Built-in snippets for `filetype`, as (trigger, template)
+pub fn builtin(filetype: &str) -> &'static [(&'static str, &'static str)] {
+    match filetype {
+        "rust" => &[
+            ("fn", "fn ${1:name}($2) {\n\t$0\n}"),
+            ("test", "#[test]\nfn ${1:name}() {\n\t$0\n}"),
+            ("impl", "impl ${1:Type} {\n\t$0\n}"),
+            ("match", "match ${1:value} {\n\t$0\n}"),
+        ],
+        "python" => &[
+            ("def", "def ${1:name}($2):\n\t${0:pass}"),
+            ("class", "class ${1:Name}:\n\t${0:pass}"),
+            ("main", "if __name__ == \"__main__\":\n\t${0:main()}"),
+        ],
+        "c" | "cpp" => &[
+            (
+                "main",
+                "int main(int argc, char *argv[]) {\n\t$0\n\treturn 0;\n}",
+            ),
+            (
+                "for",
+                "for (${1:int i = 0}; ${2:i < n}; ${3:i++}) {\n\t$0\n}",
+            ),
+            ("inc", "#include <${1:stdio.h}>$0"),
+        ],
+        "go" => &[
+            ("func", "func ${1:name}($2) {\n\t$0\n}"),
+            ("iferr", "if err != nil {\n\treturn ${1:err}\n}$0"),
+        ],
+        "javascript" | "typescript" => &[
+            ("fn", "function ${1:name}($2) {\n\t$0\n}"),
+            ("log", "console.log($0);"),
+        ],
+        "sh" => &[
+            ("if", "if [ $1 ]; then\n\t$0\nfi"),
+            ("for", "for ${1:x} in $2; do\n\t$0\ndone"),
+        ],
+        _ => &[],
+    }
+}
+
+// A place in expanded text, in chars
+pub struct Stop {
+    pub number: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+// The text of `template` for a line indented with `indent`, where one level
+// of indentation is `unit`, and its stops in the order Tab visits them
+pub fn expand(template: &str, indent: &str, unit: &str) -> (String, Vec<Stop>) {
+    let mut text = String::new();
+    let mut len = 0;
+    let mut stops: Vec<Stop> = Vec::new();
+    let mut chars = template.chars().peekable();
+    let mut push = |text: &mut String, s: &str| {
+        text.push_str(s);
+        len += s.chars().count();
+        len
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('$' | '\\' | '}')) => {
+                let escaped = chars.next().unwrap();
+                push(&mut text, &escaped.to_string());
+            }
+            '\n' => {
+                push(&mut text, &format!("\n{}", indent));
+            }
+            '\t' => {
+                push(&mut text, unit);
+            }
+            '$' if chars
+                .peek()
+                .is_some_and(|c| c.is_ascii_digit() || *c == '{') =>
+            {
+                let braced = chars.next_if_eq(&'{').is_some();
+                let mut number = String::new();
+                while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                    number.push(digit);
+                }
+                let start = push(&mut text, "");
+                if braced {
+                    if chars.next_if_eq(&':').is_some() {
+                        while let Some(c) = chars.next_if(|c| *c != '}') {
+                            push(&mut text, &c.to_string());
+                        }
+                    }
+                    chars.next_if_eq(&'}');
+                }
+                let end = push(&mut text, "");
+                // A number used twice is only a stop the first time
+                let number = number.parse().unwrap_or(0);
+                if !stops.iter().any(|stop| stop.number == number) {
+                    stops.push(Stop { number, start, end });
+                }
+            }
+            c => {
+                push(&mut text, &c.to_string());
+            }
+        }
+    }
+    if !stops.iter().any(|stop| stop.number == 0) {
+        let end = text.chars().count();
+        stops.push(Stop {
+            number: 0,
+            start: end,
+            end,
+        });
+    }
+    stops.sort_by_key(|stop| {
+        if stop.number == 0 {
+            usize::MAX
+        } else {
+            stop.number
+        }
+    });
+    (text, stops)
+}
+
+// A snippet being filled in: its stops in the buffer, as char ranges kept up
+// to date as the text around them changes
+pub struct Active {
+    pub stops: Vec<(usize, usize)>,
+    pub current: usize,
+    // Set on arriving at a stop, until something is typed there
+    pub fresh: bool,
+}
+
+impl Active {
+    // Text typed at the end of the current stop goes into it, text typed at
+    // the end of any other stop does not
+    pub fn inserted(&mut self, pos: usize, len: usize) {
+        for (i, (start, end)) in self.stops.iter_mut().enumerate() {
+            if i == self.current {
+                if *start > pos {
+                    *start += len;
+                }
+                if *end >= pos {
+                    *end += len;
+                }
+            } else if *start >= pos {
+                *start += len;
+                *end += len;
+            } else if *end > pos {
+                *end += len;
+            }
+        }
+    }
+
+    pub fn removed(&mut self, range: std::ops::Range<usize>) {
+        let shift = |pos: &mut usize| {
+            if *pos >= range.end {
+                *pos -= range.len();
+            } else if *pos > range.start {
+                *pos = range.start;
+            }
+        };
+        for (start, end) in self.stops.iter_mut() {
+            shift(start);
+            shift(end);
+        }
+    }
+
+    // The placeholder text typing would replace, if the cursor is at it
+    pub fn placeholder(&self, cursor: usize) -> Option<std::ops::Range<usize>> {
+        let (start, end) = self.stops[self.current];
+        (self.fresh && cursor == start && start < end).then_some(start..end)
+    }
+}
@@ -0,0 +1,149 @@
+// Spell checking against the word list bundled in words.txt.
+//
+// The list only holds base forms, common plurals and inflections are accepted
+// by stripping the suffix and looking the stem up again.
+use std::collections::HashSet;
+
+const WORDS: &str = include_str!("words.txt");
+const SUFFIXES: [&str; 9] = ["'s", "s", "es", "ed", "ing", "er", "est", "ly", "ness"];
+
+pub struct Dictionary {
+    words: HashSet<&'static str>,
+}
+
+impl Dictionary {
+    pub fn new() -> Dictionary {
+        Dictionary {
+            words: WORDS.split_whitespace().collect(),
+        }
+    }
+
+    pub fn check(&self, word: &str) -> bool {
+        // Skip things that look like identifiers or abbreviations rather than words
+        if word.chars().count() < 2 || word.chars().skip(1).any(|c| c.is_uppercase()) {
+            return true;
+        }
+        let word = word.to_lowercase();
+        self.words.contains(word.as_str()) || self.check_stem(&word)
+    }
+
+    fn check_stem(&self, word: &str) -> bool {
+        SUFFIXES.iter().any(|suffix| {
+            let Some(stem) = word.strip_suffix(suffix) else {
+                return false;
+            };
+            if stem.len() < 2 {
+                return false;
+            }
+            let known = |stem: &str| self.words.contains(stem);
+            // jumped, jumping / baked, baking / stopped, stopping / tried, happily
+            known(stem)
+                || known(&format!("{}e", stem))
+                || (stem.len() > 2
+                    && stem.as_bytes()[stem.len() - 1] == stem.as_bytes()[stem.len() - 2]
+                    && known(&stem[..stem.len() - 1]))
+                || stem
+                    .strip_suffix('i')
+                    .is_some_and(|base| known(&format!("{}y", base)))
+        })
+    }
+
+    // Dictionary words closest to `word` by edit distance, best first
+    pub fn suggest(&self, word: &str, limit: usize) -> Vec<String> {
+        let word: Vec<char> = word.to_lowercase().chars().collect();
+        let max_distance = if word.len() <= 4 { 1 } else { 2 };
+        let mut candidates: Vec<(usize, &str)> = self
+            .words
+            .iter()
+            .filter(|candidate| candidate.len().abs_diff(word.len()) <= max_distance)
+            .filter_map(|candidate| {
+                let candidate_chars: Vec<char> = candidate.chars().collect();
+                let distance = edit_distance(&word, &candidate_chars);
+                (distance <= max_distance).then_some((distance, *candidate))
+            })
+            .collect();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(_, candidate)| candidate.to_string())
+            .collect()
+    }
+}
+
+// Char ranges of the misspelled words in `line`
+pub fn misspellings(dictionary: &Dictionary, line: &[char]) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut col = 0;
+    while col < line.len() {
+        let (start, end) = word_at(line, col);
+        if start == end {
+            col += 1;
+            continue;
+        }
+        // Words glued to digits or underscores are identifiers, leave them be
+        let touches_code = |c: Option<&char>| c.is_some_and(|c| c.is_alphanumeric() || *c == '_');
+        let word: String = line[start..end].iter().collect();
+        if !touches_code(start.checked_sub(1).and_then(|i| line.get(i)))
+            && !touches_code(line.get(end))
+            && !dictionary.check(&word)
+        {
+            ranges.push((start, end));
+        }
+        col = end.max(col + 1);
+    }
+    ranges
+}
+
+// The word containing `col`, if the cursor is on one
+pub fn word_under(line: &[char], col: usize) -> Option<(usize, usize)> {
+    if col >= line.len() || !line[col].is_alphabetic() {
+        return None;
+    }
+    let mut start = col;
+    while start > 0
+        && (line[start - 1].is_alphabetic()
+            || (line[start - 1] == '\'' && start > 1 && line[start - 2].is_alphabetic()))
+    {
+        start -= 1;
+    }
+    Some(word_at(line, start))
+}
+
+// The alphabetic word (apostrophes allowed inside) starting at `col`, or an
+// empty range if there is none
+fn word_at(line: &[char], col: usize) -> (usize, usize) {
+    if !line[col].is_alphabetic() {
+        return (col, col);
+    }
+    let mut end = col;
+    while end < line.len()
+        && (line[end].is_alphabetic()
+            || (line[end] == '\'' && line.get(end + 1).is_some_and(|c| c.is_alphabetic())))
+    {
+        end += 1;
+    }
+    (col, end)
+}
+
+// Levenshtein distance where swapping two neighbouring chars counts as one edit
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut best = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                best = best.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = best;
+        }
+    }
+    rows[a.len()][b.len()]
+}
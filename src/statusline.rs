@@ -0,0 +1,75 @@
+// The `statusline` option: the status line as text with `%` items in it.
+//
+// `%M` is the mode, `%f` the file name, `%m` a `+` when modified, `%r` `RO`
+// when read-only, `%y` the filetype, `%l` and `%c` the cursor's line and
+// column, `%L` the number of lines and `%p` how far down the cursor is in
+// percent. `%i` is the indentation, `%e` `[noeol]` for a last line without a
+// line ending, `%w` a note when syntax is off for a large file, `%d` the
+// error and warning counts and `%n` the number of cursors. Text between
+// `%(` and `%)` is left out when all items in it are empty, `%=` puts what
+// follows against the right edge and `%%` is a `%`.
+
+pub const DEFAULT: &str = "%M%( | %f%) | %i%( | %m%)%( | %r%)%( | %e%)%( | %w%)%( | %d%)%( | %n%)";
+
+const ITEMS: &str = "MfmrylcLpiewdn";
+
+// What `item` gives for each item of `format`, in `width` columns
+pub fn render(format: &str, width: usize, item: impl Fn(char) -> String) -> String {
+    let mut left = String::new();
+    let mut right: Option<String> = None;
+    // The text of the open group so far, and whether an item in it is not
+    // empty
+    let mut group: Option<(String, bool)> = None;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        let next = if c == '%' { chars.next() } else { None };
+        let (text, is_item) = match next {
+            Some('(') => {
+                group = Some((String::new(), false));
+                continue;
+            }
+            Some(')') => {
+                if let Some((text, true)) = group.take() {
+                    right.as_mut().unwrap_or(&mut left).push_str(&text);
+                }
+                continue;
+            }
+            Some('=') => {
+                right.get_or_insert_with(String::new);
+                continue;
+            }
+            Some('%') => ("%".to_string(), false),
+            Some(c) => (item(c), true),
+            None if c == '%' => continue,
+            None => (c.to_string(), false),
+        };
+        match group.as_mut() {
+            Some((group_text, filled)) => {
+                group_text.push_str(&text);
+                *filled |= is_item && !text.is_empty();
+            }
+            None => right.as_mut().unwrap_or(&mut left).push_str(&text),
+        }
+    }
+    let Some(right) = right else {
+        return left;
+    };
+    let gap = width.saturating_sub(left.chars().count() + right.chars().count());
+    format!("{}{}{}", left, " ".repeat(gap), right)
+}
+
+// An error for the first unknown item of `format`
+pub fn check(format: &str) -> Result<(), String> {
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            continue;
+        }
+        match chars.next() {
+            Some(c) if ITEMS.contains(c) || "()=%".contains(c) => {}
+            Some(c) => return Err(format!("Unknown item in statusline: %{}", c)),
+            None => return Err("statusline ends with %".to_string()),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,293 @@
+// Undo history, kept as the edits each command made so it is cheap to store
+// between sessions.
+//
+// Every state the text has been in is kept, as a tree: making a change after
+// undoing starts a new branch rather than throwing the undone changes away.
+// Undo and redo move along a branch, g- and g+ through the states in the
+// order they were made.
+//
+// The history file for a buffer lives under $XDG_STATE_HOME/owl/undo (or
+// ~/.local/state/owl/undo) and records a hash of the text it was saved with.
+// It is only loaded back if the file still has exactly that text, and was
+// saved in this format.
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "owl-undo 2";
+
+pub struct Edit {
+    pub pos: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+// The edits made by one command, oldest first
+pub type Change = Vec<Edit>;
+
+// A state of the text, numbered by when it was made. State 0 is the text as
+// it was first loaded and every other one comes from its parent by a change.
+struct State {
+    parent: usize,
+    change: Change,
+    // Seconds since the epoch
+    time: u64,
+    // The child redo goes to: the one last moved from or to, or failing that
+    // the newest
+    redo: Option<usize>,
+}
+
+pub struct History {
+    states: Vec<State>,
+    state: usize,
+    current: Change,
+    // `:undojoin`: the next change goes into the state the text is in
+    // rather than making a new one
+    join: bool,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History {
+            states: vec![State {
+                parent: 0,
+                change: Vec::new(),
+                time: now(),
+                redo: None,
+            }],
+            state: 0,
+            current: Vec::new(),
+            join: false,
+        }
+    }
+}
+
+impl History {
+    pub fn record(&mut self, edit: Edit) {
+        // Typing in Insert mode makes one insertion per key, join them up
+        if let Some(last) = self.current.last_mut() {
+            let joins = last.removed.is_empty()
+                && edit.removed.is_empty()
+                && last.pos + last.inserted.chars().count() == edit.pos;
+            if joins {
+                last.inserted.push_str(&edit.inserted);
+                return;
+            }
+        }
+        self.current.push(edit);
+    }
+
+    // Ends the change the edits recorded so far belong to
+    pub fn commit(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        if std::mem::take(&mut self.join) && self.state != 0 {
+            let state = &mut self.states[self.state];
+            state.change.append(&mut self.current);
+            state.time = now();
+        } else {
+            let parent = self.state;
+            self.state = self.states.len();
+            self.states.push(State {
+                parent,
+                change: std::mem::take(&mut self.current),
+                time: now(),
+                redo: None,
+            });
+            self.states[parent].redo = Some(self.state);
+        }
+    }
+
+    // Has the next change join the last one, so they are undone together.
+    // Not after an undo, when the state the text is in is not the last
+    // change made on its branch.
+    pub fn join_next(&mut self) -> Result<(), &'static str> {
+        self.commit();
+        if self.redo_target().is_some() {
+            return Err("undojoin is not allowed after undo");
+        }
+        self.join = true;
+        Ok(())
+    }
+
+    // The number of the state the text is in
+    pub fn state(&self) -> usize {
+        self.state
+    }
+
+    pub fn newest(&self) -> usize {
+        self.states.len() - 1
+    }
+
+    // When `state` was made, in seconds since the epoch
+    pub fn time(&self, state: usize) -> u64 {
+        self.states[state].time
+    }
+
+    // The state undo goes back to
+    pub fn undo_target(&self) -> Option<usize> {
+        (self.state != 0).then(|| self.states[self.state].parent)
+    }
+
+    // The state redo goes forward to
+    pub fn redo_target(&self) -> Option<usize> {
+        let state = self.state;
+        self.states[state].redo.or_else(|| {
+            (state + 1..self.states.len())
+                .rev()
+                .find(|&child| self.states[child].parent == state)
+        })
+    }
+
+    // Moves to `target`, returning the changes that take the text there in
+    // the order they apply, each with whether it is redone rather than undone
+    pub fn go_to(&mut self, target: usize) -> Vec<(&Change, bool)> {
+        // A parent is always older than its children, so stepping back from
+        // whichever of the two is newer meets where their branches join
+        let (mut from, mut to) = (self.state, target);
+        let mut undone = Vec::new();
+        let mut redone = Vec::new();
+        while from != to {
+            if from > to {
+                undone.push(from);
+                from = self.states[from].parent;
+            } else {
+                redone.push(to);
+                to = self.states[to].parent;
+            }
+        }
+        for &state in undone.iter().chain(redone.iter()) {
+            let parent = self.states[state].parent;
+            self.states[parent].redo = Some(state);
+        }
+        self.state = target;
+        self.join = false;
+        let states = &self.states;
+        undone
+            .into_iter()
+            .map(|state| (&states[state].change, false))
+            .chain(
+                redone
+                    .into_iter()
+                    .rev()
+                    .map(|state| (&states[state].change, true)),
+            )
+            .collect()
+    }
+
+    pub fn path(filename: &Path) -> Option<PathBuf> {
+        let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+        };
+        // Like vim's undodir, the absolute path with `/` swapped for `%`
+        let absolute = std::fs::canonicalize(filename).ok()?;
+        let name = absolute.to_str()?.replace('/', "%");
+        Some(state_dir.join("owl").join("undo").join(name))
+    }
+
+    pub fn save(&self, filename: &Path, text: &str) -> std::io::Result<()> {
+        let Some(path) = History::path(filename) else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut out = format!(
+            "{}\n{:016x}\nstate {} {}\n",
+            HEADER,
+            hash(text),
+            self.state,
+            self.states[0].time
+        );
+        for state in &self.states[1..] {
+            out.push_str(&format!(
+                "change {} {} {}\n",
+                state.parent,
+                state.time,
+                state.change.len()
+            ));
+            for edit in &state.change {
+                // Lengths in bytes, then both texts verbatim
+                out.push_str(&format!(
+                    "{} {} {}\n{}{}\n",
+                    edit.pos,
+                    edit.removed.len(),
+                    edit.inserted.len(),
+                    edit.removed,
+                    edit.inserted
+                ));
+            }
+        }
+        std::fs::write(path, out)
+    }
+
+    // The history saved for `filename`, if it was saved with `text`
+    pub fn load(filename: &Path, text: &str) -> Option<History> {
+        let data = std::fs::read_to_string(History::path(filename)?).ok()?;
+        let rest = data.strip_prefix(HEADER)?.strip_prefix('\n')?;
+        let (saved_hash, mut rest) = rest.split_once('\n')?;
+        if u64::from_str_radix(saved_hash, 16).ok()? != hash(text) {
+            return None;
+        }
+
+        let (line, after) = rest.split_once('\n')?;
+        let mut numbers = line.strip_prefix("state ")?.split(' ');
+        let (state, time) = (numbers.next()?.parse().ok()?, numbers.next()?.parse().ok()?);
+        rest = after;
+
+        let mut history = History::default();
+        history.states[0].time = time;
+        while !rest.is_empty() {
+            let (line, after) = rest.split_once('\n')?;
+            let mut numbers = line
+                .strip_prefix("change ")?
+                .split(' ')
+                .map(|n| n.parse::<u64>().ok());
+            let (parent, time, count) = (numbers.next()??, numbers.next()??, numbers.next()??);
+            rest = after;
+            let mut change = Vec::new();
+            for _ in 0..count {
+                let (line, after) = rest.split_once('\n')?;
+                let mut numbers = line.split(' ').map(|n| n.parse::<usize>().ok());
+                let (pos, removed, inserted) =
+                    (numbers.next()??, numbers.next()??, numbers.next()??);
+                let removed_text = after.get(..removed)?;
+                let inserted_text = after.get(removed..removed + inserted)?;
+                rest = after.get(removed + inserted..)?.strip_prefix('\n')?;
+                change.push(Edit {
+                    pos,
+                    removed: removed_text.to_string(),
+                    inserted: inserted_text.to_string(),
+                });
+            }
+            if parent as usize >= history.states.len() {
+                return None;
+            }
+            history.states.push(State {
+                parent: parent as usize,
+                change,
+                time,
+                redo: None,
+            });
+        }
+        if state >= history.states.len() {
+            return None;
+        }
+        history.state = state;
+        Some(history)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+// FNV-1a, which unlike std's hasher is stable between builds
+fn hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,483 @@
+// How the text area is shared out between windows.
+//
+// Splitting a window puts the new one above it, or left of it. Windows split
+// the same way sit side by side in one split, and a split going the other way
+// nests inside it. Every part keeps the size it was last laid out at, so a
+// resized terminal is shared out in the same proportions.
+
+// Part of the screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect {
+    pub col: u16,
+    pub row: u16,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+impl Rect {
+    pub fn contains(&self, col: u16, row: u16) -> bool {
+        self.col <= col
+            && col < self.col + self.cols
+            && self.row <= row
+            && row < self.row + self.rows
+    }
+}
+
+// What resizing leaves of a window: a row of text and its status line, or a
+// column
+const MIN_ROWS: u16 = 2;
+const MIN_COLS: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Layout {
+    // The window at this index in the editor's list
+    Window(usize),
+    // Side by side when `vertical`, with a column between each two,
+    // otherwise one above the other. Each part has its size with it.
+    Split {
+        vertical: bool,
+        parts: Vec<(Layout, u16)>,
+    },
+}
+
+impl Default for Layout {
+    fn default() -> Layout {
+        Layout::Window(0)
+    }
+}
+
+impl Layout {
+    // Every window with where it is in `area`, from the top left
+    pub fn rects(&self, area: Rect) -> Vec<(usize, Rect)> {
+        let mut rects = Vec::new();
+        self.lay_out(area, &mut rects);
+        rects
+    }
+
+    fn lay_out(&self, area: Rect, rects: &mut Vec<(usize, Rect)>) {
+        match self {
+            Layout::Window(index) => rects.push((*index, area)),
+            Layout::Split { vertical, parts } => {
+                for ((part, _), part_area) in parts.iter().zip(part_areas(*vertical, parts, area)) {
+                    part.lay_out(part_area, rects);
+                }
+            }
+        }
+    }
+
+    // Sizes every part as it is laid out in `area`, for changes to go from
+    fn fit(&mut self, area: Rect) {
+        if let Layout::Split { vertical, parts } = self {
+            let areas = part_areas(*vertical, parts, area);
+            for ((part, size), part_area) in parts.iter_mut().zip(areas) {
+                *size = if *vertical {
+                    part_area.cols
+                } else {
+                    part_area.rows
+                };
+                part.fit(part_area);
+            }
+        }
+    }
+
+    fn has(&self, index: usize) -> bool {
+        match self {
+            Layout::Window(i) => *i == index,
+            Layout::Split { parts, .. } => parts.iter().any(|(part, _)| part.has(index)),
+        }
+    }
+
+    fn first(&self) -> usize {
+        match self {
+            Layout::Window(index) => *index,
+            Layout::Split { parts, .. } => parts[0].0.first(),
+        }
+    }
+
+    fn last(&self) -> usize {
+        match self {
+            Layout::Window(index) => *index,
+            Layout::Split { parts, .. } => parts[parts.len() - 1].0.last(),
+        }
+    }
+
+    // Splits window `index` in two, with window `new` above it, or left of
+    // it when `vertical`, as laid out in `area`
+    pub fn split(&mut self, index: usize, new: usize, vertical: bool, area: Rect) {
+        self.fit(area);
+        self.split_fitted(index, new, vertical);
+    }
+
+    fn split_fitted(&mut self, index: usize, new: usize, vertical: bool) -> bool {
+        match self {
+            Layout::Window(i) if *i == index => {
+                *self = Layout::Split {
+                    vertical,
+                    parts: vec![(Layout::Window(new), 1), (Layout::Window(index), 1)],
+                };
+                true
+            }
+            Layout::Window(_) => false,
+            Layout::Split {
+                vertical: split_vertical,
+                parts,
+            } => {
+                // A window of a split going the same way is shared in it
+                let at = parts
+                    .iter()
+                    .position(|(part, _)| *part == Layout::Window(index));
+                match at {
+                    Some(at) if *split_vertical == vertical => {
+                        let size = parts[at].1;
+                        parts[at].1 = size - size / 2;
+                        parts.insert(at, (Layout::Window(new), size / 2));
+                        true
+                    }
+                    _ => parts
+                        .iter_mut()
+                        .any(|(part, _)| part.split_fitted(index, new, vertical)),
+                }
+            }
+        }
+    }
+
+    // Takes window `index` out, as laid out in `area`. Its space goes to the
+    // part after it, or before it for the last, and the windows after it in
+    // the list move up one. Returns the window next to where it was.
+    pub fn close(&mut self, index: usize, area: Rect) -> usize {
+        self.fit(area);
+        let next = self.remove(index).unwrap_or(0);
+        self.renumber(index);
+        if next > index {
+            next - 1
+        } else {
+            next
+        }
+    }
+
+    fn remove(&mut self, index: usize) -> Option<usize> {
+        let Layout::Split { vertical, parts } = self else {
+            return None;
+        };
+        let vertical = *vertical;
+        if let Some(at) = parts
+            .iter()
+            .position(|(part, _)| *part == Layout::Window(index))
+        {
+            let (_, size) = parts.remove(at);
+            let to = at.min(parts.len() - 1);
+            // With the column that was between them
+            parts[to].1 += size + vertical as u16;
+            let next = if to == at {
+                parts[to].0.first()
+            } else {
+                parts[to].0.last()
+            };
+            if parts.len() == 1 {
+                *self = parts.remove(0).0;
+            }
+            return Some(next);
+        }
+        for i in 0..parts.len() {
+            if let Some(next) = parts[i].0.remove(index) {
+                // What is left of a split inside it may go the same way,
+                // and is then part of this one
+                if let Layout::Split {
+                    vertical: inner_vertical,
+                    parts: inner,
+                } = &parts[i].0
+                {
+                    if *inner_vertical == vertical {
+                        let inner = inner.clone();
+                        parts.splice(i..=i, inner);
+                    }
+                }
+                return Some(next);
+            }
+        }
+        None
+    }
+
+    fn renumber(&mut self, removed: usize) {
+        match self {
+            Layout::Window(index) if *index > removed => *index -= 1,
+            Layout::Window(_) => {}
+            Layout::Split { parts, .. } => {
+                for (part, _) in parts {
+                    part.renumber(removed);
+                }
+            }
+        }
+    }
+
+    // Makes window `index` `delta` rows taller, or columns wider when
+    // `vertical`, as laid out in `area`. They come from the parts after it
+    // and then those before it. A negative `delta` gives them to the part
+    // after it, or before it for the last.
+    pub fn resize(&mut self, index: usize, vertical: bool, delta: i32, area: Rect) {
+        self.fit(area);
+        self.resize_fitted(index, vertical, delta);
+    }
+
+    fn resize_fitted(&mut self, index: usize, vertical: bool, delta: i32) -> bool {
+        let Layout::Split {
+            vertical: split_vertical,
+            parts,
+        } = self
+        else {
+            return false;
+        };
+        let Some(at) = parts.iter().position(|(part, _)| part.has(index)) else {
+            return false;
+        };
+        // The innermost split going that way does it
+        if parts[at].0.resize_fitted(index, vertical, delta) {
+            return true;
+        }
+        if *split_vertical != vertical {
+            return false;
+        }
+        let min = if vertical { MIN_COLS } else { MIN_ROWS };
+        let amount = delta.unsigned_abs().min(u16::MAX as u32) as u16;
+        if delta > 0 {
+            let mut wanted = amount;
+            for i in (at + 1..parts.len()).chain((0..at).rev()) {
+                let taken = wanted.min(parts[i].1.saturating_sub(min));
+                parts[i].1 -= taken;
+                parts[at].1 += taken;
+                wanted -= taken;
+            }
+        } else {
+            let given = amount.min(parts[at].1.saturating_sub(min));
+            let to = if at + 1 < parts.len() { at + 1 } else { at - 1 };
+            parts[at].1 -= given;
+            parts[to].1 += given;
+        }
+        true
+    }
+}
+
+// Where each of `parts` goes in `area`
+fn part_areas(vertical: bool, parts: &[(Layout, u16)], area: Rect) -> Vec<Rect> {
+    let (total, between) = if vertical {
+        (area.cols, parts.len() as u16 - 1)
+    } else {
+        (area.rows, 0)
+    };
+    let sizes = share(
+        parts.iter().map(|(_, size)| *size),
+        total.saturating_sub(between),
+    );
+    let mut start = 0;
+    sizes
+        .into_iter()
+        .map(|size| {
+            let rect = if vertical {
+                Rect {
+                    col: area.col + start,
+                    cols: size,
+                    ..area
+                }
+            } else {
+                Rect {
+                    row: area.row + start,
+                    rows: size,
+                    ..area
+                }
+            };
+            start += size + vertical as u16;
+            rect
+        })
+        .collect()
+}
+
+// `total` shared out in proportion to `sizes`, with what rounding down
+// leaves going to the last
+fn share(sizes: impl Iterator<Item = u16>, total: u16) -> Vec<u16> {
+    let sizes: Vec<u32> = sizes.map(|size| size.max(1) as u32).collect();
+    let sum: u32 = sizes.iter().sum();
+    let mut shares: Vec<u16> = sizes
+        .iter()
+        .map(|size| (size * total as u32 / sum) as u16)
+        .collect();
+    let given: u16 = shares.iter().sum();
+    if let Some(last) = shares.last_mut() {
+        *last += total - given;
+    }
+    shares
+}
+
+// The window beside `from` in the direction of `key`, `h`, `j`, `k` or `l`:
+// the nearest one, and of those the one level with `at`, a cell in `from`
+pub fn neighbour(rects: &[(usize, Rect)], from: Rect, at: (u16, u16), key: char) -> Option<usize> {
+    rects
+        .iter()
+        .filter_map(|&(index, rect)| {
+            let beside = rect.row < from.row + from.rows && from.row < rect.row + rect.rows;
+            let above_below = rect.col < from.col + from.cols && from.col < rect.col + rect.cols;
+            let distance = match key {
+                'h' if beside && rect.col + rect.cols <= from.col => {
+                    from.col - (rect.col + rect.cols)
+                }
+                'l' if beside && rect.col >= from.col + from.cols => {
+                    rect.col - (from.col + from.cols)
+                }
+                'k' if above_below && rect.row + rect.rows <= from.row => {
+                    from.row - (rect.row + rect.rows)
+                }
+                'j' if above_below && rect.row >= from.row + from.rows => {
+                    rect.row - (from.row + from.rows)
+                }
+                _ => return None,
+            };
+            let level = if key == 'h' || key == 'l' {
+                rect.row <= at.1 && at.1 < rect.row + rect.rows
+            } else {
+                rect.col <= at.0 && at.0 < rect.col + rect.cols
+            };
+            Some((distance, !level, rect.row, rect.col, index))
+        })
+        .min()
+        .map(|(.., index)| index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AREA: Rect = Rect {
+        col: 0,
+        row: 0,
+        cols: 80,
+        rows: 20,
+    };
+
+    fn rect(col: u16, row: u16, cols: u16, rows: u16) -> Rect {
+        Rect {
+            col,
+            row,
+            cols,
+            rows,
+        }
+    }
+
+    #[test]
+    fn split_in_halves() {
+        let mut layout = Layout::default();
+        layout.split(0, 1, false, AREA);
+        assert_eq!(
+            layout.rects(AREA),
+            [(1, rect(0, 0, 80, 10)), (0, rect(0, 10, 80, 10))]
+        );
+        // Beside the bottom one, with a column between
+        layout.split(0, 2, true, AREA);
+        assert_eq!(
+            layout.rects(AREA),
+            [
+                (1, rect(0, 0, 80, 10)),
+                (2, rect(0, 10, 39, 10)),
+                (0, rect(40, 10, 40, 10))
+            ]
+        );
+        // Splitting the same way again shares the split
+        layout.split(1, 3, false, AREA);
+        assert_eq!(
+            layout.rects(AREA),
+            [
+                (3, rect(0, 0, 80, 5)),
+                (1, rect(0, 5, 80, 5)),
+                (2, rect(0, 10, 39, 10)),
+                (0, rect(40, 10, 40, 10))
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_in_proportion() {
+        let mut layout = Layout::default();
+        layout.split(0, 1, false, AREA);
+        layout.resize(1, false, 5, AREA);
+        let area = Rect { rows: 40, ..AREA };
+        assert_eq!(
+            layout.rects(area),
+            [(1, rect(0, 0, 80, 30)), (0, rect(0, 30, 80, 10))]
+        );
+    }
+
+    #[test]
+    fn close_gives_the_space_on() {
+        let mut layout = Layout::default();
+        layout.split(0, 1, false, AREA);
+        layout.split(1, 2, false, AREA);
+        // The window after it gets it, and the ones after that move up
+        assert_eq!(layout.close(2, AREA), 1);
+        assert_eq!(
+            layout.rects(AREA),
+            [(1, rect(0, 0, 80, 10)), (0, rect(0, 10, 80, 10))]
+        );
+        // The one before it for the last
+        assert_eq!(layout.close(0, AREA), 0);
+        assert_eq!(layout, Layout::Window(0));
+    }
+
+    #[test]
+    fn close_joins_what_is_left() {
+        // Beside the one on the left: one above the other, the lower one
+        // split beside itself
+        let mut layout = Layout::default();
+        layout.split(0, 1, true, AREA);
+        layout.split(0, 2, false, AREA);
+        layout.split(0, 3, true, AREA);
+        assert_eq!(layout.close(2, AREA), 2);
+        assert_eq!(
+            layout,
+            Layout::Split {
+                vertical: true,
+                parts: vec![
+                    (Layout::Window(1), 39),
+                    (Layout::Window(2), 19),
+                    (Layout::Window(0), 20)
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn resize_takes_from_the_next() {
+        let mut layout = Layout::default();
+        layout.split(0, 1, false, AREA);
+        layout.split(0, 2, true, AREA);
+        layout.resize(2, false, 3, AREA);
+        assert_eq!(
+            layout.rects(AREA),
+            [
+                (1, rect(0, 0, 80, 7)),
+                (2, rect(0, 7, 39, 13)),
+                (0, rect(40, 7, 40, 13))
+            ]
+        );
+        layout.resize(2, true, -9, AREA);
+        assert_eq!(layout.rects(AREA)[1], (2, rect(0, 7, 30, 13)));
+        // Never past what the others can give
+        layout.resize(1, false, 100, AREA);
+        assert_eq!(layout.rects(AREA)[0], (1, rect(0, 0, 80, 18)));
+        layout.resize(1, false, -100, AREA);
+        assert_eq!(layout.rects(AREA)[0], (1, rect(0, 0, 80, 2)));
+    }
+
+    #[test]
+    fn neighbours() {
+        let mut layout = Layout::default();
+        layout.split(0, 1, false, AREA);
+        layout.split(0, 2, true, AREA);
+        let rects = layout.rects(AREA);
+        let at = |index: usize| rects.iter().find(|(i, _)| *i == index).unwrap().1;
+        assert_eq!(neighbour(&rects, at(2), (0, 10), 'l'), Some(0));
+        assert_eq!(neighbour(&rects, at(0), (40, 10), 'h'), Some(2));
+        assert_eq!(neighbour(&rects, at(0), (40, 10), 'k'), Some(1));
+        assert_eq!(neighbour(&rects, at(1), (50, 0), 'j'), Some(0));
+        assert_eq!(neighbour(&rects, at(1), (10, 0), 'j'), Some(2));
+        assert_eq!(neighbour(&rects, at(1), (10, 0), 'k'), None);
+        assert_eq!(neighbour(&rects, at(0), (40, 10), 'l'), None);
+    }
+}